@@ -0,0 +1,109 @@
+use std::time::UNIX_EPOCH;
+
+use crate::rdb::Database;
+use crate::value::Value;
+
+/// Writes waiting to be appended to the append-only file, encoded as the RESP
+/// commands that replay them.
+#[derive(Default)]
+pub struct AofBuffer {
+    pending: Vec<u8>,
+    /// Database the file is in once `pending` is written. `None` until the
+    /// first write, since an existing file may end in any database.
+    db: Option<usize>,
+}
+
+impl AofBuffer {
+    /// Queues `command args...` as applied to database `db`, preceded by a
+    /// SELECT if the file is in another database.
+    pub fn record(&mut self, db: usize, command: &str, args: &[&[u8]]) {
+        if self.db != Some(db) {
+            self.push(&[b"SELECT", db.to_string().as_bytes()]);
+            self.db = Some(db);
+        }
+        let mut words = Vec::with_capacity(args.len() + 1);
+        words.push(command.as_bytes());
+        words.extend_from_slice(args);
+        self.push(&words);
+    }
+
+    fn push(&mut self, words: &[&[u8]]) {
+        self.pending
+            .extend_from_slice(format!("*{}\r\n", words.len()).as_bytes());
+        for word in words {
+            self.pending
+                .extend_from_slice(format!("${}\r\n", word.len()).as_bytes());
+            self.pending.extend_from_slice(word);
+            self.pending.extend_from_slice(b"\r\n");
+        }
+    }
+
+    /// Forgets which database the file is in, so the next write starts with
+    /// a SELECT. Used when the writes move to a freshly rewritten file.
+    pub fn reset_db(&mut self) {
+        self.db = None;
+    }
+
+    /// Everything queued since the last call.
+    pub fn take(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.pending)
+    }
+}
+
+/// The contents of an append-only file that rebuilds `databases` with one
+/// command per key (one per entry for streams), each key with a TTL followed
+/// by a `PEXPIREAT` to its deadline.
+pub fn rewrite(databases: &[(usize, Database)]) -> Vec<u8> {
+    let mut out = AofBuffer::default();
+    for (db, keys) in databases {
+        for (key, value, expiry) in keys {
+            let key = key.as_bytes();
+            match value {
+                Value::String(bytes) => out.record(*db, "SET", &[key, bytes]),
+                Value::List(items) => {
+                    let mut args = vec![key];
+                    args.extend(items.iter().map(String::as_bytes));
+                    out.record(*db, "RPUSH", &args);
+                }
+                Value::Hash(fields) => {
+                    let mut args = vec![key];
+                    for (field, value) in fields {
+                        args.extend([field.as_bytes(), value.as_bytes()]);
+                    }
+                    out.record(*db, "HMSET", &args);
+                }
+                Value::Set(members) => {
+                    let mut args = vec![key];
+                    args.extend(members.iter().map(String::as_bytes));
+                    out.record(*db, "SADD", &args);
+                }
+                Value::ZSet(members) => {
+                    let scores: Vec<String> =
+                        members.iter().map(|(_, score)| score.to_string()).collect();
+                    let mut args = vec![key];
+                    for ((member, _), score) in members.iter().zip(&scores) {
+                        args.extend([score.as_bytes(), member.as_bytes()]);
+                    }
+                    out.record(*db, "ZADD", &args);
+                }
+                Value::Stream(entries) => {
+                    for (id, fields) in entries {
+                        let id = id.to_string();
+                        let mut args = vec![key, id.as_bytes()];
+                        for (field, value) in fields {
+                            args.extend([field.as_bytes(), value.as_bytes()]);
+                        }
+                        out.record(*db, "XADD", &args);
+                    }
+                }
+            }
+            if let Some(deadline) = expiry {
+                let ms = deadline
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_millis());
+                out.record(*db, "PEXPIREAT", &[key, ms.to_string().as_bytes()]);
+            }
+        }
+    }
+    out.take()
+}
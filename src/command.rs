@@ -1,80 +1,834 @@
-use tokio::sync::{Mutex, MutexGuard};
+use tokio::sync::RwLock;
 
-use crate::store::Store;
-use crate::value::Value;
+use crate::store::{
+    ListEnd, MAX_DEADLINE_MS, SetCondition, SetOp, SetOptions, Store, StoreError, deadline_in,
+    resolve_range,
+};
+use crate::value::{ScoreBound, StreamEntry, StreamId, Value};
+use std::fmt;
 use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
 
-pub type DB = Arc<Mutex<Store>>;
+pub type DB = Arc<RwLock<Store>>;
+
+/// Reply for a missing single value. Aggregate replies report "nothing" as an
+/// empty array, never as a null bulk.
+const NULL_BULK: &str = "$-1\r\n";
+/// Reply for an aggregate that is absent as a whole, such as an XREAD that
+/// found nothing before its timeout.
+pub const NULL_ARRAY: &str = "*-1\r\n";
+/// Parse error for a numeric argument that is malformed or does not fit,
+/// worded as Redis words it since clients match on the text.
+const NOT_AN_INTEGER: &str = "value is not an integer or out of range";
+/// Like `NOT_AN_INTEGER`, for a sorted-set score.
+const NOT_A_FLOAT: &str = "value is not a valid float";
+pub const INTERNAL_ERROR: &str = "-ERR internal error\r\n";
+pub const PROTOCOL_ERROR: &str = "-ERR Protocol error\r\n";
+pub const REPLY_TOO_LARGE: &str = "-ERR reply too large\r\n";
+pub const WRONGTYPE: &str =
+    "-WRONGTYPE Operation against a key holding the wrong kind of value\r\n";
+
+/// Static description of a command, as reported by `COMMAND INFO`.
+pub struct CommandMeta {
+    pub name: &'static str,
+    /// Number of words including the command name; negative means "at least".
+    pub arity: i64,
+    pub flags: &'static [&'static str],
+    /// Positions of the first and last key argument and the step between
+    /// keys, all zero for commands that take no keys or find them by parsing.
+    pub first_key: i64,
+    pub last_key: i64,
+    pub step: i64,
+}
+
+const fn meta(
+    name: &'static str,
+    arity: i64,
+    flags: &'static [&'static str],
+    first_key: i64,
+    last_key: i64,
+    step: i64,
+) -> CommandMeta {
+    CommandMeta {
+        name,
+        arity,
+        flags,
+        first_key,
+        last_key,
+        step,
+    }
+}
+
+/// Every command `Command::parse` understands.
+pub const COMMAND_TABLE: &[CommandMeta] = &[
+    meta("PING", 1, &["fast"], 0, 0, 0),
+    meta("QUIT", 1, &["fast"], 0, 0, 0),
+    meta(
+        "SHUTDOWN",
+        -1,
+        &["admin", "noscript", "loading", "stale"],
+        0,
+        0,
+        0,
+    ),
+    meta("SET", -3, &["write", "denyoom"], 1, 1, 1),
+    meta("SETNX", 3, &["write", "denyoom", "fast"], 1, 1, 1),
+    meta("SETEX", 4, &["write", "denyoom"], 1, 1, 1),
+    meta("GET", 2, &["readonly", "fast"], 1, 1, 1),
+    meta("DEL", -2, &["write"], 1, -1, 1),
+    meta("UNLINK", -2, &["write", "fast"], 1, -1, 1),
+    meta("GETSET", 3, &["write", "denyoom", "fast"], 1, 1, 1),
+    meta("GETDEL", 2, &["write", "fast"], 1, 1, 1),
+    meta("MSET", -3, &["write", "denyoom"], 1, -1, 2),
+    meta("MGET", -2, &["readonly", "fast"], 1, -1, 1),
+    meta("EXPIRE", -3, &["write", "fast"], 1, 1, 1),
+    meta("TTL", 2, &["readonly", "fast"], 1, 1, 1),
+    meta("PEXPIRE", 3, &["write", "fast"], 1, 1, 1),
+    meta("EXPIREAT", 3, &["write", "fast"], 1, 1, 1),
+    meta("PEXPIREAT", 3, &["write", "fast"], 1, 1, 1),
+    meta("PERSIST", 2, &["write", "fast"], 1, 1, 1),
+    meta("PTTL", 2, &["readonly", "fast"], 1, 1, 1),
+    meta("EXPIRETIME", 2, &["readonly", "fast"], 1, 1, 1),
+    meta("PEXPIRETIME", 2, &["readonly", "fast"], 1, 1, 1),
+    meta("RANDOMKEY", 1, &["readonly"], 0, 0, 0),
+    meta("KEYS", 2, &["readonly"], 0, 0, 0),
+    meta("DBSIZE", 1, &["readonly", "fast"], 0, 0, 0),
+    meta("INFO", -1, &["loading", "stale"], 0, 0, 0),
+    meta("SCAN", -2, &["readonly"], 0, 0, 0),
+    meta("EXISTS", -2, &["readonly", "fast"], 1, -1, 1),
+    meta("TOUCH", -2, &["readonly", "fast"], 1, -1, 1),
+    meta("TYPE", 2, &["readonly", "fast"], 1, 1, 1),
+    meta("STRLEN", 2, &["readonly", "fast"], 1, 1, 1),
+    meta("APPEND", 3, &["write", "denyoom", "fast"], 1, 1, 1),
+    meta("GETRANGE", 4, &["readonly"], 1, 1, 1),
+    meta("SETRANGE", 4, &["write", "denyoom"], 1, 1, 1),
+    meta("SETBIT", 4, &["write", "denyoom"], 1, 1, 1),
+    meta("GETBIT", 3, &["readonly", "fast"], 1, 1, 1),
+    meta("BITCOUNT", 2, &["readonly"], 1, 1, 1),
+    meta("INCRBY", -3, &["write", "denyoom", "fast"], 1, 1, 1),
+    meta("DECRBY", 3, &["write", "denyoom", "fast"], 1, 1, 1),
+    meta("INCR", 2, &["write", "denyoom", "fast"], 1, 1, 1),
+    meta("DECR", 2, &["write", "denyoom", "fast"], 1, 1, 1),
+    meta("RENAME", 3, &["write"], 1, 2, 1),
+    meta("RENAMENX", 3, &["write", "fast"], 1, 2, 1),
+    meta("RENAMEPX", 4, &["write"], 1, 2, 1),
+    meta("COPY", -3, &["write", "denyoom"], 1, 2, 1),
+    meta("MOVE", 3, &["write", "fast"], 1, 1, 1),
+    meta("CAD", 3, &["write", "fast"], 1, 1, 1),
+    meta("CAS", 4, &["write", "denyoom", "fast"], 1, 1, 1),
+    meta("LPUSH", -3, &["write", "denyoom", "fast"], 1, 1, 1),
+    meta("RPUSH", -3, &["write", "denyoom", "fast"], 1, 1, 1),
+    meta("LPOP", -2, &["write", "fast"], 1, 1, 1),
+    meta("RPOP", -2, &["write", "fast"], 1, 1, 1),
+    meta("LRANGE", 4, &["readonly"], 1, 1, 1),
+    meta("LREM", 4, &["write"], 1, 1, 1),
+    meta("RPOPLPUSH", 3, &["write", "denyoom"], 1, 2, 1),
+    meta("LMOVE", 5, &["write", "denyoom"], 1, 2, 1),
+    meta("LINDEX", 3, &["readonly"], 1, 1, 1),
+    meta("LSET", 4, &["write", "denyoom"], 1, 1, 1),
+    meta("LLEN", 2, &["readonly", "fast"], 1, 1, 1),
+    meta("HSET", 4, &["write", "denyoom", "fast"], 1, 1, 1),
+    meta("HGET", 3, &["readonly", "fast"], 1, 1, 1),
+    meta("HDEL", 3, &["write", "fast"], 1, 1, 1),
+    meta("HGETALL", -2, &["readonly"], 1, 1, 1),
+    meta("HINCRBY", 4, &["write", "denyoom", "fast"], 1, 1, 1),
+    meta("HLEN", 2, &["readonly", "fast"], 1, 1, 1),
+    meta("HMSET", -4, &["write", "denyoom", "fast"], 1, 1, 1),
+    meta("HMGET", -3, &["readonly", "fast"], 1, 1, 1),
+    meta("HEXISTS", 3, &["readonly", "fast"], 1, 1, 1),
+    meta("HKEYS", 2, &["readonly"], 1, 1, 1),
+    meta("HVALS", 2, &["readonly"], 1, 1, 1),
+    meta("HGETDEL", -5, &["write", "fast"], 1, 1, 1),
+    meta("HSCAN", -3, &["readonly"], 1, 1, 1),
+    meta("HGETEX", -5, &["write", "fast"], 1, 1, 1),
+    meta("SADD", -3, &["write", "denyoom", "fast"], 1, 1, 1),
+    meta("SREM", -3, &["write", "fast"], 1, 1, 1),
+    meta("SISMEMBER", 3, &["readonly", "fast"], 1, 1, 1),
+    meta("SMEMBERS", 2, &["readonly"], 1, 1, 1),
+    meta("SSCAN", -3, &["readonly"], 1, 1, 1),
+    meta("SCARD", 2, &["readonly", "fast"], 1, 1, 1),
+    meta("SINTER", -2, &["readonly"], 1, -1, 1),
+    meta("SUNION", -2, &["readonly"], 1, -1, 1),
+    meta("SDIFF", -2, &["readonly"], 1, -1, 1),
+    meta("SINTERSTORE", -3, &["write", "denyoom"], 1, -1, 1),
+    meta("SUNIONSTORE", -3, &["write", "denyoom"], 1, -1, 1),
+    meta("SDIFFSTORE", -3, &["write", "denyoom"], 1, -1, 1),
+    meta("ZADD", -4, &["write", "denyoom", "fast"], 1, 1, 1),
+    meta("ZSCORE", 3, &["readonly", "fast"], 1, 1, 1),
+    meta("ZRANK", 3, &["readonly", "fast"], 1, 1, 1),
+    meta("ZRANGE", -4, &["readonly"], 1, 1, 1),
+    meta("ZCARD", 2, &["readonly", "fast"], 1, 1, 1),
+    meta("ZRANGEBYSCORE", -4, &["readonly"], 1, 1, 1),
+    meta("ZREM", -3, &["write", "fast"], 1, 1, 1),
+    meta("XADD", -5, &["write", "denyoom", "fast"], 1, 1, 1),
+    meta("XLEN", 2, &["readonly", "fast"], 1, 1, 1),
+    meta("XRANGE", -4, &["readonly"], 1, 1, 1),
+    meta(
+        "XREAD",
+        -4,
+        &["readonly", "blocking", "movablekeys"],
+        0,
+        0,
+        0,
+    ),
+    meta("EVAL", -3, &["write", "noscript", "movablekeys"], 0, 0, 0),
+    meta(
+        "EVALSHA",
+        -3,
+        &["write", "noscript", "movablekeys"],
+        0,
+        0,
+        0,
+    ),
+    meta("SELECT", 2, &["loading", "stale", "fast"], 0, 0, 0),
+    meta(
+        "CLIENT",
+        -2,
+        &["admin", "noscript", "loading", "stale"],
+        0,
+        0,
+        0,
+    ),
+    meta("FLUSHDB", 1, &["write"], 0, 0, 0),
+    meta("FLUSHALL", 1, &["write"], 0, 0, 0),
+    meta(
+        "MULTI",
+        1,
+        &["noscript", "loading", "stale", "fast"],
+        0,
+        0,
+        0,
+    ),
+    meta("EXEC", 1, &["noscript", "loading", "stale"], 0, 0, 0),
+    meta(
+        "DISCARD",
+        1,
+        &["noscript", "loading", "stale", "fast"],
+        0,
+        0,
+        0,
+    ),
+    meta(
+        "SUBSCRIBE",
+        -2,
+        &["pubsub", "noscript", "loading", "stale"],
+        0,
+        0,
+        0,
+    ),
+    meta(
+        "UNSUBSCRIBE",
+        -1,
+        &["pubsub", "noscript", "loading", "stale"],
+        0,
+        0,
+        0,
+    ),
+    meta(
+        "PUBLISH",
+        3,
+        &["pubsub", "loading", "stale", "fast"],
+        0,
+        0,
+        0,
+    ),
+    meta(
+        "PUBSUB",
+        -2,
+        &["pubsub", "random", "loading", "stale"],
+        0,
+        0,
+        0,
+    ),
+    meta(
+        "HELLO",
+        -1,
+        &["noscript", "loading", "stale", "fast"],
+        0,
+        0,
+        0,
+    ),
+    meta(
+        "AUTH",
+        2,
+        &["noscript", "loading", "stale", "fast", "no_auth"],
+        0,
+        0,
+        0,
+    ),
+    meta(
+        "REPLCONF",
+        -1,
+        &["admin", "noscript", "loading", "stale"],
+        0,
+        0,
+        0,
+    ),
+    meta("PSYNC", 3, &["admin", "noscript"], 0, 0, 0),
+    meta("WAIT", 3, &["noscript"], 0, 0, 0),
+    meta("SAVE", 1, &["admin", "noscript"], 0, 0, 0),
+    meta("BGSAVE", 1, &["admin", "noscript"], 0, 0, 0),
+    meta("BGREWRITEAOF", 1, &["admin", "noscript"], 0, 0, 0),
+    meta("OBJECT", -2, &["readonly"], 2, 2, 1),
+    meta(
+        "CONFIG",
+        -2,
+        &["admin", "noscript", "loading", "stale"],
+        0,
+        0,
+        0,
+    ),
+    meta(
+        "LATENCY",
+        -2,
+        &["admin", "noscript", "loading", "stale"],
+        0,
+        0,
+        0,
+    ),
+    meta(
+        "DEBUG",
+        -2,
+        &["admin", "noscript", "loading", "stale"],
+        0,
+        0,
+        0,
+    ),
+    meta("COMMAND", -1, &["loading", "stale"], 0, 0, 0),
+];
 
 #[derive(Debug, Clone)]
 pub enum Command {
     Ping,
     Quit,
+    /// `SHUTDOWN [NOSAVE|SAVE]`; true when a save was asked for.
+    Shutdown(bool),
 
-    Set(String, Value),
+    /// `SET key value [EX seconds | PX milliseconds] [NX | XX]`.
+    Set(String, Value, SetOptions),
+    SetNx(String, Vec<u8>),
+    /// `SETEX key seconds value`.
+    SetEx(String, u64, Vec<u8>),
     Get(String),
-    Del(String),
-    Expire(String, u64),
-    TTL(String),
-    Exists(String),
+    Del(Vec<String>),
+    Unlink(Vec<String>),
+    GetSet(String, Vec<u8>),
+    GetDel(String),
+    MSet(Vec<(String, Vec<u8>)>),
+    MGet(Vec<String>),
+    /// `EXPIRE key seconds [JITTER seconds]`.
+    Expire(String, u64, u64),
+    Ttl(String),
+    PExpire(String, u64),
+    ExpireAt(String, u64),
+    PExpireAt(String, u64),
+    Persist(String),
+    PTtl(String),
+    ExpireTime(String),
+    PExpireTime(String),
+    Exists(Vec<String>),
+    Touch(Vec<String>),
+    Keys(String),
+    RandomKey,
+    DbSize,
+    /// `INFO [section]`.
+    Info(Option<String>),
+    /// `SCAN cursor [MATCH pattern] [COUNT n]`.
+    Scan(u64, usize, Option<String>),
+    Type(String),
     Strlen(String),
+    Append(String, Vec<u8>),
+    GetRange(String, i64, i64),
+    SetRange(String, usize, Vec<u8>),
+    SetBit(String, u64, bool),
+    GetBit(String, u64),
+    BitCount(String),
     IncrBy(String, i64),
+    IncrByEx(String, i64, u64),
     DecrBy(String, i64),
     Incr(String),
     Decr(String),
+    Rename(String, String),
+    RenameNx(String, String),
+    RenamePx(String, String, u64),
+    Copy(String, String, bool),
+    /// `MOVE key db`.
+    Move(String, usize),
+    Cad(String, Vec<u8>),
+    Cas(String, Vec<u8>, Vec<u8>),
 
     LPush(String, Vec<String>),
     RPush(String, Vec<String>),
-    LPop(String),
-    RPop(String),
-    LRange(String, usize, usize),
+    /// `LPOP key [count]`; with a count the reply is an array.
+    LPop(String, Option<usize>),
+    RPop(String, Option<usize>),
+    LRange(String, i64, i64),
     LRem(String, i64, String),
-    LIndex(String, usize),
-    LSet(String, usize, String),
+    RPopLPush(String, String),
+    /// `LMOVE source destination LEFT|RIGHT LEFT|RIGHT`.
+    LMove(String, String, ListEnd, ListEnd),
+    LIndex(String, i64),
+    LSet(String, i64, String),
     LLen(String),
 
     HSet(String, String, String),
     HGet(String, String),
     HDel(String, String),
-    HGetAll(String),
+    /// `HGETALL key [SORT]`. Without `SORT` fields come back in hash order,
+    /// which is arbitrary and may differ between runs; with it they are
+    /// sorted by field name.
+    HGetAll(String, bool),
     HIncrBy(String, String, i64),
     HLen(String),
+    HMSet(String, Vec<(String, String)>),
+    HMGet(String, Vec<String>),
+    HExists(String, String),
+    HKeys(String),
+    HVals(String),
+    HGetDel(String, Vec<String>),
+    HGetEx(String, Vec<String>),
+    /// `HSCAN key cursor [COUNT n] [NOVALUES]`; with `NOVALUES` only field
+    /// names are returned.
+    HScan(String, usize, usize, bool),
+
+    SAdd(String, Vec<String>),
+    SRem(String, Vec<String>),
+    SIsMember(String, String),
+    SMembers(String),
+    /// `SSCAN key cursor [MATCH pattern] [COUNT n]`; members not matching
+    /// the glob are dropped from each page after it is taken.
+    SScan(String, usize, usize, Option<String>),
+    SCard(String),
+    SInter(Vec<String>),
+    SUnion(Vec<String>),
+    SDiff(Vec<String>),
+    /// `SINTERSTORE destination key...`; likewise for the other two.
+    SInterStore(String, Vec<String>),
+    SUnionStore(String, Vec<String>),
+    SDiffStore(String, Vec<String>),
+
+    /// `ZADD key score member [score member ...]`.
+    ZAdd(String, Vec<(f64, String)>),
+    ZScore(String, String),
+    ZRank(String, String),
+    /// `ZRANGE key start stop [WITHSCORES]`.
+    ZRange(String, i64, i64, bool),
+    ZCard(String),
+    /// `ZRANGEBYSCORE key min max [WITHSCORES]`.
+    ZRangeByScore(String, ScoreBound, ScoreBound, bool),
+    ZRem(String, Vec<String>),
+
+    XAdd(String, Option<StreamId>, Vec<(String, String)>),
+    XLen(String),
+    XRange(String, StreamId, StreamId, Option<usize>),
+    /// `XREAD [COUNT n] [BLOCK ms] STREAMS key... id...`; an id of `None`
+    /// stands for `$`, i.e. only entries added after the call.
+    XRead(Option<usize>, Option<u64>, Vec<(String, Option<StreamId>)>),
+
+    /// `EVAL`/`EVALSHA name numkeys key... arg...`, running the registered
+    /// script `name` with the given KEYS and ARGV.
+    Eval(String, Vec<String>, Vec<String>),
+
+    Select(usize),
+    FlushDb,
+    FlushAll,
+
+    /// `MULTI`, `EXEC` and `DISCARD`, handled by the server since the
+    /// transaction belongs to the connection.
+    Multi,
+    Exec,
+    Discard,
+
+    /// `SUBSCRIBE channel...`, handled by the server since it changes the
+    /// connection rather than the store.
+    Subscribe(Vec<String>),
+    /// `UNSUBSCRIBE [channel...]`; no channels means all of them.
+    Unsubscribe(Vec<String>),
+    Publish(String, String),
+    /// `PUBSUB CHANNELS [pattern]`: channels with at least one subscriber.
+    PubSubChannels(Option<String>),
+    /// `PUBSUB NUMSUB [channel...]`.
+    PubSubNumSub(Vec<String>),
+    /// `PUBSUB NUMPAT`, always 0 as there are no pattern subscriptions.
+    PubSubNumPat,
+    /// `HELLO [protover]`, handled by the server since the protocol version
+    /// belongs to the connection.
+    Hello(Option<i64>),
+    /// `AUTH password`, handled by the server since being authenticated
+    /// belongs to the connection.
+    Auth(String),
+    /// `REPLCONF option value...`, accepted and ignored: there is nothing a
+    /// replica can configure yet.
+    ReplConf,
+    /// `PSYNC replid offset`, handled by the server since it turns the
+    /// connection into a replication feed. Always a full resync.
+    PSync,
+    /// `WAIT numreplicas timeout`. Neither argument matters yet, since it
+    /// never has to wait.
+    Wait,
+
+    Save,
+    BgSave,
+    BgRewriteAof,
+
+    ObjectEncoding(String),
+    ObjectRefCount(String),
+    ObjectIdleTime(String),
+    ConfigGet(String),
+    ConfigSet(String, String),
+    /// `CLIENT NO-TOUCH ON|OFF`: whether the connection's lookups leave key
+    /// access times alone.
+    ClientNoTouch(bool),
+    /// `CLIENT NO-EVICT ON|OFF`, accepted for compatibility. Keys are never
+    /// evicted, so the setting is not kept.
+    ClientNoEvict,
+
+    LatencyHistory(String),
+    LatencyLatest,
+    LatencyReset(Vec<String>),
+
+    DebugSetActiveExpire(bool),
+    DebugObject(String),
+    DebugDumpStruct(String, usize),
+    DebugConsistencyCheck,
+    /// `DEBUG RELOAD`: saves a snapshot and loads the dataset back from it.
+    DebugReload,
+    /// `DEBUG SLEEP`, which the server runs without holding the store lock.
+    DebugSleep(Duration),
+    /// `DEBUG LOCKED-SLEEP`, which keeps the store locked while it sleeps.
+    DebugLockedSleep(Duration),
+
+    CmdCount,
+    /// `COMMAND INFO [name ...]`; no names means every command.
+    CmdInfo(Vec<String>),
+    /// `COMMAND DOCS [name ...]`; no names means every command.
+    CmdDocs(Vec<String>),
+}
+
+/// Why a request could not be parsed into a `Command`.
+#[derive(Debug)]
+pub enum ParseError {
+    /// No command has this name. Holds the name and arguments as given, and
+    /// the closest known command if any is close enough to suggest.
+    UnknownCommand {
+        name: String,
+        args: Vec<String>,
+        suggestion: Option<&'static str>,
+    },
+    /// A known command, by upper-case name, given a number of arguments it
+    /// never takes.
+    WrongArity(String),
+    /// Anything else, such as an argument that is not a valid integer.
+    Invalid(String),
+}
+
+impl ParseError {
+    /// The error for `parts`, whose first word names no command the client
+    /// may run.
+    pub fn unknown_command<T: AsRef<[u8]>>(parts: &[T]) -> Self {
+        let mut words = parts
+            .iter()
+            .map(|part| String::from_utf8_lossy(part.as_ref()).into_owned());
+        let name = words.next().unwrap_or_default();
+        let upper = name.to_uppercase();
+        ParseError::UnknownCommand {
+            name,
+            args: words.collect(),
+            // A disabled command is its own closest match; never suggest it.
+            suggestion: suggest_command(&upper).filter(|&candidate| candidate != upper),
+        }
+    }
+}
+
+impl From<String> for ParseError {
+    fn from(message: String) -> Self {
+        ParseError::Invalid(message)
+    }
+}
+
+impl From<&str> for ParseError {
+    fn from(message: &str) -> Self {
+        ParseError::Invalid(message.to_string())
+    }
+}
+
+/// The message of an `-ERR` reply, worded as Redis words it.
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::UnknownCommand {
+                name,
+                args,
+                suggestion,
+            } => {
+                write!(f, "unknown command '{}', with args beginning with: ", name)?;
+                for arg in args {
+                    write!(f, "'{}' ", arg)?;
+                }
+                match suggestion {
+                    Some(candidate) => write!(f, "(did you mean '{}'?)", candidate.to_lowercase()),
+                    None => Ok(()),
+                }
+            }
+            ParseError::WrongArity(name) => write!(
+                f,
+                "wrong number of arguments for '{}' command",
+                name.to_lowercase()
+            ),
+            ParseError::Invalid(message) => f.write_str(message),
+        }
+    }
 }
 
 impl Command {
-    pub fn parse(input: &String) -> Result<Command, String> {
-        let parts: Vec<&str> = input.trim().split_whitespace().collect();
+    /// Parses a request already split into words, from either an inline
+    /// command or a multi-bulk array. String values are binary-safe and taken
+    /// as raw bytes; every other word must be UTF-8.
+    pub fn parse(parts: &[&[u8]]) -> Result<Command, ParseError> {
         if parts.is_empty() {
-            return Err("Empty command".to_string());
+            return Err(ParseError::Invalid("Empty command".to_string()));
+        }
+
+        let cmd = String::from_utf8_lossy(parts[0]).to_uppercase();
+        if let Some(command) = Command::parse_string_write(&cmd, &parts[1..])? {
+            return Ok(command);
         }
+        let words = parts
+            .iter()
+            .map(|part| text(part))
+            .collect::<Result<Vec<_>, _>>()?;
+        Command::parse_words(&words)
+    }
+
+    /// Parses the commands that store a string value, taking the value as
+    /// given, byte for byte. `None` if `cmd` is not one of them or has the
+    /// wrong number of arguments for it.
+    fn parse_string_write(cmd: &str, args: &[&[u8]]) -> Result<Option<Command>, ParseError> {
+        let command = match cmd {
+            "SET" if args.len() >= 2 => {
+                let options = args[2..]
+                    .iter()
+                    .map(|arg| text(arg))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Command::Set(
+                    text(args[0])?.to_string(),
+                    Value::from(args[1].to_vec()),
+                    parse_set_options(&options)?,
+                )
+            }
+            "SETNX" if args.len() == 2 => {
+                Command::SetNx(text(args[0])?.to_string(), args[1].to_vec())
+            }
+            "SETEX" if args.len() == 3 => {
+                let seconds = text(args[1])?
+                    .parse::<u64>()
+                    .map_err(|_| NOT_AN_INTEGER.to_string())?;
+                if seconds == 0 || deadline_in(Duration::from_secs(seconds)).is_none() {
+                    return Err("invalid expire time in 'setex' command".into());
+                }
+                Command::SetEx(text(args[0])?.to_string(), seconds, args[2].to_vec())
+            }
+            "GETSET" if args.len() == 2 => {
+                Command::GetSet(text(args[0])?.to_string(), args[1].to_vec())
+            }
+            "MSET" if !args.is_empty() && args.len().is_multiple_of(2) => Command::MSet(
+                args.chunks(2)
+                    .map(|pair| Ok((text(pair[0])?.to_string(), pair[1].to_vec())))
+                    .collect::<Result<_, ParseError>>()?,
+            ),
+            "APPEND" if args.len() == 2 => {
+                Command::Append(text(args[0])?.to_string(), args[1].to_vec())
+            }
+            "SETRANGE" if args.len() == 3 => {
+                let offset = text(args[1])?
+                    .parse::<usize>()
+                    .map_err(|_| "offset is out of range".to_string())?;
+                Command::SetRange(text(args[0])?.to_string(), offset, args[2].to_vec())
+            }
+            "CAD" if args.len() == 2 => Command::Cad(text(args[0])?.to_string(), args[1].to_vec()),
+            "CAS" if args.len() == 3 => Command::Cas(
+                text(args[0])?.to_string(),
+                args[1].to_vec(),
+                args[2].to_vec(),
+            ),
+            _ => return Ok(None),
+        };
+        Ok(Some(command))
+    }
 
+    /// Parses every other command, once its words are known to be text.
+    fn parse_words(parts: &[&str]) -> Result<Command, ParseError> {
         let cmd = parts[0].to_uppercase();
         let args = &parts[1..];
 
         match cmd.as_str() {
-            "SET" if args.len() == 2 => Ok(Command::Set(
-                args[0].to_string(),
-                Value::from(args[1].to_string()),
-            )),
             "GET" if args.len() == 1 => Ok(Command::Get(args[0].to_string())),
-            "DEL" if args.len() == 1 => Ok(Command::Del(args[0].to_string())),
+            "DEL" if !args.is_empty() => Ok(Command::Del(
+                args.iter().map(|&key| key.to_string()).collect(),
+            )),
+            "UNLINK" if !args.is_empty() => Ok(Command::Unlink(
+                args.iter().map(|&key| key.to_string()).collect(),
+            )),
+            "GETDEL" if args.len() == 1 => Ok(Command::GetDel(args[0].to_string())),
+            "MGET" if !args.is_empty() => Ok(Command::MGet(
+                args.iter().map(|&key| key.to_string()).collect(),
+            )),
             "EXPIRE" if args.len() == 2 => match args[1].parse::<u64>() {
-                Ok(time) => Ok(Command::Expire(args[0].to_string(), time)),
-                Err(_) => Err("Invalid time".to_string()),
+                Ok(time) if deadline_in(Duration::from_secs(time)).is_none() => {
+                    Err("invalid expire time in 'expire' command".into())
+                }
+                Ok(time) => Ok(Command::Expire(args[0].to_string(), time, 0)),
+                Err(_) => Err(NOT_AN_INTEGER.into()),
+            },
+            "EXPIRE" if args.len() == 4 && args[2].eq_ignore_ascii_case("JITTER") => {
+                let time = args[1]
+                    .parse::<u64>()
+                    .map_err(|_| NOT_AN_INTEGER.to_string())?;
+                let jitter = args[3]
+                    .parse::<u64>()
+                    .map_err(|_| NOT_AN_INTEGER.to_string())?;
+                Ok(Command::Expire(args[0].to_string(), time, jitter))
+            }
+            "TTL" if args.len() == 1 => Ok(Command::Ttl(args[0].to_string())),
+            "PEXPIRE" if args.len() == 2 => match args[1].parse::<u64>() {
+                Ok(ms) if deadline_in(Duration::from_millis(ms)).is_none() => {
+                    Err("invalid expire time in 'pexpire' command".into())
+                }
+                Ok(ms) => Ok(Command::PExpire(args[0].to_string(), ms)),
+                Err(_) => Err(NOT_AN_INTEGER.into()),
+            },
+            "EXPIREAT" if args.len() == 2 => match args[1].parse::<u64>() {
+                Ok(secs) if secs > MAX_DEADLINE_MS / 1000 => {
+                    Err("invalid expire time in 'expireat' command".into())
+                }
+                Ok(secs) => Ok(Command::ExpireAt(args[0].to_string(), secs)),
+                Err(_) => Err(NOT_AN_INTEGER.into()),
+            },
+            "PEXPIREAT" if args.len() == 2 => match args[1].parse::<u64>() {
+                Ok(ms) if ms > MAX_DEADLINE_MS => {
+                    Err("invalid expire time in 'pexpireat' command".into())
+                }
+                Ok(ms) => Ok(Command::PExpireAt(args[0].to_string(), ms)),
+                Err(_) => Err(NOT_AN_INTEGER.into()),
             },
-            "TTL" if args.len() == 1 => Ok(Command::TTL(args[0].to_string())),
-            "EXISTS" if args.len() == 1 => Ok(Command::Exists(args[0].to_string())),
+            "PERSIST" if args.len() == 1 => Ok(Command::Persist(args[0].to_string())),
+            "PTTL" if args.len() == 1 => Ok(Command::PTtl(args[0].to_string())),
+            "EXPIRETIME" if args.len() == 1 => Ok(Command::ExpireTime(args[0].to_string())),
+            "PEXPIRETIME" if args.len() == 1 => Ok(Command::PExpireTime(args[0].to_string())),
+            "KEYS" if args.len() == 1 => Ok(Command::Keys(args[0].to_string())),
+            "RANDOMKEY" if args.is_empty() => Ok(Command::RandomKey),
+            "DBSIZE" if args.is_empty() => Ok(Command::DbSize),
+            "INFO" if args.len() <= 1 => Ok(Command::Info(args.first().map(|s| s.to_string()))),
+            "SCAN" if args.len() % 2 == 1 => {
+                let cursor = args[0]
+                    .parse::<u64>()
+                    .map_err(|_| "invalid cursor".to_string())?;
+                let mut count = 10;
+                let mut pattern = None;
+                for option in args[1..].chunks(2) {
+                    match option[0].to_uppercase().as_str() {
+                        "COUNT" => {
+                            count = option[1]
+                                .parse::<usize>()
+                                .ok()
+                                .filter(|&n| n > 0)
+                                .ok_or_else(|| NOT_AN_INTEGER.to_string())?;
+                        }
+                        "MATCH" => pattern = Some(option[1].to_string()),
+                        _ => return Err(format!("Invalid SCAN option: {}", option[0]).into()),
+                    }
+                }
+                Ok(Command::Scan(cursor, count, pattern))
+            }
+            "EXISTS" if !args.is_empty() => Ok(Command::Exists(
+                args.iter().map(|&key| key.to_string()).collect(),
+            )),
+            "TOUCH" if !args.is_empty() => Ok(Command::Touch(
+                args.iter().map(|&key| key.to_string()).collect(),
+            )),
+            "TYPE" if args.len() == 1 => Ok(Command::Type(args[0].to_string())),
             "STRLEN" if args.len() == 1 => Ok(Command::Strlen(args[0].to_string())),
+            "GETRANGE" if args.len() == 3 => {
+                let start = args[1]
+                    .parse::<i64>()
+                    .map_err(|_| NOT_AN_INTEGER.to_string())?;
+                let end = args[2]
+                    .parse::<i64>()
+                    .map_err(|_| NOT_AN_INTEGER.to_string())?;
+                Ok(Command::GetRange(args[0].to_string(), start, end))
+            }
+            "SETBIT" if args.len() == 3 => {
+                let on = match args[2] {
+                    "0" => false,
+                    "1" => true,
+                    _ => return Err("bit is not an integer or out of range".into()),
+                };
+                Ok(Command::SetBit(
+                    args[0].to_string(),
+                    parse_bit_offset(args[1])?,
+                    on,
+                ))
+            }
+            "GETBIT" if args.len() == 2 => Ok(Command::GetBit(
+                args[0].to_string(),
+                parse_bit_offset(args[1])?,
+            )),
+            "BITCOUNT" if args.len() == 1 => Ok(Command::BitCount(args[0].to_string())),
             "INCRBY" if args.len() == 2 => match args[1].parse::<i64>() {
                 Ok(value) => Ok(Command::IncrBy(args[0].to_string(), value)),
-                Err(_) => Err("Invalid value".to_string()),
+                Err(_) => Err(NOT_AN_INTEGER.into()),
             },
+            "INCRBY" if args.len() == 4 && args[2].eq_ignore_ascii_case("EX") => {
+                let value = args[1]
+                    .parse::<i64>()
+                    .map_err(|_| NOT_AN_INTEGER.to_string())?;
+                let seconds = args[3]
+                    .parse::<u64>()
+                    .map_err(|_| NOT_AN_INTEGER.to_string())?;
+                if seconds == 0 {
+                    return Err("invalid expire time in 'incrby' command".into());
+                }
+                Ok(Command::IncrByEx(args[0].to_string(), value, seconds))
+            }
             "DECRBY" if args.len() == 2 => match args[1].parse::<i64>() {
                 Ok(value) => Ok(Command::DecrBy(args[0].to_string(), value)),
-                Err(_) => Err("Invalid value".to_string()),
+                Err(_) => Err(NOT_AN_INTEGER.into()),
             },
             "INCR" if args.len() == 1 => Ok(Command::Incr(args[0].to_string())),
             "DECR" if args.len() == 1 => Ok(Command::Decr(args[0].to_string())),
+            "RENAME" if args.len() == 2 => {
+                Ok(Command::Rename(args[0].to_string(), args[1].to_string()))
+            }
+            "RENAMENX" if args.len() == 2 => {
+                Ok(Command::RenameNx(args[0].to_string(), args[1].to_string()))
+            }
+            "RENAMEPX" if args.len() == 3 => {
+                let ms = args[2]
+                    .parse::<u64>()
+                    .map_err(|_| NOT_AN_INTEGER.to_string())?;
+                Ok(Command::RenamePx(
+                    args[0].to_string(),
+                    args[1].to_string(),
+                    ms,
+                ))
+            }
+            "COPY" if args.len() == 2 || args.len() == 3 => {
+                let replace = match args.get(2) {
+                    None => false,
+                    Some(flag) if flag.eq_ignore_ascii_case("REPLACE") => true,
+                    Some(_) => return Err("syntax error".into()),
+                };
+                Ok(Command::Copy(
+                    args[0].to_string(),
+                    args[1].to_string(),
+                    replace,
+                ))
+            }
+            "MOVE" if args.len() == 2 => match args[1].parse::<usize>() {
+                Ok(db) => Ok(Command::Move(args[0].to_string(), db)),
+                Err(_) => Err("DB index is out of range".into()),
+            },
 
             "LPUSH" if args.len() >= 2 => {
                 let values = args[1..].iter().map(|&s| s.to_string()).collect();
@@ -84,21 +838,44 @@ impl Command {
                 let values = args[1..].iter().map(|&s| s.to_string()).collect();
                 Ok(Command::RPush(args[0].to_string(), values))
             }
-            "LPOP" if args.len() == 1 => Ok(Command::LPop(args[0].to_string())),
-            "RPOP" if args.len() == 1 => Ok(Command::RPop(args[0].to_string())),
+            "LPOP" | "RPOP" if args.len() == 1 || args.len() == 2 => {
+                let count = match args.get(1) {
+                    Some(count) => Some(
+                        count
+                            .parse::<usize>()
+                            .map_err(|_| NOT_AN_INTEGER.to_string())?,
+                    ),
+                    None => None,
+                };
+                let key = args[0].to_string();
+                Ok(if cmd == "LPOP" {
+                    Command::LPop(key, count)
+                } else {
+                    Command::RPop(key, count)
+                })
+            }
             "LRANGE" if args.len() == 3 => {
                 let start = args[1]
-                    .parse::<usize>()
-                    .map_err(|_| "Invalid start".to_string())?;
+                    .parse::<i64>()
+                    .map_err(|_| NOT_AN_INTEGER.to_string())?;
                 let end = args[2]
-                    .parse::<usize>()
-                    .map_err(|_| "Invalid end".to_string())?;
+                    .parse::<i64>()
+                    .map_err(|_| NOT_AN_INTEGER.to_string())?;
                 Ok(Command::LRange(args[0].to_string(), start, end))
             }
+            "RPOPLPUSH" if args.len() == 2 => {
+                Ok(Command::RPopLPush(args[0].to_string(), args[1].to_string()))
+            }
+            "LMOVE" if args.len() == 4 => Ok(Command::LMove(
+                args[0].to_string(),
+                args[1].to_string(),
+                parse_list_end(args[2])?,
+                parse_list_end(args[3])?,
+            )),
             "LREM" if args.len() == 3 => {
                 let count = args[1]
                     .parse::<i64>()
-                    .map_err(|_| "Invalid count".to_string())?;
+                    .map_err(|_| NOT_AN_INTEGER.to_string())?;
                 Ok(Command::LRem(
                     args[0].to_string(),
                     count,
@@ -107,14 +884,14 @@ impl Command {
             }
             "LINDEX" if args.len() == 2 => {
                 let index = args[1]
-                    .parse::<usize>()
-                    .map_err(|_| "Invalid index".to_string())?;
+                    .parse::<i64>()
+                    .map_err(|_| NOT_AN_INTEGER.to_string())?;
                 Ok(Command::LIndex(args[0].to_string(), index))
             }
             "LSET" if args.len() == 3 => {
                 let index = args[1]
-                    .parse::<usize>()
-                    .map_err(|_| "Invalid index".to_string())?;
+                    .parse::<i64>()
+                    .map_err(|_| NOT_AN_INTEGER.to_string())?;
                 Ok(Command::LSet(
                     args[0].to_string(),
                     index,
@@ -134,11 +911,14 @@ impl Command {
             "HDEL" if args.len() == 2 => {
                 Ok(Command::HDel(args[0].to_string(), args[1].to_string()))
             }
-            "HGETALL" if args.len() == 1 => Ok(Command::HGetAll(args[0].to_string())),
+            "HGETALL" if args.len() == 1 => Ok(Command::HGetAll(args[0].to_string(), false)),
+            "HGETALL" if args.len() == 2 && args[1].eq_ignore_ascii_case("SORT") => {
+                Ok(Command::HGetAll(args[0].to_string(), true))
+            }
             "HINCRBY" if args.len() == 3 => {
                 let value = args[2]
                     .parse::<i64>()
-                    .map_err(|_| "Invalid value".to_string())?;
+                    .map_err(|_| NOT_AN_INTEGER.to_string())?;
                 Ok(Command::HIncrBy(
                     args[0].to_string(),
                     args[1].to_string(),
@@ -146,139 +926,2661 @@ impl Command {
                 ))
             }
             "HLEN" if args.len() == 1 => Ok(Command::HLen(args[0].to_string())),
+            "HMSET" if args.len() >= 3 && args.len() % 2 == 1 => Ok(Command::HMSet(
+                args[0].to_string(),
+                args[1..]
+                    .chunks(2)
+                    .map(|pair| (pair[0].to_string(), pair[1].to_string()))
+                    .collect(),
+            )),
+            "HMGET" if args.len() >= 2 => Ok(Command::HMGet(
+                args[0].to_string(),
+                args[1..].iter().map(|&field| field.to_string()).collect(),
+            )),
+            "HEXISTS" if args.len() == 2 => {
+                Ok(Command::HExists(args[0].to_string(), args[1].to_string()))
+            }
+            "HKEYS" if args.len() == 1 => Ok(Command::HKeys(args[0].to_string())),
+            "HVALS" if args.len() == 1 => Ok(Command::HVals(args[0].to_string())),
+            "HGETDEL" if args.len() >= 4 => {
+                let fields = parse_fields(&args[1..])?;
+                Ok(Command::HGetDel(args[0].to_string(), fields))
+            }
+            "HSCAN" if args.len() >= 2 => {
+                let cursor = args[1]
+                    .parse::<usize>()
+                    .map_err(|_| "invalid cursor".to_string())?;
+                let mut count = 10;
+                let mut novalues = false;
+                let mut rest = &args[2..];
+                while let Some(option) = rest.first() {
+                    match option.to_uppercase().as_str() {
+                        "COUNT" if rest.len() >= 2 => {
+                            count = rest[1]
+                                .parse::<usize>()
+                                .ok()
+                                .filter(|&n| n > 0)
+                                .ok_or_else(|| NOT_AN_INTEGER.to_string())?;
+                            rest = &rest[2..];
+                        }
+                        "NOVALUES" => {
+                            novalues = true;
+                            rest = &rest[1..];
+                        }
+                        _ => return Err(format!("Invalid HSCAN option: {}", option).into()),
+                    }
+                }
+                Ok(Command::HScan(args[0].to_string(), cursor, count, novalues))
+            }
+            "HGETEX" if args.len() >= 4 => {
+                let rest = match args[1].to_uppercase().as_str() {
+                    "FIELDS" => &args[1..],
+                    "PERSIST" => &args[2..],
+                    "EX" | "PX" | "EXAT" | "PXAT" => {
+                        return Err("Field expiration is not supported".into());
+                    }
+                    _ => return Err("Invalid HGETEX option".into()),
+                };
+                let fields = parse_fields(rest)?;
+                Ok(Command::HGetEx(args[0].to_string(), fields))
+            }
 
-            "PING" if args.is_empty() => Ok(Command::Ping),
-            "QUIT" if args.is_empty() => Ok(Command::Quit),
+            "SADD" if args.len() >= 2 => {
+                let members = args[1..].iter().map(|&s| s.to_string()).collect();
+                Ok(Command::SAdd(args[0].to_string(), members))
+            }
+            "SREM" if args.len() >= 2 => {
+                let members = args[1..].iter().map(|&s| s.to_string()).collect();
+                Ok(Command::SRem(args[0].to_string(), members))
+            }
+            "SISMEMBER" if args.len() == 2 => {
+                Ok(Command::SIsMember(args[0].to_string(), args[1].to_string()))
+            }
+            "SMEMBERS" if args.len() == 1 => Ok(Command::SMembers(args[0].to_string())),
+            "SSCAN" if args.len() >= 2 && args.len().is_multiple_of(2) => {
+                let cursor = args[1]
+                    .parse::<usize>()
+                    .map_err(|_| "invalid cursor".to_string())?;
+                let mut count = 10;
+                let mut pattern = None;
+                for option in args[2..].chunks(2) {
+                    match option[0].to_uppercase().as_str() {
+                        "COUNT" => {
+                            count = option[1]
+                                .parse::<usize>()
+                                .ok()
+                                .filter(|&n| n > 0)
+                                .ok_or_else(|| NOT_AN_INTEGER.to_string())?;
+                        }
+                        "MATCH" => pattern = Some(option[1].to_string()),
+                        _ => return Err(format!("Invalid SSCAN option: {}", option[0]).into()),
+                    }
+                }
+                Ok(Command::SScan(args[0].to_string(), cursor, count, pattern))
+            }
+            "SCARD" if args.len() == 1 => Ok(Command::SCard(args[0].to_string())),
+            "SINTER" if !args.is_empty() => Ok(Command::SInter(
+                args.iter().map(|&s| s.to_string()).collect(),
+            )),
+            "SUNION" if !args.is_empty() => Ok(Command::SUnion(
+                args.iter().map(|&s| s.to_string()).collect(),
+            )),
+            "SDIFF" if !args.is_empty() => Ok(Command::SDiff(
+                args.iter().map(|&s| s.to_string()).collect(),
+            )),
+            "SINTERSTORE" if args.len() >= 2 => Ok(Command::SInterStore(
+                args[0].to_string(),
+                args[1..].iter().map(|&s| s.to_string()).collect(),
+            )),
+            "SUNIONSTORE" if args.len() >= 2 => Ok(Command::SUnionStore(
+                args[0].to_string(),
+                args[1..].iter().map(|&s| s.to_string()).collect(),
+            )),
+            "SDIFFSTORE" if args.len() >= 2 => Ok(Command::SDiffStore(
+                args[0].to_string(),
+                args[1..].iter().map(|&s| s.to_string()).collect(),
+            )),
 
-            _ => Err(format!("Unknown or malformed command: {}", cmd)),
-        }
-    }
+            "ZADD" if args.len() >= 3 && args.len() % 2 == 1 => {
+                let members = args[1..]
+                    .chunks(2)
+                    .map(|pair| Ok((parse_score(pair[0])?, pair[1].to_string())))
+                    .collect::<Result<Vec<_>, String>>()?;
+                Ok(Command::ZAdd(args[0].to_string(), members))
+            }
+            "ZSCORE" if args.len() == 2 => {
+                Ok(Command::ZScore(args[0].to_string(), args[1].to_string()))
+            }
+            "ZRANK" if args.len() == 2 => {
+                Ok(Command::ZRank(args[0].to_string(), args[1].to_string()))
+            }
+            "ZRANGE" if args.len() == 3 || args.len() == 4 => {
+                let start = args[1]
+                    .parse::<i64>()
+                    .map_err(|_| NOT_AN_INTEGER.to_string())?;
+                let end = args[2]
+                    .parse::<i64>()
+                    .map_err(|_| NOT_AN_INTEGER.to_string())?;
+                let with_scores = match args.get(3) {
+                    Some(option) if option.eq_ignore_ascii_case("WITHSCORES") => true,
+                    Some(_) => return Err("syntax error".into()),
+                    None => false,
+                };
+                Ok(Command::ZRange(
+                    args[0].to_string(),
+                    start,
+                    end,
+                    with_scores,
+                ))
+            }
+            "ZCARD" if args.len() == 1 => Ok(Command::ZCard(args[0].to_string())),
+            "ZRANGEBYSCORE" if args.len() == 3 || args.len() == 4 => {
+                let with_scores = match args.get(3) {
+                    Some(option) if option.eq_ignore_ascii_case("WITHSCORES") => true,
+                    Some(_) => return Err("syntax error".into()),
+                    None => false,
+                };
+                Ok(Command::ZRangeByScore(
+                    args[0].to_string(),
+                    parse_score_bound(args[1])?,
+                    parse_score_bound(args[2])?,
+                    with_scores,
+                ))
+            }
+            "ZREM" if args.len() >= 2 => Ok(Command::ZRem(
+                args[0].to_string(),
+                args[1..].iter().map(|&s| s.to_string()).collect(),
+            )),
 
-    pub fn execute(&self, store: &mut MutexGuard<Store>) -> String {
-        match self {
-            Command::Ping => format!("+PONG\r\n"),
-            Command::Quit => format!("+OK\r\n"),
+            "XADD" if args.len() >= 4 && args.len().is_multiple_of(2) => {
+                let id = match args[1] {
+                    "*" => None,
+                    id => Some(StreamId::parse(id, 0).ok_or("Invalid stream ID")?),
+                };
+                let fields = args[2..]
+                    .chunks(2)
+                    .map(|pair| (pair[0].to_string(), pair[1].to_string()))
+                    .collect();
+                Ok(Command::XAdd(args[0].to_string(), id, fields))
+            }
+            "XLEN" if args.len() == 1 => Ok(Command::XLen(args[0].to_string())),
+            "XRANGE" if args.len() == 3 || args.len() == 5 => {
+                let start = match args[1] {
+                    "-" => StreamId::MIN,
+                    id => StreamId::parse(id, 0).ok_or("Invalid stream ID")?,
+                };
+                let end = match args[2] {
+                    "+" => StreamId::MAX,
+                    id => StreamId::parse(id, u64::MAX).ok_or("Invalid stream ID")?,
+                };
+                let count = if args.len() == 5 {
+                    if !args[3].eq_ignore_ascii_case("COUNT") {
+                        return Err("Expected COUNT".into());
+                    }
+                    Some(
+                        args[4]
+                            .parse::<usize>()
+                            .map_err(|_| NOT_AN_INTEGER.to_string())?,
+                    )
+                } else {
+                    None
+                };
+                Ok(Command::XRange(args[0].to_string(), start, end, count))
+            }
+            "XREAD" if args.len() >= 3 => Ok(parse_xread(args)?),
 
-            Command::Set(key, value) => {
-                store.set(key, value);
-                format!("+OK\r\n")
+            "EVAL" | "EVALSHA" if args.len() >= 2 => {
+                let numkeys = args[1]
+                    .parse::<usize>()
+                    .map_err(|_| NOT_AN_INTEGER.to_string())?;
+                let rest = &args[2..];
+                if numkeys > rest.len() {
+                    return Err("Number of keys can't be greater than number of args".into());
+                }
+                let (keys, argv) = rest.split_at(numkeys);
+                Ok(Command::Eval(
+                    args[0].to_string(),
+                    keys.iter().map(|&s| s.to_string()).collect(),
+                    argv.iter().map(|&s| s.to_string()).collect(),
+                ))
             }
-            Command::Get(key) => match store.get(key) {
-                Some(value) => format!("${}\r\n{}\r\n", value.len(), value),
-                None => "$-1\r\n".to_string(),
-            },
-            Command::Del(key) => {
-                format!(":{}\r\n", if store.del(key) { 1 } else { 0 })
+
+            "OBJECT" if args.len() == 2 && args[0].eq_ignore_ascii_case("ENCODING") => {
+                Ok(Command::ObjectEncoding(args[1].to_string()))
             }
-            Command::Expire(key, time) => {
-                format!(":{}\r\n", if store.expire(key, *time) { 1 } else { 0 })
+            "OBJECT" if args.len() == 2 && args[0].eq_ignore_ascii_case("REFCOUNT") => {
+                Ok(Command::ObjectRefCount(args[1].to_string()))
             }
-            Command::TTL(key) => {
-                format!(":{}\r\n", store.ttl(key).unwrap())
+            "OBJECT" if args.len() == 2 && args[0].eq_ignore_ascii_case("IDLETIME") => {
+                Ok(Command::ObjectIdleTime(args[1].to_string()))
             }
-            Command::Exists(key) => {
-                format!(":{}\r\n", if store.get(key).is_some() { 1 } else { 0 })
+            "CONFIG" if !args.is_empty() => match args[0].to_uppercase().as_str() {
+                "GET" if args.len() == 2 => Ok(Command::ConfigGet(args[1].to_string())),
+                "SET" if args.len() == 3 => {
+                    Ok(Command::ConfigSet(args[1].to_string(), args[2].to_string()))
+                }
+                _ => Err(format!("Unknown CONFIG subcommand: {}", args[0]).into()),
+            },
+            "CLIENT" if !args.is_empty() => {
+                let switch = || match args.get(1).map(|s| s.to_uppercase()).as_deref() {
+                    Some("ON") if args.len() == 2 => Ok(true),
+                    Some("OFF") if args.len() == 2 => Ok(false),
+                    _ => Err(ParseError::from("syntax error")),
+                };
+                match args[0].to_uppercase().as_str() {
+                    "NO-TOUCH" => Ok(Command::ClientNoTouch(switch()?)),
+                    "NO-EVICT" => switch().map(|_| Command::ClientNoEvict),
+                    _ => Err(format!("Unknown CLIENT subcommand: {}", args[0]).into()),
+                }
             }
-            Command::Strlen(key) => match store.get(key) {
-                Some(value) => format!(":{}\r\n", value.len()),
-                None => "$-1\r\n".to_string(),
+
+            "LATENCY" if !args.is_empty() => match args[0].to_uppercase().as_str() {
+                "HISTORY" if args.len() == 2 => Ok(Command::LatencyHistory(args[1].to_string())),
+                "LATEST" if args.len() == 1 => Ok(Command::LatencyLatest),
+                "RESET" => Ok(Command::LatencyReset(
+                    args[1..].iter().map(|&s| s.to_string()).collect(),
+                )),
+                _ => Err(format!("Unknown LATENCY subcommand: {}", args[0]).into()),
             },
-            Command::IncrBy(key, value) => match store.incr_by(key, *value) {
-                Some(v) => format!("{}\r\n", v),
-                None => "-ERR value is not an integer or out of range\r\n".to_string(),
+
+            "DEBUG" if !args.is_empty() => match args[0].to_uppercase().as_str() {
+                "SET-ACTIVE-EXPIRE" if args.len() == 2 => match args[1] {
+                    "0" => Ok(Command::DebugSetActiveExpire(false)),
+                    "1" => Ok(Command::DebugSetActiveExpire(true)),
+                    _ => Err("Invalid value".into()),
+                },
+                "OBJECT" if args.len() == 2 => Ok(Command::DebugObject(args[1].to_string())),
+                "DUMP-STRUCT" if args.len() == 2 || args.len() == 3 => {
+                    let limit = match args.get(2) {
+                        Some(limit) => limit
+                            .parse::<usize>()
+                            .map_err(|_| NOT_AN_INTEGER.to_string())?,
+                        None => 10,
+                    };
+                    Ok(Command::DebugDumpStruct(args[1].to_string(), limit))
+                }
+                "CONSISTENCY-CHECK" if args.len() == 1 => Ok(Command::DebugConsistencyCheck),
+                "RELOAD" if args.len() == 1 => Ok(Command::DebugReload),
+                "SLEEP" if args.len() == 2 => Ok(Command::DebugSleep(parse_seconds(args[1])?)),
+                "LOCKED-SLEEP" if args.len() == 2 => {
+                    Ok(Command::DebugLockedSleep(parse_seconds(args[1])?))
+                }
+                _ => Err(format!("Unknown DEBUG subcommand: {}", args[0]).into()),
+            },
+
+            "COMMAND" if args.is_empty() => Ok(Command::CmdInfo(Vec::new())),
+            "COMMAND" => match args[0].to_uppercase().as_str() {
+                "COUNT" if args.len() == 1 => Ok(Command::CmdCount),
+                "INFO" => Ok(Command::CmdInfo(
+                    args[1..].iter().map(|&s| s.to_string()).collect(),
+                )),
+                "DOCS" => Ok(Command::CmdDocs(
+                    args[1..].iter().map(|&s| s.to_string()).collect(),
+                )),
+                _ => Err(format!("Unknown COMMAND subcommand: {}", args[0]).into()),
             },
-            Command::DecrBy(key, value) => match store.incr_by(key, -value) {
-                Some(v) => format!("{}\r\n", v),
-                None => "-ERR value is not an integer or out of range\r\n".to_string(),
+
+            "SELECT" if args.len() == 1 => match args[0].parse::<usize>() {
+                Ok(index) => Ok(Command::Select(index)),
+                Err(_) => Err(NOT_AN_INTEGER.into()),
             },
-            Command::Incr(key) => match store.incr_by(key, 1) {
-                Some(v) => format!("{}\r\n", v),
-                None => "-ERR value is not an integer or out of range\r\n".to_string(),
+            "FLUSHDB" if args.is_empty() => Ok(Command::FlushDb),
+            "FLUSHALL" if args.is_empty() => Ok(Command::FlushAll),
+            "MULTI" if args.is_empty() => Ok(Command::Multi),
+            "EXEC" if args.is_empty() => Ok(Command::Exec),
+            "DISCARD" if args.is_empty() => Ok(Command::Discard),
+            "SUBSCRIBE" if !args.is_empty() => Ok(Command::Subscribe(
+                args.iter().map(|&channel| channel.to_string()).collect(),
+            )),
+            "UNSUBSCRIBE" => Ok(Command::Unsubscribe(
+                args.iter().map(|&channel| channel.to_string()).collect(),
+            )),
+            "PUBLISH" if args.len() == 2 => {
+                Ok(Command::Publish(args[0].to_string(), args[1].to_string()))
+            }
+            "PUBSUB" if !args.is_empty() => match args[0].to_uppercase().as_str() {
+                "CHANNELS" if args.len() <= 2 => {
+                    Ok(Command::PubSubChannels(args.get(1).map(|s| s.to_string())))
+                }
+                "NUMSUB" => Ok(Command::PubSubNumSub(
+                    args[1..].iter().map(|&s| s.to_string()).collect(),
+                )),
+                "NUMPAT" if args.len() == 1 => Ok(Command::PubSubNumPat),
+                _ => Err(format!("Unknown PUBSUB subcommand: {}", args[0]).into()),
             },
-            Command::Decr(key) => match store.incr_by(key, -1) {
-                Some(v) => format!("{}\r\n", v),
-                None => "-ERR value is not an integer or out of range\r\n".to_string(),
+            "HELLO" if args.len() <= 1 => match args.first() {
+                Some(version) => match version.parse::<i64>() {
+                    Ok(version) => Ok(Command::Hello(Some(version))),
+                    Err(_) => Err("Protocol version is not an integer or out of range".into()),
+                },
+                None => Ok(Command::Hello(None)),
+            },
+            "AUTH" if args.len() == 1 => Ok(Command::Auth(args[0].to_string())),
+            "REPLCONF" => Ok(Command::ReplConf),
+            "PSYNC" if args.len() == 2 => Ok(Command::PSync),
+            "WAIT" if args.len() == 2 => match (args[0].parse::<u64>(), args[1].parse::<u64>()) {
+                (Ok(_), Ok(_)) => Ok(Command::Wait),
+                _ => Err(NOT_AN_INTEGER.into()),
+            },
+            "SAVE" if args.is_empty() => Ok(Command::Save),
+            "BGSAVE" if args.is_empty() => Ok(Command::BgSave),
+            "BGREWRITEAOF" if args.is_empty() => Ok(Command::BgRewriteAof),
+
+            "PING" if args.is_empty() => Ok(Command::Ping),
+            "QUIT" if args.is_empty() => Ok(Command::Quit),
+            "SHUTDOWN" if args.is_empty() => Ok(Command::Shutdown(false)),
+            "SHUTDOWN" if args.len() == 1 => match args[0].to_uppercase().as_str() {
+                "NOSAVE" => Ok(Command::Shutdown(false)),
+                "SAVE" => Ok(Command::Shutdown(true)),
+                _ => Err(format!("Invalid SHUTDOWN option: {}", args[0]).into()),
+            },
+
+            _ => match command_meta(&cmd) {
+                Some(meta) if !arity_matches(meta.arity, parts.len()) => {
+                    Err(ParseError::WrongArity(cmd))
+                }
+                Some(_) => Err(ParseError::Invalid("syntax error".to_string())),
+                None => Err(ParseError::unknown_command(parts)),
             },
+        }
+    }
+
+    /// Runs the command and returns its complete RESP reply. Replies that
+    /// carry string values are built here, byte for byte; everything else is
+    /// text and comes from `execute_text`.
+    pub fn execute(&self, store: &mut Store) -> Vec<u8> {
+        if self.reads_only() {
+            return self.execute_read(store);
+        }
+        let value = match self {
+            Command::GetSet(key, value) => store.get_set(key, value),
+            Command::GetDel(key) => store.get_del(key),
+            Command::GetRange(key, start, end) => store.get_range(key, *start, *end).map(Some),
+            _ => return self.execute_text(store).into_bytes(),
+        };
+        match value {
+            Ok(Some(value)) => bulk_string(&value),
+            Ok(None) => NULL_BULK.into(),
+            Err(err) => store_error(err).into_bytes(),
+        }
+    }
+
+    /// Whether the command only reads, so it can run under a shared lock on
+    /// the store through `execute_read`.
+    pub fn reads_only(&self) -> bool {
+        matches!(
+            self,
+            Command::Get(_)
+                | Command::MGet(_)
+                | Command::Strlen(_)
+                | Command::Exists(_)
+                | Command::Type(_)
+                | Command::Ttl(_)
+                | Command::LLen(_)
+                | Command::LIndex(..)
+                | Command::HGet(..)
+                | Command::HExists(..)
+                | Command::HLen(_)
+                | Command::SIsMember(..)
+                | Command::SCard(_)
+        )
+    }
 
-            Command::LPush(key, values) => {
-                format!("{}\r\n", store.lpush(key, values.clone()))
+    /// Runs a command that `reads_only`. Keys past their deadline read as
+    /// missing but are left for the sweeper or the next write to delete,
+    /// since deleting them would need the store to itself.
+    pub fn execute_read(&self, store: &Store) -> Vec<u8> {
+        let reply = match self {
+            Command::Get(key) => match store.get_string(key) {
+                Ok(Some(value)) => return bulk_string(&value),
+                Ok(None) => NULL_BULK.to_string(),
+                Err(err) => store_error(err),
+            },
+            // Keys that are missing or hold something other than a string
+            // both read as nil, as in Redis.
+            Command::MGet(keys) => {
+                let values: Vec<Option<Vec<u8>>> = keys
+                    .iter()
+                    .map(|key| store.get_string(key).ok().flatten())
+                    .collect();
+                return bulk_bytes_array(&values);
             }
-            Command::RPush(key, values) => {
-                format!("{}\r\n", store.rpush(key, values.clone()))
+            Command::Strlen(key) => match store.strlen(key) {
+                Ok(len) => resp_integer(len as i64),
+                Err(err) => store_error(err),
+            },
+            Command::Exists(keys) => {
+                resp_integer(keys.iter().filter(|key| store.contains(key)).count() as i64)
             }
-            Command::LPop(key) => match store.lpop(key) {
-                Some(value) => format!("{}\r\n", value),
-                None => "$-1\r\n".to_string(),
+            Command::Type(key) => format!("+{}\r\n", store.key_type(key).unwrap_or("none")),
+            Command::Ttl(key) => resp_integer(store.ttl(key)),
+            Command::LLen(key) => match store.llen(key) {
+                Ok(len) => resp_integer(len as i64),
+                Err(err) => store_error(err),
             },
-            Command::RPop(key) => match store.rpop(key) {
-                Some(value) => format!("{}\r\n", value),
-                None => "$-1\r\n".to_string(),
+            Command::LIndex(key, index) => match store.lindex(key, *index) {
+                Ok(Some(value)) => format!("${}\r\n{}\r\n", value.len(), value),
+                Ok(None) => "-ERR index out of range\r\n".to_string(),
+                Err(err) => store_error(err),
             },
-            Command::LRange(key, start, end) => {
-                match store.lrange(key, *start as usize, *end as usize) {
-                    Some(result) => {
-                        format!("*{}\r\n", result.len())
-                            + &result
-                                .iter()
-                                .map(|v| format!("${}\r\n{}\r\n", v.len(), v))
-                                .collect::<String>()
-                    }
-                    None => return "-ERR index out of range\r\n".to_string(),
+            Command::HGet(key, field) => match store.hget(key, field) {
+                Ok(Some(value)) => format!("${}\r\n{}\r\n", value.len(), value),
+                Ok(None) => NULL_BULK.to_string(),
+                Err(err) => store_error(err),
+            },
+            Command::HExists(key, field) => match store.hexists(key, field) {
+                Ok(exists) => resp_integer(exists as i64),
+                Err(err) => store_error(err),
+            },
+            Command::HLen(key) => match store.hlen(key) {
+                Ok(len) => resp_integer(len as i64),
+                Err(err) => store_error(err),
+            },
+            Command::SIsMember(key, member) => match store.sismember(key, member) {
+                Ok(found) => resp_integer(found as i64),
+                Err(err) => store_error(err),
+            },
+            Command::SCard(key) => match store.scard(key) {
+                Ok(len) => resp_integer(len as i64),
+                Err(err) => store_error(err),
+            },
+            _ => unreachable!("{:?} does not only read", self),
+        };
+        reply.into_bytes()
+    }
+
+    fn execute_text(&self, store: &mut Store) -> String {
+        match self {
+            // Answered by `execute` or `execute_read`.
+            Command::Get(_)
+            | Command::GetSet(..)
+            | Command::GetDel(_)
+            | Command::GetRange(..)
+            | Command::MGet(_)
+            | Command::Strlen(_)
+            | Command::Exists(_)
+            | Command::Type(_)
+            | Command::Ttl(_)
+            | Command::LLen(_)
+            | Command::LIndex(..)
+            | Command::HGet(..)
+            | Command::HExists(..)
+            | Command::HLen(_)
+            | Command::SIsMember(..)
+            | Command::SCard(_) => unreachable!(),
+            Command::Ping => "+PONG\r\n".to_string(),
+            Command::Quit => "+OK\r\n".to_string(),
+            Command::Shutdown(true) => match store.save() {
+                Ok(()) => {
+                    store.request_shutdown();
+                    "+OK\r\n".to_string()
                 }
-            }
-            Command::LRem(key, count, value) => {
-                format!(":{}\r\n", store.lrem(key, *count, value.to_string()))
-            }
-            Command::LIndex(key, index) => match store.lindex(key, *index as usize) {
-                Some(value) => format!("{}\r\n", value),
-                None => "-ERR index out of range\r\n".to_string(),
+                Err(e) => format!("-ERR Errors trying to SHUTDOWN: {}\r\n", e),
             },
-            Command::LSet(key, index, value) => {
-                if store.lset(key, *index, value.clone()) {
-                    format!("+OK\r\n")
+            Command::Shutdown(false) => {
+                store.request_shutdown();
+                "+OK\r\n".to_string()
+            }
+
+            Command::Set(key, value, options) => {
+                if store.set_with_options(key, value, options) {
+                    "+OK\r\n".to_string()
                 } else {
-                    "-ERR index out of range\r\n".to_string()
+                    NULL_BULK.to_string()
                 }
             }
-            Command::LLen(key) => match store.llen(key) {
-                Some(len) => format!("{}\r\n", len),
-                None => "$-1\r\n".to_string(),
+            Command::SetNx(key, value) => {
+                let options = SetOptions {
+                    ttl: None,
+                    condition: Some(SetCondition::IfMissing),
+                };
+                let set = store.set_with_options(key, &Value::from(value.clone()), &options);
+                resp_integer(set as i64)
+            }
+            Command::SetEx(key, seconds, value) => {
+                let options = SetOptions {
+                    ttl: Some(Duration::from_secs(*seconds)),
+                    condition: None,
+                };
+                store.set_with_options(key, &Value::from(value.clone()), &options);
+                "+OK\r\n".to_string()
+            }
+            // Values are freed inline, so UNLINK is DEL under another name.
+            Command::Del(keys) | Command::Unlink(keys) => {
+                resp_integer(keys.iter().filter(|key| store.del(key)).count() as i64)
+            }
+            Command::MSet(pairs) => {
+                store.mset(pairs);
+                "+OK\r\n".to_string()
+            }
+            Command::Expire(key, time, 0) => resp_integer(store.expire(key, *time) as i64),
+            Command::Expire(key, time, jitter) => {
+                let set = store.expire_with_jitter(key, *time, *jitter);
+                resp_integer(set as i64)
+            }
+            Command::PExpire(key, ms) => resp_integer(store.pexpire(key, *ms) as i64),
+            Command::ExpireAt(key, secs) => {
+                resp_integer(store.pexpire_at(key, secs.saturating_mul(1000)) as i64)
+            }
+            Command::PExpireAt(key, ms) => resp_integer(store.pexpire_at(key, *ms) as i64),
+            Command::Persist(key) => resp_integer(store.persist(key) as i64),
+            Command::PTtl(key) => resp_integer(store.pttl(key)),
+            Command::ExpireTime(key) => match store.expire_time(key) {
+                ms if ms < 0 => resp_integer(ms),
+                ms => resp_integer(ms / 1000),
             },
-
-            Command::HSet(key, field, value) => {
-                format!(":{}\r\n", if store.hset(key, field, value) { 1 } else { 0 })
+            Command::PExpireTime(key) => resp_integer(store.expire_time(key)),
+            Command::Keys(pattern) => string_array(&store.keys(pattern)),
+            Command::RandomKey => match store.random_key() {
+                Some(key) => format!("${}\r\n{}\r\n", key.len(), key),
+                None => NULL_BULK.to_string(),
+            },
+            Command::DbSize => resp_integer(store.dbsize() as i64),
+            Command::Info(section) => {
+                let report = info_report(store, section.as_deref());
+                format!("${}\r\n{}\r\n", report.len(), report)
             }
-            Command::HGet(key, field) => match store.hget(key, field) {
-                Some(value) => format!("${}\r\n{}\r\n", value.len(), value),
-                None => "$-1\r\n".to_string(),
+            Command::Scan(cursor, count, pattern) => {
+                let (next, keys) = store.scan(*cursor, *count, pattern.as_deref());
+                let next = next.to_string();
+                format!("*2\r\n${}\r\n{}\r\n", next.len(), next) + &string_array(&keys)
+            }
+            Command::Touch(keys) => {
+                resp_integer(keys.iter().filter(|key| store.touch_existing(key)).count() as i64)
+            }
+            Command::Append(key, suffix) => match store.append(key, suffix) {
+                Ok(len) => resp_integer(len as i64),
+                Err(err) => store_error(err),
             },
-            Command::HDel(key, field) => {
-                format!(":{}\r\n", if store.hdel(key, field) { 1 } else { 0 })
+            Command::SetRange(key, offset, value) => match store.set_range(key, *offset, value) {
+                Ok(len) => resp_integer(len as i64),
+                Err(err) => store_error(err),
+            },
+            Command::SetBit(key, offset, on) => match store.setbit(key, *offset, *on) {
+                Ok(old) => resp_integer(old as i64),
+                Err(err) => store_error(err),
+            },
+            Command::GetBit(key, offset) => match store.getbit(key, *offset) {
+                Ok(bit) => resp_integer(bit as i64),
+                Err(err) => store_error(err),
+            },
+            Command::BitCount(key) => match store.bitcount(key) {
+                Ok(count) => resp_integer(count as i64),
+                Err(err) => store_error(err),
+            },
+            Command::IncrBy(key, value) => match store.incr_by(key, *value) {
+                Ok(v) => resp_integer(v),
+                Err(err) => store_error(err),
+            },
+            Command::IncrByEx(key, value, seconds) => {
+                match store.incr_by_with_ttl(key, *value, *seconds) {
+                    Ok(v) => resp_integer(v),
+                    Err(err) => store_error(err),
+                }
             }
-            Command::HGetAll(key) => match store.hget_all(key) {
-                Some(hash) => {
-                    format!("*{}\r\n", hash.len() * 2)
-                        + &hash
-                            .iter()
-                            .map(|(k, v)| {
-                                format!("${}\r\n{}\r\n${}\r\n{}\r\n", k.len(), k, v.len(), v)
-                            })
-                            .collect::<String>()
+            Command::DecrBy(key, value) => {
+                match value.checked_neg().map(|by| store.incr_by(key, by)) {
+                    Some(Ok(v)) => resp_integer(v),
+                    Some(Err(err)) => store_error(err),
+                    None => store_error(StoreError::NotAnInteger),
                 }
-                None => "*0\r\n".to_string(),
-            },
-            Command::HIncrBy(key, field, value) => match store.hincr_by(key, field, *value) {
-                Some(v) => format!("{}\r\n", v),
-                None => "-ERR hash value is not an integer\r\n".to_string(),
+            }
+            Command::Incr(key) => match store.incr_by(key, 1) {
+                Ok(v) => resp_integer(v),
+                Err(err) => store_error(err),
             },
-            Command::HLen(key) => match store.hlen(key) {
-                Some(len) => format!(":{}\r\n", len),
-                None => ":0\r\n".to_string(),
+            Command::Decr(key) => match store.incr_by(key, -1) {
+                Ok(v) => resp_integer(v),
+                Err(err) => store_error(err),
             },
+            Command::Rename(old, new) => {
+                if store.rename(old, new) {
+                    "+OK\r\n".to_string()
+                } else {
+                    "-ERR no such key\r\n".to_string()
+                }
+            }
+            Command::RenameNx(old, new) => {
+                if !store.exists(old) {
+                    "-ERR no such key\r\n".to_string()
+                } else if store.exists(new) {
+                    resp_integer(0)
+                } else {
+                    resp_integer(store.rename(old, new) as i64)
+                }
+            }
+            Command::RenamePx(old, new, ms) => {
+                if store.rename_with_ttl(old, new, *ms) {
+                    "+OK\r\n".to_string()
+                } else {
+                    "-ERR no such key\r\n".to_string()
+                }
+            }
+            Command::Copy(src, dst, replace) => resp_integer(store.copy(src, dst, *replace) as i64),
+            Command::Move(_, db) if *db >= store.databases() => {
+                "-ERR DB index is out of range\r\n".to_string()
+            }
+            Command::Move(_, db) if *db == store.selected_db() => {
+                "-ERR source and destination objects are the same\r\n".to_string()
+            }
+            Command::Move(key, db) => resp_integer(store.move_key(key, *db) as i64),
+            Command::Cad(key, expected) => {
+                let deleted = store.compare_and_delete(key, expected);
+                resp_integer(deleted as i64)
+            }
+            Command::Cas(key, expected, value) => {
+                let swapped = store.compare_and_set(key, expected, value);
+                resp_integer(swapped as i64)
+            }
+
+            Command::LPush(key, values) => match store.lpush(key, values.clone()) {
+                Ok(len) => resp_integer(len as i64),
+                Err(err) => store_error(err),
+            },
+            Command::RPush(key, values) => match store.rpush(key, values.clone()) {
+                Ok(len) => resp_integer(len as i64),
+                Err(err) => store_error(err),
+            },
+            Command::LPop(key, None) => match store.lpop(key) {
+                Ok(Some(value)) => format!("${}\r\n{}\r\n", value.len(), value),
+                Ok(None) => NULL_BULK.to_string(),
+                Err(err) => store_error(err),
+            },
+            Command::RPop(key, None) => match store.rpop(key) {
+                Ok(Some(value)) => format!("${}\r\n{}\r\n", value.len(), value),
+                Ok(None) => NULL_BULK.to_string(),
+                Err(err) => store_error(err),
+            },
+            Command::LPop(key, Some(count)) => match store.lpop_n(key, *count) {
+                Ok(Some(values)) => string_array(&values),
+                Ok(None) => NULL_ARRAY.to_string(),
+                Err(err) => store_error(err),
+            },
+            Command::RPop(key, Some(count)) => match store.rpop_n(key, *count) {
+                Ok(Some(values)) => string_array(&values),
+                Ok(None) => NULL_ARRAY.to_string(),
+                Err(err) => store_error(err),
+            },
+            Command::LRange(key, start, end) => match store.lrange(key, *start, *end) {
+                Ok(result) => string_array(&result),
+                Err(err) => store_error(err),
+            },
+            Command::RPopLPush(src, dst) => {
+                match store.lmove(src, dst, ListEnd::Right, ListEnd::Left) {
+                    Ok(Some(value)) => format!("${}\r\n{}\r\n", value.len(), value),
+                    Ok(None) => NULL_BULK.to_string(),
+                    Err(err) => store_error(err),
+                }
+            }
+            Command::LMove(src, dst, from, to) => match store.lmove(src, dst, *from, *to) {
+                Ok(Some(value)) => format!("${}\r\n{}\r\n", value.len(), value),
+                Ok(None) => NULL_BULK.to_string(),
+                Err(err) => store_error(err),
+            },
+            Command::LRem(key, count, value) => match store.lrem(key, *count, value.to_string()) {
+                Ok(removed) => resp_integer(removed as i64),
+                Err(err) => store_error(err),
+            },
+            Command::LSet(key, index, value) => match store.lset(key, *index, value.clone()) {
+                Ok(true) => "+OK\r\n".to_string(),
+                Ok(false) => "-ERR index out of range\r\n".to_string(),
+                Err(err) => store_error(err),
+            },
+
+            Command::HSet(key, field, value) => match store.hset(key, field, value) {
+                Ok(added) => resp_integer(added as i64),
+                Err(err) => store_error(err),
+            },
+            Command::HDel(key, field) => match store.hdel(key, field) {
+                Ok(removed) => resp_integer(removed as i64),
+                Err(err) => store_error(err),
+            },
+            Command::HGetAll(key, sort) => match store.hget_all(key) {
+                Ok(hash) => {
+                    let mut fields: Vec<_> = hash.iter().collect();
+                    if *sort {
+                        fields.sort();
+                    }
+                    format!("*{}\r\n", fields.len() * 2)
+                        + &fields
+                            .iter()
+                            .map(|(k, v)| {
+                                format!("${}\r\n{}\r\n${}\r\n{}\r\n", k.len(), k, v.len(), v)
+                            })
+                            .collect::<String>()
+                }
+                Err(err) => store_error(err),
+            },
+            Command::HIncrBy(key, field, value) => match store.hincr_by(key, field, *value) {
+                Ok(v) => resp_integer(v),
+                Err(StoreError::NotAnInteger) => {
+                    "-ERR hash value is not an integer\r\n".to_string()
+                }
+                Err(err) => store_error(err),
+            },
+
+            Command::HMSet(key, pairs) => match store.hmset(key, pairs) {
+                Ok(_) => "+OK\r\n".to_string(),
+                Err(err) => store_error(err),
+            },
+            Command::HMGet(key, fields) => match store.hmget(key, fields) {
+                Ok(values) => bulk_array(&values),
+                Err(err) => store_error(err),
+            },
+            Command::HKeys(key) => match store.hkeys(key) {
+                Ok(fields) => string_array(&fields),
+                Err(err) => store_error(err),
+            },
+            Command::HVals(key) => match store.hvals(key) {
+                Ok(values) => string_array(&values),
+                Err(err) => store_error(err),
+            },
+            Command::HGetDel(key, fields) => match store.hget_del(key, fields) {
+                Ok(values) => bulk_array(&values),
+                Err(err) => store_error(err),
+            },
+            // Fields never carry their own TTL, so PERSIST has nothing to
+            // clear and HGETEX reduces to a plain multi-field read.
+            Command::HGetEx(key, fields) => match store.hmget(key, fields) {
+                Ok(values) => bulk_array(&values),
+                Err(err) => store_error(err),
+            },
+            Command::HScan(key, cursor, count, novalues) => {
+                match store.hscan(key, *cursor, *count) {
+                    Ok((next, page)) => {
+                        let items: Vec<String> = if *novalues {
+                            page.into_iter().map(|(field, _)| field).collect()
+                        } else {
+                            page.into_iter()
+                                .flat_map(|(field, value)| [field, value])
+                                .collect()
+                        };
+                        let next = next.to_string();
+                        format!("*2\r\n${}\r\n{}\r\n", next.len(), next) + &string_array(&items)
+                    }
+                    Err(err) => store_error(err),
+                }
+            }
+
+            Command::SAdd(key, members) => match store.sadd(key, members) {
+                Ok(added) => resp_integer(added as i64),
+                Err(err) => store_error(err),
+            },
+            Command::SRem(key, members) => match store.srem(key, members) {
+                Ok(removed) => resp_integer(removed as i64),
+                Err(err) => store_error(err),
+            },
+            Command::SMembers(key) => match store.smembers(key) {
+                Ok(members) => string_array(&members),
+                Err(err) => store_error(err),
+            },
+            Command::SScan(key, cursor, count, pattern) => {
+                match store.sscan(key, *cursor, *count, pattern.as_deref()) {
+                    Ok((next, members)) => {
+                        let next = next.to_string();
+                        format!("*2\r\n${}\r\n{}\r\n", next.len(), next) + &string_array(&members)
+                    }
+                    Err(err) => store_error(err),
+                }
+            }
+            Command::SInter(keys) => match store.set_op(SetOp::Inter, keys) {
+                Ok(members) => string_array(&Vec::from_iter(members)),
+                Err(err) => store_error(err),
+            },
+            Command::SUnion(keys) => match store.set_op(SetOp::Union, keys) {
+                Ok(members) => string_array(&Vec::from_iter(members)),
+                Err(err) => store_error(err),
+            },
+            Command::SDiff(keys) => match store.set_op(SetOp::Diff, keys) {
+                Ok(members) => string_array(&Vec::from_iter(members)),
+                Err(err) => store_error(err),
+            },
+            Command::SInterStore(dest, keys) => {
+                match store.set_op_store(SetOp::Inter, dest, keys) {
+                    Ok(len) => resp_integer(len as i64),
+                    Err(err) => store_error(err),
+                }
+            }
+            Command::SUnionStore(dest, keys) => {
+                match store.set_op_store(SetOp::Union, dest, keys) {
+                    Ok(len) => resp_integer(len as i64),
+                    Err(err) => store_error(err),
+                }
+            }
+            Command::SDiffStore(dest, keys) => match store.set_op_store(SetOp::Diff, dest, keys) {
+                Ok(len) => resp_integer(len as i64),
+                Err(err) => store_error(err),
+            },
+
+            Command::ZAdd(key, members) => match store.zadd(key, members) {
+                Ok(added) => resp_integer(added as i64),
+                Err(err) => store_error(err),
+            },
+            Command::ZScore(key, member) => match store.zscore(key, member) {
+                Ok(Some(score)) => {
+                    let score = score.to_string();
+                    format!("${}\r\n{}\r\n", score.len(), score)
+                }
+                Ok(None) => NULL_BULK.to_string(),
+                Err(err) => store_error(err),
+            },
+            Command::ZRank(key, member) => match store.zrank(key, member) {
+                Ok(Some(rank)) => resp_integer(rank as i64),
+                Ok(None) => NULL_BULK.to_string(),
+                Err(err) => store_error(err),
+            },
+            Command::ZRange(key, start, end, with_scores) => {
+                match store.zrange(key, *start, *end) {
+                    Ok(members) => string_array(&scored_members(members, *with_scores)),
+                    Err(err) => store_error(err),
+                }
+            }
+            Command::ZRangeByScore(key, min, max, with_scores) => {
+                match store.zrange_by_score(key, *min, *max) {
+                    Ok(members) => string_array(&scored_members(members, *with_scores)),
+                    Err(err) => store_error(err),
+                }
+            }
+            Command::ZRem(key, members) => match store.zrem(key, members) {
+                Ok(removed) => resp_integer(removed as i64),
+                Err(err) => store_error(err),
+            },
+            Command::ZCard(key) => match store.zcard(key) {
+                Ok(len) => resp_integer(len as i64),
+                Err(err) => store_error(err),
+            },
+
+            Command::XAdd(key, id, fields) => match store.xadd(key, *id, fields.clone()) {
+                Ok(id) => {
+                    let id = id.to_string();
+                    format!("${}\r\n{}\r\n", id.len(), id)
+                }
+                Err(err) => store_error(err),
+            },
+            Command::XLen(key) => match store.xlen(key) {
+                Ok(len) => resp_integer(len as i64),
+                Err(err) => store_error(err),
+            },
+            Command::XRange(key, start, end, count) => {
+                match store.xrange(key, *start, *end, *count) {
+                    Ok(entries) => stream_entries(&entries),
+                    Err(err) => store_error(err),
+                }
+            }
+            Command::XRead(count, _, streams) => {
+                let streams: Vec<(String, StreamId)> = streams
+                    .iter()
+                    .map(|(key, id)| (key.clone(), id.unwrap_or(StreamId::MAX)))
+                    .collect();
+                match store.xread(&streams, *count) {
+                    Ok(results) if results.is_empty() => NULL_ARRAY.to_string(),
+                    Ok(results) => {
+                        format!("*{}\r\n", results.len())
+                            + &results
+                                .iter()
+                                .map(|(key, entries)| {
+                                    format!("*2\r\n${}\r\n{}\r\n", key.len(), key)
+                                        + &stream_entries(entries)
+                                })
+                                .collect::<String>()
+                    }
+                    Err(err) => store_error(err),
+                }
+            }
+
+            Command::Eval(name, keys, argv) => match store.script(name) {
+                Some(script) => script(store, keys, argv),
+                None => "-NOSCRIPT No matching script\r\n".to_string(),
+            },
+
+            // The connection picks up the new selection from the store once
+            // the command returns.
+            Command::Select(index) => {
+                if store.select(*index) {
+                    "+OK\r\n".to_string()
+                } else {
+                    "-ERR DB index is out of range\r\n".to_string()
+                }
+            }
+            Command::FlushDb => {
+                store.flush_db();
+                "+OK\r\n".to_string()
+            }
+            Command::FlushAll => {
+                store.flush_all();
+                "+OK\r\n".to_string()
+            }
+
+            Command::Multi | Command::Exec | Command::Discard => {
+                "-ERR Command not allowed inside a transaction\r\n".to_string()
+            }
+            Command::Subscribe(_) | Command::Unsubscribe(_) => {
+                "-ERR SUBSCRIBE is not allowed in this context\r\n".to_string()
+            }
+            Command::Hello(_) => "-ERR HELLO is not allowed in this context\r\n".to_string(),
+            Command::Auth(_) => "-ERR AUTH is not allowed in this context\r\n".to_string(),
+            Command::ReplConf => "+OK\r\n".to_string(),
+            Command::PSync => "-ERR PSYNC is not allowed in this context\r\n".to_string(),
+            // Replicas do not acknowledge offsets, so every one still
+            // connected counts as caught up, and there is nothing to wait for.
+            Command::Wait => {
+                resp_integer(store.stats().connected_replicas.load(Ordering::Relaxed) as i64)
+            }
+            Command::Publish(channel, message) => {
+                let frame = format!(
+                    "*3\r\n$7\r\nmessage\r\n${}\r\n{}\r\n${}\r\n{}\r\n",
+                    channel.len(),
+                    channel,
+                    message.len(),
+                    message
+                );
+                resp_integer(store.publish(channel, &frame) as i64)
+            }
+            Command::PubSubChannels(pattern) => {
+                string_array(&store.channels(pattern.as_deref().unwrap_or("*")))
+            }
+            Command::PubSubNumSub(channels) => {
+                format!("*{}\r\n", channels.len() * 2)
+                    + &channels
+                        .iter()
+                        .map(|channel| {
+                            format!("${}\r\n{}\r\n", channel.len(), channel)
+                                + &resp_integer(store.subscriber_count(channel) as i64)
+                        })
+                        .collect::<String>()
+            }
+            Command::PubSubNumPat => resp_integer(0),
+            Command::Save => match store.save() {
+                Ok(()) => "+OK\r\n".to_string(),
+                Err(e) => format!("-ERR {}\r\n", e),
+            },
+            Command::BgSave => {
+                if store.background_save() {
+                    "+Background saving started\r\n".to_string()
+                } else {
+                    "-ERR Background save already in progress\r\n".to_string()
+                }
+            }
+            Command::BgRewriteAof => {
+                if !store.aof_enabled() {
+                    "-ERR Append only file is not enabled\r\n".to_string()
+                } else if store.request_aof_rewrite() {
+                    "+Background append only file rewriting started\r\n".to_string()
+                } else {
+                    "-ERR Background append only file rewriting already in progress\r\n".to_string()
+                }
+            }
+
+            Command::ObjectEncoding(key) => match store.object_encoding(key) {
+                Some(encoding) => format!("${}\r\n{}\r\n", encoding.len(), encoding),
+                None => NULL_BULK.to_string(),
+            },
+            // Values are never shared between keys, so every one has a single
+            // reference.
+            Command::ObjectRefCount(key) => {
+                if store.exists(key) {
+                    resp_integer(1)
+                } else {
+                    NULL_BULK.to_string()
+                }
+            }
+            Command::ObjectIdleTime(key) => match store.idle_time(key) {
+                Some(secs) => resp_integer(secs as i64),
+                None => NULL_BULK.to_string(),
+            },
+            // As with SELECT, the connection picks the setting up from the
+            // store once the command returns.
+            Command::ClientNoTouch(on) => {
+                store.set_no_touch(*on);
+                "+OK\r\n".to_string()
+            }
+            Command::ClientNoEvict => "+OK\r\n".to_string(),
+            Command::ConfigGet(name) => match store.config_get(name) {
+                Some(value) => string_array(&[name.to_lowercase(), value]),
+                None => "*0\r\n".to_string(),
+            },
+            Command::ConfigSet(name, value) => match store.config_set(name, value) {
+                Ok(()) => "+OK\r\n".to_string(),
+                Err(e) => format!("-ERR {}\r\n", e),
+            },
+
+            Command::LatencyHistory(event) => {
+                let history = store.latency().history(event);
+                format!("*{}\r\n", history.len())
+                    + &history
+                        .iter()
+                        .map(|s| format!("*2\r\n:{}\r\n:{}\r\n", s.timestamp, s.millis))
+                        .collect::<String>()
+            }
+            Command::LatencyLatest => {
+                let latest = store.latency().latest();
+                format!("*{}\r\n", latest.len())
+                    + &latest
+                        .iter()
+                        .map(|(event, sample, max)| {
+                            format!(
+                                "*4\r\n${}\r\n{}\r\n:{}\r\n:{}\r\n:{}\r\n",
+                                event.len(),
+                                event,
+                                sample.timestamp,
+                                sample.millis,
+                                max
+                            )
+                        })
+                        .collect::<String>()
+            }
+            Command::LatencyReset(events) => resp_integer(store.latency().reset(events) as i64),
+
+            Command::DebugSetActiveExpire(enabled) => {
+                store.set_active_expire(*enabled);
+                "+OK\r\n".to_string()
+            }
+            Command::DebugObject(key) => match store.debug_object(key) {
+                Some((value, remaining)) => {
+                    let ttl_ms = match remaining {
+                        Some(d) => d.as_millis() as i64,
+                        None => -1,
+                    };
+                    format!(
+                        "+Value type:{} length:{} ttl_ms:{}\r\n",
+                        value.type_name(),
+                        value.len(),
+                        ttl_ms
+                    )
+                }
+                None => "-ERR no such key\r\n".to_string(),
+            },
+            Command::DebugDumpStruct(key, limit) => match store.debug_object(key) {
+                Some((value, remaining)) => {
+                    let expiry = match remaining {
+                        Some(d) => format!("expires_in_ms: {}\n", d.as_millis()),
+                        None => "expires: never\n".to_string(),
+                    };
+                    let description = value.describe(*limit) + &expiry;
+                    format!("${}\r\n{}\r\n", description.len(), description)
+                }
+                None => "-ERR no such key\r\n".to_string(),
+            },
+            Command::DebugReload => match store.save().and_then(|()| store.load_snapshot()) {
+                Ok(_) => "+OK\r\n".to_string(),
+                Err(e) => format!("-ERR Error trying to reload: {}\r\n", e),
+            },
+            Command::DebugConsistencyCheck => {
+                let problems = store.consistency_check();
+                if problems.is_empty() {
+                    "+OK\r\n".to_string()
+                } else {
+                    format!("-ERR consistency check failed: {}\r\n", problems.join("; "))
+                }
+            }
+            // The server sleeps for both itself, asynchronously, so neither
+            // ever blocks a worker thread here.
+            Command::DebugSleep(_) | Command::DebugLockedSleep(_) => {
+                "-ERR DEBUG SLEEP is not allowed in this context\r\n".to_string()
+            }
+
+            Command::CmdCount => resp_integer(COMMAND_TABLE.len() as i64),
+            Command::CmdInfo(names) => {
+                if names.is_empty() {
+                    format!("*{}\r\n", COMMAND_TABLE.len())
+                        + &COMMAND_TABLE.iter().map(command_entry).collect::<String>()
+                } else {
+                    format!("*{}\r\n", names.len())
+                        + &names
+                            .iter()
+                            .map(|name| match command_meta(name) {
+                                Some(meta) => command_entry(meta),
+                                None => NULL_ARRAY.to_string(),
+                            })
+                            .collect::<String>()
+                }
+            }
+            // Unknown names are left out rather than answered with a nil, as
+            // in Redis.
+            Command::CmdDocs(names) => {
+                let metas: Vec<&CommandMeta> = if names.is_empty() {
+                    COMMAND_TABLE.iter().collect()
+                } else {
+                    names.iter().filter_map(|name| command_meta(name)).collect()
+                };
+                format!("*{}\r\n", metas.len() * 2)
+                    + &metas.into_iter().map(command_docs).collect::<String>()
+            }
+        }
+    }
+
+    /// How long a blocking command may wait for data, if it blocks at all.
+    /// A zero duration means wait indefinitely.
+    pub fn block_timeout(&self) -> Option<Duration> {
+        match self {
+            Command::XRead(_, Some(ms), _) => Some(Duration::from_millis(*ms)),
+            _ => None,
+        }
+    }
+
+    /// Pins `$` ids to the stream's current last entry, so that retries of a
+    /// blocked XREAD only return entries added after it was first issued.
+    pub fn resolve_stream_ids(&mut self, store: &mut Store) {
+        if let Command::XRead(_, _, streams) = self {
+            for (key, id) in streams.iter_mut() {
+                // A key of another type is left for the read to report.
+                if id.is_none() {
+                    *id = Some(store.stream_last_id(key).unwrap_or(StreamId::MIN));
+                }
+            }
+        }
+    }
+
+    /// Rewrites `response`, this command's RESP2 reply, for a RESP3
+    /// connection: field/value arrays become maps, member arrays sets, scores
+    /// doubles and null bulks nulls. Replies that RESP3 writes the same way,
+    /// errors included, pass through unchanged.
+    pub fn resp3_reply(&self, response: Vec<u8>) -> Vec<u8> {
+        if response == NULL_BULK.as_bytes() || response == NULL_ARRAY.as_bytes() {
+            return b"_\r\n".to_vec();
+        }
+        match self {
+            Command::HGetAll(..) | Command::ConfigGet(_) => retype_array(response, '%', 2),
+            Command::SMembers(_) | Command::SInter(_) | Command::SUnion(_) | Command::SDiff(_) => {
+                retype_array(response, '~', 1)
+            }
+            Command::ZScore(..) => match split_first_line(&response) {
+                Some((header, score)) if header.starts_with(b"$") => [b",", score].concat(),
+                _ => response,
+            },
+            _ => response,
+        }
+    }
+
+    /// The exact size of the reply for a command that sends stored values
+    /// back whole, worked out from the values in place, so a reply over
+    /// `proto-max-bulk-len` can be refused before it is built. `None` for
+    /// every other command, and for replies that are only an error or nil.
+    pub fn reply_size(&self, store: &Store) -> Option<usize> {
+        match self {
+            Command::Get(key) => match store.peek(key)? {
+                Value::String(s) => Some(bulk_len(s.len())),
+                _ => None,
+            },
+            Command::MGet(keys) => Some(
+                array_header_len(keys.len())
+                    + keys
+                        .iter()
+                        .map(|key| match store.peek(key) {
+                            Some(Value::String(s)) => bulk_len(s.len()),
+                            _ => NULL_BULK.len(),
+                        })
+                        .sum::<usize>(),
+            ),
+            Command::LRange(key, start, end) => match store.peek(key)? {
+                Value::List(list) => Some(match resolve_range(*start, *end, list.len()) {
+                    Some((start, end)) => array_size(list.range(start..=end)),
+                    None => array_header_len(0),
+                }),
+                _ => None,
+            },
+            Command::HGetAll(key, _) => match store.peek(key)? {
+                Value::Hash(hash) => Some(
+                    array_header_len(hash.len() * 2)
+                        + hash
+                            .iter()
+                            .map(|(field, value)| bulk_len(field.len()) + bulk_len(value.len()))
+                            .sum::<usize>(),
+                ),
+                _ => None,
+            },
+            Command::HKeys(key) => match store.peek(key)? {
+                Value::Hash(hash) => Some(array_size(hash.keys())),
+                _ => None,
+            },
+            Command::HVals(key) => match store.peek(key)? {
+                Value::Hash(hash) => Some(array_size(hash.values())),
+                _ => None,
+            },
+            Command::SMembers(key) => match store.peek(key)? {
+                Value::Set(members) => Some(array_size(members.iter())),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Whether the connection should be closed once this command's reply has
+    /// been written.
+    pub fn closes_connection(&self) -> bool {
+        matches!(self, Command::Quit | Command::Shutdown(false))
+    }
+
+    /// Whether the command may run on a connection with subscriptions, which
+    /// only accepts commands that manage them.
+    pub fn allowed_while_subscribed(&self) -> bool {
+        matches!(
+            self,
+            Command::Subscribe(_) | Command::Unsubscribe(_) | Command::Ping | Command::Quit
+        )
+    }
+
+    /// Whether the command may run before the connection has authenticated.
+    pub fn allowed_unauthenticated(&self) -> bool {
+        matches!(self, Command::Auth(_) | Command::Ping | Command::Quit)
+    }
+
+    /// Whether the command acts on the connection's MULTI block rather than
+    /// being queued in it.
+    pub fn controls_transaction(&self) -> bool {
+        matches!(
+            self,
+            Command::Multi | Command::Exec | Command::Discard | Command::Quit
+        )
+    }
+}
+
+/// Gives the array `response` the RESP3 aggregate type `marker`, counting
+/// `per_element` array items as one element of the new type. Anything that is
+/// not an array, such as an error, is returned as is.
+fn retype_array(response: Vec<u8>, marker: char, per_element: usize) -> Vec<u8> {
+    let len = split_first_line(&response).and_then(|(header, body)| {
+        let len = std::str::from_utf8(header.strip_prefix(b"*")?).ok()?;
+        Some((len.parse::<usize>().ok()?, body))
+    });
+    match len {
+        Some((len, body)) => [
+            format!("{}{}\r\n", marker, len / per_element).as_bytes(),
+            body,
+        ]
+        .concat(),
+        None => response,
+    }
+}
+
+/// Splits a reply into its first line, without the `\r\n`, and the rest.
+fn split_first_line(reply: &[u8]) -> Option<(&[u8], &[u8])> {
+    let end = reply.windows(2).position(|pair| pair == b"\r\n")?;
+    Some((&reply[..end], &reply[end + 2..]))
+}
+
+/// Parses the options after `SET key value`. Giving both `EX` and `PX`, or
+/// both `NX` and `XX`, is a syntax error.
+fn parse_set_options(args: &[&str]) -> Result<SetOptions, String> {
+    let mut options = SetOptions::default();
+    let mut rest = args;
+    while let Some(option) = rest.first() {
+        match option.to_uppercase().as_str() {
+            unit @ ("EX" | "PX") if rest.len() >= 2 && options.ttl.is_none() => {
+                let amount = rest[1]
+                    .parse::<u64>()
+                    .map_err(|_| NOT_AN_INTEGER.to_string())?;
+                let ttl = if unit == "EX" {
+                    Duration::from_secs(amount)
+                } else {
+                    Duration::from_millis(amount)
+                };
+                if amount == 0 || deadline_in(ttl).is_none() {
+                    return Err("invalid expire time in 'set' command".to_string());
+                }
+                options.ttl = Some(ttl);
+                rest = &rest[2..];
+            }
+            "NX" if options.condition.is_none() => {
+                options.condition = Some(SetCondition::IfMissing);
+                rest = &rest[1..];
+            }
+            "XX" if options.condition.is_none() => {
+                options.condition = Some(SetCondition::IfExists);
+                rest = &rest[1..];
+            }
+            _ => return Err("syntax error".to_string()),
+        }
+    }
+    Ok(options)
+}
+
+/// A word of a request that must be text, such as a key or an option.
+fn text(word: &[u8]) -> Result<&str, ParseError> {
+    std::str::from_utf8(word).map_err(|_| {
+        ParseError::Invalid("only string values may be binary; invalid UTF-8 in argument".into())
+    })
+}
+
+/// Parses a SETBIT or GETBIT offset, which Redis caps at 2^32 bits.
+fn parse_bit_offset(arg: &str) -> Result<u64, String> {
+    arg.parse::<u64>()
+        .ok()
+        .filter(|&offset| offset < 1 << 32)
+        .ok_or_else(|| "bit offset is not an integer or out of range".to_string())
+}
+
+/// Parses a sorted-set score. `inf`, `+inf` and `-inf` are accepted, NaN is
+/// not.
+fn parse_score(arg: &str) -> Result<f64, String> {
+    arg.parse::<f64>()
+        .ok()
+        .filter(|score| !score.is_nan())
+        .ok_or_else(|| NOT_A_FLOAT.to_string())
+}
+
+/// Parses a ZRANGEBYSCORE bound: a score, exclusive if prefixed with `(`.
+fn parse_score_bound(arg: &str) -> Result<ScoreBound, String> {
+    let (value, exclusive) = match arg.strip_prefix('(') {
+        Some(value) => (value, true),
+        None => (arg, false),
+    };
+    let value = parse_score(value).map_err(|_| "min or max is not a float".to_string())?;
+    Ok(ScoreBound { value, exclusive })
+}
+
+/// Sorted-set members as reply elements, each followed by its score if
+/// `with_scores`.
+fn scored_members(members: Vec<(String, f64)>, with_scores: bool) -> Vec<String> {
+    members
+        .into_iter()
+        .flat_map(|(member, score)| {
+            let score = with_scores.then(|| score.to_string());
+            std::iter::once(member).chain(score)
+        })
+        .collect()
+}
+
+/// Parses the `LEFT` or `RIGHT` of an LMOVE.
+fn parse_list_end(arg: &str) -> Result<ListEnd, String> {
+    match arg.to_uppercase().as_str() {
+        "LEFT" => Ok(ListEnd::Left),
+        "RIGHT" => Ok(ListEnd::Right),
+        _ => Err("syntax error".to_string()),
+    }
+}
+
+/// Parses a `FIELDS numfields field [field ...]` block, checking that the
+/// declared count matches the number of fields given.
+fn parse_fields(args: &[&str]) -> Result<Vec<String>, String> {
+    if args.len() < 2 || !args[0].eq_ignore_ascii_case("FIELDS") {
+        return Err("Expected FIELDS numfields field [field ...]".to_string());
+    }
+    let count = args[1]
+        .parse::<usize>()
+        .map_err(|_| "Invalid numfields".to_string())?;
+    let fields = &args[2..];
+    if count == 0 || count != fields.len() {
+        return Err("numfields does not match the number of fields".to_string());
+    }
+    Ok(fields.iter().map(|&s| s.to_string()).collect())
+}
+
+/// Parses a possibly fractional, non-negative number of seconds.
+fn parse_seconds(arg: &str) -> Result<Duration, String> {
+    arg.parse::<f64>()
+        .ok()
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        .ok_or_else(|| "value is not a valid float".to_string())
+}
+
+/// Looks up a command's metadata by name, ignoring case.
+fn command_meta(name: &str) -> Option<&'static CommandMeta> {
+    COMMAND_TABLE
+        .iter()
+        .find(|meta| meta.name.eq_ignore_ascii_case(name))
+}
+
+/// The key arguments of a call to `name` with `args`, located through its
+/// `COMMAND_TABLE` entry. Commands that find their keys by parsing report
+/// none.
+pub fn key_args<'a, T: ?Sized>(name: &str, args: &[&'a T]) -> Vec<&'a T> {
+    let Some(meta) = command_meta(name).filter(|meta| meta.first_key > 0) else {
+        return Vec::new();
+    };
+    // Positions count the command name; a negative last key counts from the
+    // end.
+    let last = if meta.last_key < 0 {
+        args.len() as i64 + 1 + meta.last_key
+    } else {
+        meta.last_key
+    };
+    (meta.first_key..=last)
+        .step_by(meta.step.max(1) as usize)
+        .filter_map(|position| args.get(position as usize - 1).copied())
+        .collect()
+}
+
+/// Whether the command named `name` modifies the dataset.
+pub fn is_write(name: &str) -> bool {
+    command_meta(name).is_some_and(|meta| meta.flags.contains(&"write"))
+}
+
+/// One `COMMAND INFO` entry: name, arity, flags, first key, last key, step.
+fn command_entry(meta: &CommandMeta) -> String {
+    let name = meta.name.to_lowercase();
+    format!(
+        "*6\r\n${}\r\n{}\r\n:{}\r\n*{}\r\n{}:{}\r\n:{}\r\n:{}\r\n",
+        name.len(),
+        name,
+        meta.arity,
+        meta.flags.len(),
+        meta.flags
+            .iter()
+            .map(|flag| format!("+{}\r\n", flag))
+            .collect::<String>(),
+        meta.first_key,
+        meta.last_key,
+        meta.step
+    )
+}
+
+/// The INFO report: `field:value` lines under `# Section` headers. With a
+/// section name other than `all` or `default`, only that section is included.
+fn info_report(store: &Store, section: Option<&str>) -> String {
+    let stats = store.stats();
+    let uptime = stats.started.elapsed().as_secs();
+    let keyspace: String = store
+        .keyspace_info()
+        .iter()
+        .map(|(index, keys, expires)| format!("db{}:keys={},expires={}\r\n", index, keys, expires))
+        .collect();
+    let sections = [
+        (
+            "Server",
+            format!(
+                "keyval_version:{}\r\nprocess_id:{}\r\nuptime_in_seconds:{}\r\nuptime_in_days:{}\r\n",
+                env!("CARGO_PKG_VERSION"),
+                std::process::id(),
+                uptime,
+                uptime / 86400
+            ),
+        ),
+        (
+            "Clients",
+            format!(
+                "connected_clients:{}\r\n",
+                stats.connected_clients.load(Ordering::Relaxed)
+            ),
+        ),
+        (
+            "Stats",
+            format!(
+                "total_connections_received:{}\r\ntotal_commands_processed:{}\r\n",
+                stats.connections_received.load(Ordering::Relaxed),
+                stats.commands_processed.load(Ordering::Relaxed)
+            ),
+        ),
+        (
+            "Replication",
+            format!(
+                "role:master\r\nconnected_slaves:{}\r\nmaster_replid:{}\r\n",
+                stats.connected_replicas.load(Ordering::Relaxed),
+                store.replid()
+            ),
+        ),
+        ("Keyspace", keyspace),
+    ];
+
+    let wanted = section.map(str::to_lowercase);
+    sections
+        .iter()
+        .filter(|(name, _)| match wanted.as_deref() {
+            None | Some("all" | "default" | "everything") => true,
+            Some(wanted) => name.eq_ignore_ascii_case(wanted),
+        })
+        .map(|(name, body)| format!("# {}\r\n{}", name, body))
+        .collect::<Vec<_>>()
+        .join("\r\n")
+}
+
+/// One `COMMAND DOCS` entry: the name, then its documentation as a flat
+/// map. Only the arity is known here, since the table carries no prose.
+fn command_docs(meta: &CommandMeta) -> String {
+    let name = meta.name.to_lowercase();
+    format!(
+        "${}\r\n{}\r\n*2\r\n$5\r\narity\r\n:{}\r\n",
+        name.len(),
+        name,
+        meta.arity
+    )
+}
+
+/// Whether a request of `words` words, counting the command name, fits a
+/// command of the given arity.
+fn arity_matches(arity: i64, words: usize) -> bool {
+    if arity < 0 {
+        words as i64 >= -arity
+    } else {
+        words as i64 == arity
+    }
+}
+
+/// The known command closest to `name` by edit distance, if any is close
+/// enough to plausibly be what was meant.
+fn suggest_command(name: &str) -> Option<&'static str> {
+    let max_distance = (name.len() / 2).clamp(1, 2);
+    COMMAND_TABLE
+        .iter()
+        .map(|meta| (meta.name, edit_distance(name, meta.name)))
+        .filter(|&(_, distance)| distance <= max_distance)
+        .min_by_key(|&(_, distance)| distance)
+        .map(|(candidate, _)| candidate)
+}
+
+/// Edit distance where insertions, deletions, substitutions and swaps of two
+/// adjacent characters each cost one, so `GTE` is a single edit from `GET`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in d[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            d[i][j] = (d[i - 1][j] + 1)
+                .min(d[i][j - 1] + 1)
+                .min(d[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+    d[a.len()][b.len()]
+}
+
+fn parse_xread(args: &[&str]) -> Result<Command, String> {
+    let mut count = None;
+    let mut block = None;
+    let mut i = 0;
+
+    while i < args.len() {
+        match args[i].to_uppercase().as_str() {
+            "COUNT" if i + 1 < args.len() => {
+                count = Some(
+                    args[i + 1]
+                        .parse::<usize>()
+                        .map_err(|_| NOT_AN_INTEGER.to_string())?,
+                );
+                i += 2;
+            }
+            "BLOCK" if i + 1 < args.len() => {
+                block = Some(
+                    args[i + 1]
+                        .parse::<u64>()
+                        .map_err(|_| "timeout is not an integer or out of range".to_string())?,
+                );
+                i += 2;
+            }
+            "STREAMS" => break,
+            _ => return Err("Invalid XREAD option".to_string()),
+        }
+    }
+
+    let rest = args.get(i + 1..).unwrap_or_default();
+    if rest.is_empty() || !rest.len().is_multiple_of(2) {
+        return Err("Unbalanced XREAD list of streams".to_string());
+    }
+
+    let (keys, ids) = rest.split_at(rest.len() / 2);
+    let streams = keys
+        .iter()
+        .zip(ids)
+        .map(|(&key, &id)| {
+            let id = match id {
+                "$" => None,
+                id => Some(StreamId::parse(id, 0).ok_or("Invalid stream ID")?),
+            };
+            Ok((key.to_string(), id))
+        })
+        .collect::<Result<_, String>>()?;
+
+    Ok(Command::XRead(count, block, streams))
+}
+
+fn stream_entries(entries: &[StreamEntry]) -> String {
+    format!("*{}\r\n", entries.len())
+        + &entries
+            .iter()
+            .map(|(id, fields)| {
+                let id = id.to_string();
+                format!("*2\r\n${}\r\n{}\r\n*{}\r\n", id.len(), id, fields.len() * 2)
+                    + &fields
+                        .iter()
+                        .map(|(f, v)| format!("${}\r\n{}\r\n${}\r\n{}\r\n", f.len(), f, v.len(), v))
+                        .collect::<String>()
+            })
+            .collect::<String>()
+}
+
+/// Whether `line` is clearly not meant for this server: an HTTP request line
+/// (someone pointed a browser at the port), or text containing control
+/// characters that no inline command would. The connection is closed at the
+/// request line, so the headers after it are never read.
+pub fn is_protocol_garbage(line: &str) -> bool {
+    let line = line.trim_end_matches(['\r', '\n']);
+    let control = line.chars().any(|c| c.is_control() && c != '\t');
+    control || is_http_request_line(line)
+}
+
+/// Whether `line` is exactly `METHOD target HTTP/x.y` with a known method.
+fn is_http_request_line(line: &str) -> bool {
+    const METHODS: &[&str] = &[
+        "GET", "HEAD", "POST", "PUT", "DELETE", "CONNECT", "OPTIONS", "TRACE", "PATCH", "PRI",
+    ];
+    let tokens: Vec<&str> = line.split_whitespace().collect();
+    let [method, _target, version] = tokens[..] else {
+        return false;
+    };
+    let Some(version) = version.strip_prefix("HTTP/") else {
+        return false;
+    };
+    let is_version = match version.split_once('.') {
+        Some((major, minor)) => is_digits(major) && is_digits(minor),
+        None => is_digits(version),
+    };
+    METHODS.contains(&method) && is_version
+}
+
+fn is_digits(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Replaces a reply longer than `max` bytes with an error, so one command
+/// cannot push an arbitrarily large payload at the client.
+pub fn cap_reply(response: Vec<u8>, max: usize) -> Vec<u8> {
+    if response.len() > max {
+        REPLY_TOO_LARGE.into()
+    } else {
+        response
+    }
+}
+
+/// A RESP integer reply.
+fn resp_integer(n: i64) -> String {
+    format!(":{}\r\n", n)
+}
+
+/// The error reply for a failed typed store operation.
+pub fn store_error(err: StoreError) -> String {
+    match err {
+        StoreError::WrongType => WRONGTYPE.to_string(),
+        StoreError::NotAnInteger => format!("-ERR {}\r\n", NOT_AN_INTEGER),
+        StoreError::TooLarge => {
+            "-ERR string exceeds maximum allowed size (proto-max-bulk-len)\r\n".to_string()
+        }
+        StoreError::StreamIdTooSmall => {
+            "-ERR The ID specified in XADD is equal or smaller than the target stream top item\r\n"
+                .to_string()
+        }
+    }
+}
+
+pub fn string_array(values: &[String]) -> String {
+    format!("*{}\r\n", values.len())
+        + &values
+            .iter()
+            .map(|v| format!("${}\r\n{}\r\n", v.len(), v))
+            .collect::<String>()
+}
+
+/// Bytes a bulk string of `len` bytes takes up once framed as
+/// `$<len>\r\n...\r\n`.
+pub fn bulk_len(len: usize) -> usize {
+    len + len.to_string().len() + 5
+}
+
+/// Bytes the `*<count>\r\n` header of an array of `count` elements takes up.
+pub fn array_header_len(count: usize) -> usize {
+    count.to_string().len() + 3
+}
+
+/// Bytes `string_array` of `items` would take up.
+fn array_size<'a>(items: impl ExactSizeIterator<Item = &'a String>) -> usize {
+    array_header_len(items.len()) + items.map(|item| bulk_len(item.len())).sum::<usize>()
+}
+
+/// A RESP bulk string holding `value` as is.
+fn bulk_string(value: &[u8]) -> Vec<u8> {
+    let mut reply = format!("${}\r\n", value.len()).into_bytes();
+    reply.extend_from_slice(value);
+    reply.extend_from_slice(b"\r\n");
+    reply
+}
+
+fn bulk_array(values: &[Option<String>]) -> String {
+    format!("*{}\r\n", values.len())
+        + &values
+            .iter()
+            .map(|v| match v {
+                Some(v) => format!("${}\r\n{}\r\n", v.len(), v),
+                None => NULL_BULK.to_string(),
+            })
+            .collect::<String>()
+}
+
+/// `bulk_array` for string values, which may be binary.
+fn bulk_bytes_array(values: &[Option<Vec<u8>>]) -> Vec<u8> {
+    let mut reply = format!("*{}\r\n", values.len()).into_bytes();
+    for value in values {
+        match value {
+            Some(value) => reply.extend(bulk_string(value)),
+            None => reply.extend_from_slice(NULL_BULK.as_bytes()),
+        }
+    }
+    reply
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::Mutex;
+
+    /// Parses and runs one command against `store`, returning the reply.
+    fn run_bytes(store: &Mutex<Store>, parts: &[&[u8]]) -> Vec<u8> {
+        let command = Command::parse(parts).unwrap();
+        command.execute(&mut store.try_lock().unwrap())
+    }
+
+    /// `run_bytes` for a command and reply that are both text.
+    fn run(store: &Mutex<Store>, parts: &[&str]) -> String {
+        let parts: Vec<&[u8]> = parts.iter().map(|part| part.as_bytes()).collect();
+        String::from_utf8(run_bytes(store, &parts)).unwrap()
+    }
+
+    #[test]
+    fn http_request_lines_are_garbage() {
+        assert!(is_protocol_garbage("GET / HTTP/1.1\r\n"));
+        assert!(is_protocol_garbage("POST /submit HTTP/1.0"));
+        assert!(is_protocol_garbage("PRI * HTTP/2.0"));
+        assert!(is_protocol_garbage("GET / HTTP/2"));
+    }
+
+    #[test]
+    fn inline_commands_mentioning_http_are_not_garbage() {
+        assert!(!is_protocol_garbage("SET proto HTTP/1.1"));
+        assert!(!is_protocol_garbage("GET HTTP/1.1"));
+        assert!(!is_protocol_garbage("GET / HTTP/1.1 extra"));
+        assert!(!is_protocol_garbage("GET / HTTP/x.y"));
+        assert!(!is_protocol_garbage("Host: example.com"));
+        assert!(!is_protocol_garbage("HSET user:1 name: bob"));
+    }
+
+    #[test]
+    fn control_characters_are_garbage() {
+        assert!(is_protocol_garbage("SET k \x00\x01"));
+        assert!(!is_protocol_garbage("SET k a\tb"));
+    }
+
+    #[test]
+    fn get_and_strlen_reject_other_types() {
+        let store = Mutex::new(Store::with_seed(1));
+        run(&store, &["RPUSH", "l", "a", "bb"]);
+
+        assert_eq!(run(&store, &["GET", "l"]), WRONGTYPE);
+        assert_eq!(run(&store, &["STRLEN", "l"]), WRONGTYPE);
+        assert_eq!(run(&store, &["MGET", "l"]), "*1\r\n$-1\r\n");
+    }
+
+    #[test]
+    fn strlen_of_a_missing_key_is_zero() {
+        let store = Mutex::new(Store::with_seed(1));
+        assert_eq!(run(&store, &["STRLEN", "nokey"]), ":0\r\n");
+        run(&store, &["SET", "k", "hello"]);
+        assert_eq!(run(&store, &["STRLEN", "k"]), ":5\r\n");
+    }
+
+    #[test]
+    fn string_values_are_binary_safe() {
+        let store = Mutex::new(Store::with_seed(1));
+        assert_eq!(run(&store, &["SETBIT", "b", "0", "1"]), ":0\r\n");
+        assert_eq!(run_bytes(&store, &[b"GET", b"b"]), b"$1\r\n\x80\r\n");
+
+        run_bytes(&store, &[b"SET", b"k", b"\xff\x00\xfe"]);
+        assert_eq!(
+            run_bytes(&store, &[b"GET", b"k"]),
+            b"$3\r\n\xff\x00\xfe\r\n"
+        );
+        assert_eq!(run(&store, &["STRLEN", "k"]), ":3\r\n");
+    }
+
+    #[test]
+    fn only_string_values_may_be_binary() {
+        let parsed = Command::parse(&[b"RPUSH", b"l", b"\xff"]);
+        assert!(matches!(parsed, Err(ParseError::Invalid(_))));
+        let parsed = Command::parse(&[b"SET", b"\xff", b"v"]);
+        assert!(matches!(parsed, Err(ParseError::Invalid(_))));
+    }
+
+    #[test]
+    fn pubsub_reports_channels_and_subscriber_counts() {
+        let store = Mutex::new(Store::with_seed(1));
+        let (outbox, _messages) = tokio::sync::mpsc::unbounded_channel();
+        {
+            let mut store = store.try_lock().unwrap();
+            store.subscribe("news.sport", 1, outbox.clone());
+            store.subscribe("news.sport", 2, outbox.clone());
+            store.subscribe("weather", 2, outbox);
+        }
+
+        assert_eq!(
+            run(&store, &["PUBSUB", "CHANNELS"]),
+            "*2\r\n$10\r\nnews.sport\r\n$7\r\nweather\r\n"
+        );
+        assert_eq!(
+            run(&store, &["PUBSUB", "CHANNELS", "news.*"]),
+            "*1\r\n$10\r\nnews.sport\r\n"
+        );
+        assert_eq!(
+            run(&store, &["PUBSUB", "NUMSUB", "news.sport", "none"]),
+            "*4\r\n$10\r\nnews.sport\r\n:2\r\n$4\r\nnone\r\n:0\r\n"
+        );
+        assert_eq!(run(&store, &["PUBSUB", "NUMPAT"]), ":0\r\n");
+
+        store.try_lock().unwrap().unsubscribe("weather", 2);
+        assert_eq!(run(&store, &["PUBSUB", "CHANNELS", "w*"]), "*0\r\n");
+    }
+
+    #[test]
+    fn debug_dump_struct_shows_type_length_elements_and_expiry() {
+        let store = Mutex::new(Store::with_seed(1));
+        run(&store, &["RPUSH", "l", "a", "b", "c"]);
+        assert_eq!(
+            run(&store, &["DEBUG", "DUMP-STRUCT", "l", "2"]),
+            "$74\r\ntype: list\nlength: 3\nelements (first 2):\n  0) \"a\"\n  1) \"b\"\nexpires: never\n\r\n"
+        );
+
+        run(&store, &["HSET", "h", "b", "2"]);
+        run(&store, &["HSET", "h", "a", "1"]);
+        run(&store, &["EXPIRE", "h", "100"]);
+        let dump = run(&store, &["DEBUG", "DUMP-STRUCT", "h"]);
+        assert!(
+            dump.contains("type: hash\nlength: 2\nelements (first 2):\n  0) \"a\" => \"1\"\n  1) \"b\" => \"2\"\nexpires_in_ms: "),
+            "{:?}",
+            dump
+        );
+
+        assert_eq!(
+            run(&store, &["DEBUG", "DUMP-STRUCT", "missing"]),
+            "-ERR no such key\r\n"
+        );
+    }
+
+    #[test]
+    fn counter_commands_reply_with_resp_integers() {
+        let store = Mutex::new(Store::with_seed(1));
+        assert_eq!(run(&store, &["INCR", "n"]), ":1\r\n");
+        assert_eq!(run(&store, &["INCRBY", "n", "41"]), ":42\r\n");
+        assert_eq!(run(&store, &["DECR", "n"]), ":41\r\n");
+        assert_eq!(run(&store, &["DECRBY", "n", "50"]), ":-9\r\n");
+        assert_eq!(run(&store, &["GET", "n"]), "$2\r\n-9\r\n");
+    }
+
+    #[test]
+    fn hash_commands_create_overwrite_and_count_fields() {
+        let store = Mutex::new(Store::with_seed(1));
+        assert_eq!(run(&store, &["HSET", "h", "f", "v"]), ":1\r\n");
+        assert_eq!(run(&store, &["HGET", "h", "f"]), "$1\r\nv\r\n");
+        assert_eq!(run(&store, &["HSET", "h", "f", "w"]), ":0\r\n");
+        assert_eq!(run(&store, &["HGET", "h", "f"]), "$1\r\nw\r\n");
+        assert_eq!(run(&store, &["HLEN", "h"]), ":1\r\n");
+        assert_eq!(run(&store, &["HINCRBY", "h", "n", "5"]), ":5\r\n");
+        assert_eq!(run(&store, &["HDEL", "h", "f"]), ":1\r\n");
+        assert_eq!(
+            run(&store, &["HGETALL", "h"]),
+            "*2\r\n$1\r\nn\r\n$1\r\n5\r\n"
+        );
+    }
+
+    #[test]
+    fn hincrby_rejects_a_non_numeric_field() {
+        let store = Mutex::new(Store::with_seed(1));
+        run(&store, &["HSET", "h", "f", "abc"]);
+        assert_eq!(
+            run(&store, &["HINCRBY", "h", "f", "1"]),
+            "-ERR hash value is not an integer\r\n"
+        );
+        assert_eq!(run(&store, &["HGET", "h", "f"]), "$3\r\nabc\r\n");
+    }
+
+    #[test]
+    fn sadd_counts_only_new_members() {
+        let store = Mutex::new(Store::with_seed(1));
+        assert_eq!(run(&store, &["SADD", "s", "a", "a", "b"]), ":2\r\n");
+        assert_eq!(run(&store, &["SADD", "s", "a"]), ":0\r\n");
+        assert_eq!(run(&store, &["SCARD", "s"]), ":2\r\n");
+        assert_eq!(run(&store, &["SISMEMBER", "s", "b"]), ":1\r\n");
+        assert_eq!(run(&store, &["SREM", "s", "a", "z"]), ":1\r\n");
+        assert_eq!(run(&store, &["SMEMBERS", "s"]), "*1\r\n$1\r\nb\r\n");
+    }
+
+    #[test]
+    fn set_commands_reject_other_types() {
+        let store = Mutex::new(Store::with_seed(1));
+        run(&store, &["SET", "k", "v"]);
+        for command in [
+            &["SADD", "k", "a"][..],
+            &["SREM", "k", "a"],
+            &["SISMEMBER", "k", "a"],
+            &["SMEMBERS", "k"],
+            &["SCARD", "k"],
+        ] {
+            assert_eq!(run(&store, command), WRONGTYPE, "{:?}", command);
+        }
+        assert_eq!(run(&store, &["GET", "k"]), "$1\r\nv\r\n");
+    }
+
+    #[test]
+    fn set_applies_ex_px_nx_and_xx() {
+        let store = Mutex::new(Store::with_seed(1));
+        assert_eq!(run(&store, &["SET", "k", "v", "EX", "100"]), "+OK\r\n");
+        let ttl = run(&store, &["PTTL", "k"]);
+        let ttl: i64 = ttl[1..ttl.len() - 2].parse().unwrap();
+        assert!((99_000..=100_000).contains(&ttl), "{}", ttl);
+
+        assert_eq!(run(&store, &["SET", "k", "v", "PX", "5000"]), "+OK\r\n");
+        let ttl = run(&store, &["PTTL", "k"]);
+        let ttl: i64 = ttl[1..ttl.len() - 2].parse().unwrap();
+        assert!((4_000..=5_000).contains(&ttl), "{}", ttl);
+
+        assert_eq!(run(&store, &["SET", "k", "w", "NX"]), NULL_BULK);
+        assert_eq!(run(&store, &["SET", "new", "v", "NX"]), "+OK\r\n");
+        assert_eq!(run(&store, &["SET", "k", "x", "XX"]), "+OK\r\n");
+        assert_eq!(run(&store, &["GET", "k"]), "$1\r\nx\r\n");
+        assert_eq!(run(&store, &["TTL", "k"]), ":-1\r\n");
+        assert_eq!(run(&store, &["SET", "absent", "x", "XX"]), NULL_BULK);
+        assert_eq!(run(&store, &["EXISTS", "absent"]), ":0\r\n");
+    }
+
+    #[test]
+    fn set_rejects_conflicting_options_at_parse_time() {
+        for args in [
+            &[&b"SET"[..], b"k", b"v", b"NX", b"XX"][..],
+            &[b"SET", b"k", b"v", b"EX", b"1", b"PX", b"5"],
+            &[b"SET", b"k", b"v", b"EX", b"0"],
+            &[b"SET", b"k", b"v", b"EX"],
+        ] {
+            assert!(Command::parse(args).is_err(), "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn set_rejects_a_ttl_past_the_latest_deadline() {
+        let huge = u64::MAX.to_string();
+        let past_max = i64::MAX.to_string();
+        for (unit, amount) in [("EX", &huge), ("PX", &huge), ("PX", &past_max)] {
+            let parsed = Command::parse(&[b"SET", b"k", b"v", unit.as_bytes(), amount.as_bytes()]);
+            assert!(
+                matches!(&parsed, Err(ParseError::Invalid(msg)) if msg == "invalid expire time in 'set' command"),
+                "{} {}",
+                unit,
+                amount
+            );
+        }
+    }
+
+    #[test]
+    fn pexpire_reports_a_positive_pttl_until_the_key_lapses() {
+        let store = Mutex::new(Store::with_seed(1));
+        run(&store, &["SET", "k", "v"]);
+        assert_eq!(run(&store, &["PEXPIRE", "k", "500"]), ":1\r\n");
+        let ttl = run(&store, &["PTTL", "k"]);
+        let ttl: i64 = ttl[1..ttl.len() - 2].parse().unwrap();
+        assert!((1..=500).contains(&ttl), "{}", ttl);
+
+        std::thread::sleep(Duration::from_millis(550));
+        assert_eq!(run(&store, &["PTTL", "k"]), ":-2\r\n");
+        assert_eq!(run(&store, &["GET", "k"]), NULL_BULK);
+    }
+
+    #[test]
+    fn expire_and_ttl_still_work_in_seconds() {
+        let store = Mutex::new(Store::with_seed(1));
+        run(&store, &["SET", "k", "v"]);
+        assert_eq!(run(&store, &["TTL", "k"]), ":-1\r\n");
+        assert_eq!(run(&store, &["EXPIRE", "k", "10"]), ":1\r\n");
+        assert_eq!(run(&store, &["TTL", "k"]), ":10\r\n");
+        let ttl = run(&store, &["PTTL", "k"]);
+        let ttl: i64 = ttl[1..ttl.len() - 2].parse().unwrap();
+        assert!((9_000..=10_000).contains(&ttl), "{}", ttl);
+        assert_eq!(run(&store, &["PEXPIRE", "missing", "500"]), ":0\r\n");
+    }
+
+    #[test]
+    fn type_names_every_kind_of_value() {
+        let store = Mutex::new(Store::with_seed(1));
+        run(&store, &["SET", "s", "v"]);
+        run(&store, &["RPUSH", "l", "a"]);
+        run(&store, &["HSET", "h", "f", "v"]);
+        run(&store, &["SADD", "set", "a"]);
+        run(&store, &["ZADD", "z", "1", "a"]);
+        run(&store, &["XADD", "x", "*", "f", "v"]);
+
+        for (key, kind) in [
+            ("s", "+string\r\n"),
+            ("l", "+list\r\n"),
+            ("h", "+hash\r\n"),
+            ("set", "+set\r\n"),
+            ("z", "+zset\r\n"),
+            ("x", "+stream\r\n"),
+            ("missing", "+none\r\n"),
+        ] {
+            assert_eq!(run(&store, &["TYPE", key]), kind);
+        }
+    }
+
+    #[test]
+    fn list_and_hash_commands_on_a_string_return_wrongtype() {
+        let store = Mutex::new(Store::with_seed(1));
+        run(&store, &["SET", "k", "v"]);
+        for command in [
+            &["LPUSH", "k", "a"][..],
+            &["RPUSH", "k", "a"],
+            &["LPOP", "k"],
+            &["LLEN", "k"],
+            &["LRANGE", "k", "0", "-1"],
+            &["HSET", "k", "f", "v"],
+            &["HGET", "k", "f"],
+            &["HGETALL", "k"],
+            &["HINCRBY", "k", "f", "1"],
+        ] {
+            assert_eq!(run(&store, command), WRONGTYPE, "{:?}", command);
+        }
+        assert_eq!(run(&store, &["GET", "k"]), "$1\r\nv\r\n");
+    }
+
+    #[test]
+    fn mset_needs_key_value_pairs() {
+        assert!(Command::parse(&[b"MSET", b"a", b"1", b"b"]).is_err());
+        assert!(Command::parse(&[b"MSET"]).is_err());
+    }
+
+    #[test]
+    fn mget_mixes_present_and_missing_keys() {
+        let store = Mutex::new(Store::with_seed(1));
+        assert_eq!(run(&store, &["MSET", "a", "1", "b", "2"]), "+OK\r\n");
+        assert_eq!(
+            run(&store, &["MGET", "a", "missing", "b"]),
+            "*3\r\n$1\r\n1\r\n$-1\r\n$1\r\n2\r\n"
+        );
+    }
+
+    #[test]
+    fn append_creates_extends_and_rejects_lists() {
+        let store = Mutex::new(Store::with_seed(1));
+        assert_eq!(run(&store, &["APPEND", "k", "hi"]), ":2\r\n");
+        assert_eq!(run(&store, &["APPEND", "k", " there"]), ":8\r\n");
+        assert_eq!(run(&store, &["GET", "k"]), "$8\r\nhi there\r\n");
+
+        run(&store, &["RPUSH", "l", "a"]);
+        assert_eq!(run(&store, &["APPEND", "l", "x"]), WRONGTYPE);
+        assert_eq!(run(&store, &["LLEN", "l"]), ":1\r\n");
+    }
+
+    #[test]
+    fn getset_and_getdel_return_the_old_value() {
+        let store = Mutex::new(Store::with_seed(1));
+        assert_eq!(run(&store, &["GETSET", "k", "1"]), NULL_BULK);
+        assert_eq!(run(&store, &["GETSET", "k", "2"]), "$1\r\n1\r\n");
+        assert_eq!(run(&store, &["GET", "k"]), "$1\r\n2\r\n");
+
+        assert_eq!(run(&store, &["GETDEL", "k"]), "$1\r\n2\r\n");
+        assert_eq!(run(&store, &["EXISTS", "k"]), ":0\r\n");
+        assert_eq!(run(&store, &["GETDEL", "k"]), NULL_BULK);
+    }
+
+    #[test]
+    fn getset_clears_the_ttl() {
+        let store = Mutex::new(Store::with_seed(1));
+        run(&store, &["SET", "k", "1", "EX", "100"]);
+        run(&store, &["GETSET", "k", "2"]);
+        assert_eq!(run(&store, &["TTL", "k"]), ":-1\r\n");
+    }
+
+    #[test]
+    fn setnx_leaves_an_existing_key_alone() {
+        let store = Mutex::new(Store::with_seed(1));
+        assert_eq!(run(&store, &["SETNX", "k", "first"]), ":1\r\n");
+        assert_eq!(run(&store, &["SETNX", "k", "second"]), ":0\r\n");
+        assert_eq!(run(&store, &["GET", "k"]), "$5\r\nfirst\r\n");
+    }
+
+    #[test]
+    fn setex_sets_the_value_with_a_ttl() {
+        let store = Mutex::new(Store::with_seed(1));
+        assert_eq!(run(&store, &["SETEX", "k", "100", "v"]), "+OK\r\n");
+        assert_eq!(run(&store, &["GET", "k"]), "$1\r\nv\r\n");
+        assert_eq!(run(&store, &["TTL", "k"]), ":100\r\n");
+        assert!(Command::parse(&[b"SETEX", b"k", b"0", b"v"]).is_err());
+    }
+
+    #[test]
+    fn setex_rejects_a_ttl_past_the_latest_deadline() {
+        let parsed = Command::parse(&[b"SETEX", b"k", b"18446744073709551615", b"v"]);
+        assert!(matches!(
+            &parsed,
+            Err(ParseError::Invalid(msg)) if msg == "invalid expire time in 'setex' command"
+        ));
+    }
+
+    #[test]
+    fn rename_carries_the_ttl_to_the_new_name() {
+        let store = Mutex::new(Store::with_seed(1));
+        run(&store, &["SET", "old", "v", "EX", "100"]);
+        assert_eq!(run(&store, &["RENAME", "old", "new"]), "+OK\r\n");
+        assert_eq!(run(&store, &["EXISTS", "old"]), ":0\r\n");
+        assert_eq!(run(&store, &["GET", "new"]), "$1\r\nv\r\n");
+        assert_eq!(run(&store, &["TTL", "new"]), ":100\r\n");
+        assert_eq!(run(&store, &["RENAME", "old", "x"]), "-ERR no such key\r\n");
+    }
+
+    #[test]
+    fn renamenx_refuses_to_overwrite() {
+        let store = Mutex::new(Store::with_seed(1));
+        run(&store, &["SET", "a", "1"]);
+        run(&store, &["SET", "b", "2"]);
+        assert_eq!(run(&store, &["RENAMENX", "a", "b"]), ":0\r\n");
+        assert_eq!(run(&store, &["GET", "a"]), "$1\r\n1\r\n");
+        assert_eq!(run(&store, &["GET", "b"]), "$1\r\n2\r\n");
+
+        assert_eq!(run(&store, &["RENAMENX", "a", "c"]), ":1\r\n");
+        assert_eq!(run(&store, &["GET", "c"]), "$1\r\n1\r\n");
+        assert_eq!(run(&store, &["EXISTS", "a"]), ":0\r\n");
+    }
+
+    #[test]
+    fn select_switches_to_a_separate_keyspace() {
+        let store = Mutex::new(Store::with_seed(1));
+        run(&store, &["SET", "k", "zero"]);
+        assert_eq!(run(&store, &["SELECT", "1"]), "+OK\r\n");
+        assert_eq!(run(&store, &["GET", "k"]), NULL_BULK);
+        assert_eq!(run(&store, &["DBSIZE"]), ":0\r\n");
+        run(&store, &["SET", "k", "one"]);
+
+        run(&store, &["SELECT", "0"]);
+        assert_eq!(run(&store, &["GET", "k"]), "$4\r\nzero\r\n");
+        assert_eq!(
+            run(&store, &["SELECT", "16"]),
+            "-ERR DB index is out of range\r\n"
+        );
+        assert_eq!(run(&store, &["GET", "k"]), "$4\r\nzero\r\n");
+    }
+
+    #[test]
+    fn persist_removes_a_ttl_and_reports_whether_there_was_one() {
+        let store = Mutex::new(Store::with_seed(1));
+        run(&store, &["SET", "k", "v", "EX", "100"]);
+        assert_eq!(run(&store, &["PERSIST", "k"]), ":1\r\n");
+        assert_eq!(run(&store, &["TTL", "k"]), ":-1\r\n");
+        assert_eq!(run(&store, &["PERSIST", "k"]), ":0\r\n");
+
+        run(&store, &["SET", "plain", "v"]);
+        assert_eq!(run(&store, &["PERSIST", "plain"]), ":0\r\n");
+        assert_eq!(run(&store, &["PERSIST", "missing"]), ":0\r\n");
+    }
+
+    #[test]
+    fn command_count_matches_the_command_table() {
+        let store = Mutex::new(Store::with_seed(1));
+        assert_eq!(
+            run(&store, &["COMMAND", "COUNT"]),
+            format!(":{}\r\n", COMMAND_TABLE.len())
+        );
+        let reply = run(&store, &["COMMAND"]);
+        assert!(reply.starts_with(&format!("*{}\r\n", COMMAND_TABLE.len())));
+
+        let mut names: Vec<_> = COMMAND_TABLE.iter().map(|meta| meta.name).collect();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), COMMAND_TABLE.len(), "duplicate table entry");
+    }
+
+    #[test]
+    fn pexpire_rejects_a_ttl_past_the_latest_deadline() {
+        for command in ["EXPIRE", "PEXPIRE"] {
+            let parsed = Command::parse(&[command.as_bytes(), b"k", b"18446744073709551615"]);
+            let expected = format!(
+                "invalid expire time in '{}' command",
+                command.to_lowercase()
+            );
+            assert!(
+                matches!(&parsed, Err(ParseError::Invalid(msg)) if *msg == expected),
+                "{}",
+                command
+            );
+        }
+    }
+
+    #[test]
+    fn expireat_in_the_future_sets_a_deadline() {
+        let store = Mutex::new(Store::with_seed(1));
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap();
+        run(&store, &["SET", "k", "v"]);
+        let at = (now.as_secs() + 100).to_string();
+        assert_eq!(run(&store, &["EXPIREAT", "k", &at]), ":1\r\n");
+        let ttl = run(&store, &["TTL", "k"]);
+        assert!(ttl == ":100\r\n" || ttl == ":99\r\n", "{:?}", ttl);
+
+        run(&store, &["SET", "p", "v"]);
+        let at = (now.as_millis() + 5_000).to_string();
+        assert_eq!(run(&store, &["PEXPIREAT", "p", &at]), ":1\r\n");
+        assert_eq!(run(&store, &["PEXPIRETIME", "p"]), format!(":{}\r\n", at));
+        assert_eq!(run(&store, &["EXPIREAT", "missing", &at]), ":0\r\n");
+    }
+
+    #[test]
+    fn expireat_in_the_past_deletes_the_key() {
+        let store = Mutex::new(Store::with_seed(1));
+        run(&store, &["SET", "k", "v"]);
+        assert_eq!(run(&store, &["EXPIREAT", "k", "1000"]), ":1\r\n");
+        assert_eq!(run(&store, &["EXISTS", "k"]), ":0\r\n");
+
+        run(&store, &["SET", "p", "v"]);
+        assert_eq!(run(&store, &["PEXPIREAT", "p", "1"]), ":1\r\n");
+        assert_eq!(run(&store, &["GET", "p"]), NULL_BULK);
+    }
+
+    #[test]
+    fn expireat_rejects_a_deadline_past_the_latest_one() {
+        let store = Mutex::new(Store::with_seed(1));
+        run(&store, &["SET", "k", "v"]);
+        let latest = i64::MAX.to_string();
+        assert_eq!(run(&store, &["PEXPIREAT", "k", &latest]), ":1\r\n");
+        assert_eq!(
+            run(&store, &["PEXPIRETIME", "k"]),
+            format!(":{}\r\n", latest)
+        );
+
+        for (command, at) in [
+            ("PEXPIREAT", (i64::MAX as u64 + 1).to_string()),
+            ("EXPIREAT", (i64::MAX as u64 / 1000 + 1).to_string()),
+        ] {
+            let parsed = Command::parse(&[command.as_bytes(), b"k", at.as_bytes()]);
+            let expected = format!(
+                "invalid expire time in '{}' command",
+                command.to_lowercase()
+            );
+            assert!(
+                matches!(&parsed, Err(ParseError::Invalid(msg)) if *msg == expected),
+                "{} {}",
+                command,
+                at
+            );
+        }
+    }
+
+    #[test]
+    fn lindex_and_lset_count_negative_indices_from_the_tail() {
+        let store = Mutex::new(Store::with_seed(1));
+        run(&store, &["RPUSH", "l", "a", "b", "c"]);
+        assert_eq!(run(&store, &["LINDEX", "l", "-1"]), "$1\r\nc\r\n");
+        assert_eq!(run(&store, &["LINDEX", "l", "-3"]), "$1\r\na\r\n");
+        assert_eq!(
+            run(&store, &["LINDEX", "l", "-4"]),
+            "-ERR index out of range\r\n"
+        );
+
+        assert_eq!(run(&store, &["LSET", "l", "-1", "z"]), "+OK\r\n");
+        assert_eq!(run(&store, &["LSET", "l", "-3", "x"]), "+OK\r\n");
+        assert_eq!(
+            run(&store, &["LSET", "l", "-4", "w"]),
+            "-ERR index out of range\r\n"
+        );
+        assert_eq!(
+            run(&store, &["LRANGE", "l", "0", "-1"]),
+            "*3\r\n$1\r\nx\r\n$1\r\nb\r\n$1\r\nz\r\n"
+        );
+    }
+
+    #[test]
+    fn pop_with_a_count_stops_at_the_end_of_the_list() {
+        let store = Mutex::new(Store::with_seed(1));
+        run(&store, &["RPUSH", "l", "a", "b", "c"]);
+        assert_eq!(
+            run(&store, &["LPOP", "l", "5"]),
+            "*3\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\nc\r\n"
+        );
+        assert_eq!(run(&store, &["EXISTS", "l"]), ":0\r\n");
+        assert_eq!(run(&store, &["LPOP", "l", "2"]), NULL_ARRAY);
+    }
+
+    #[test]
+    fn popping_exactly_the_length_deletes_the_key() {
+        let store = Mutex::new(Store::with_seed(1));
+        run(&store, &["RPUSH", "l", "a", "b"]);
+        assert_eq!(
+            run(&store, &["RPOP", "l", "2"]),
+            "*2\r\n$1\r\nb\r\n$1\r\na\r\n"
+        );
+        assert_eq!(run(&store, &["EXISTS", "l"]), ":0\r\n");
+        assert_eq!(run(&store, &["TYPE", "l"]), "+none\r\n");
+    }
+
+    #[test]
+    fn lmove_and_rpoplpush_transfer_between_lists() {
+        let store = Mutex::new(Store::with_seed(1));
+        run(&store, &["RPUSH", "src", "a", "b", "c"]);
+        assert_eq!(run(&store, &["RPOPLPUSH", "src", "dst"]), "$1\r\nc\r\n");
+        assert_eq!(
+            run(&store, &["LMOVE", "src", "dst", "LEFT", "RIGHT"]),
+            "$1\r\na\r\n"
+        );
+        assert_eq!(
+            run(&store, &["LRANGE", "src", "0", "-1"]),
+            "*1\r\n$1\r\nb\r\n"
+        );
+        assert_eq!(
+            run(&store, &["LRANGE", "dst", "0", "-1"]),
+            "*2\r\n$1\r\nc\r\n$1\r\na\r\n"
+        );
+    }
+
+    #[test]
+    fn rpoplpush_onto_the_same_list_rotates_it() {
+        let store = Mutex::new(Store::with_seed(1));
+        run(&store, &["RPUSH", "l", "a", "b", "c"]);
+        assert_eq!(run(&store, &["RPOPLPUSH", "l", "l"]), "$1\r\nc\r\n");
+        assert_eq!(
+            run(&store, &["LRANGE", "l", "0", "-1"]),
+            "*3\r\n$1\r\nc\r\n$1\r\na\r\n$1\r\nb\r\n"
+        );
+    }
+
+    #[test]
+    fn lmove_from_an_empty_source_does_nothing() {
+        let store = Mutex::new(Store::with_seed(1));
+        run(&store, &["RPUSH", "dst", "x"]);
+        assert_eq!(run(&store, &["RPOPLPUSH", "missing", "dst"]), NULL_BULK);
+        assert_eq!(
+            run(&store, &["LMOVE", "missing", "dst", "LEFT", "LEFT"]),
+            NULL_BULK
+        );
+        assert_eq!(run(&store, &["LLEN", "dst"]), ":1\r\n");
+        assert_eq!(run(&store, &["EXISTS", "missing"]), ":0\r\n");
+    }
+
+    #[test]
+    fn hmget_returns_nil_for_absent_fields() {
+        let store = Mutex::new(Store::with_seed(1));
+        assert_eq!(run(&store, &["HMSET", "h", "a", "1", "b", "2"]), "+OK\r\n");
+        assert_eq!(
+            run(&store, &["HMGET", "h", "a", "nope", "b"]),
+            "*3\r\n$1\r\n1\r\n$-1\r\n$1\r\n2\r\n"
+        );
+        assert_eq!(run(&store, &["HMGET", "missing", "a"]), "*1\r\n$-1\r\n");
+        assert_eq!(run(&store, &["HEXISTS", "h", "a"]), ":1\r\n");
+        assert_eq!(run(&store, &["HEXISTS", "h", "nope"]), ":0\r\n");
+    }
+
+    #[test]
+    fn hkeys_and_hvals_list_fields_in_the_same_order() {
+        let store = Mutex::new(Store::with_seed(1));
+        let mut command = vec!["HMSET", "h"];
+        let pairs: Vec<(String, String)> = (0..20)
+            .map(|i| (format!("f{}", i), format!("v{}", i)))
+            .collect();
+        for (field, value) in &pairs {
+            command.extend([field.as_str(), value.as_str()]);
+        }
+        run(&store, &command);
+
+        let fields = run(&store, &["HKEYS", "h"]);
+        let values = run(&store, &["HVALS", "h"]);
+        let fields: Vec<&str> = fields.split("\r\n").skip(2).step_by(2).collect();
+        let values: Vec<&str> = values.split("\r\n").skip(2).step_by(2).collect();
+        assert_eq!((fields.len(), values.len()), (20, 20));
+        for (field, value) in fields.iter().zip(&values) {
+            assert_eq!(value[1..], field[1..], "{} -> {}", field, value);
+        }
+    }
+
+    #[test]
+    fn zrange_orders_by_score_then_member() {
+        let store = Mutex::new(Store::with_seed(1));
+        assert_eq!(
+            run(
+                &store,
+                &["ZADD", "z", "3", "c", "1", "b", "1", "a", "2", "d"]
+            ),
+            ":4\r\n"
+        );
+        assert_eq!(
+            run(&store, &["ZRANGE", "z", "0", "-1"]),
+            "*4\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\nd\r\n$1\r\nc\r\n"
+        );
+        assert_eq!(run(&store, &["ZRANK", "z", "b"]), ":1\r\n");
+        assert_eq!(run(&store, &["ZSCORE", "z", "d"]), "$1\r\n2\r\n");
+
+        assert_eq!(run(&store, &["ZADD", "z", "0", "c"]), ":0\r\n");
+        assert_eq!(run(&store, &["ZRANK", "z", "c"]), ":0\r\n");
+        assert_eq!(run(&store, &["ZCARD", "z"]), ":4\r\n");
+    }
+
+    #[test]
+    fn zrange_withscores_interleaves_members_and_scores() {
+        let store = Mutex::new(Store::with_seed(1));
+        run(&store, &["ZADD", "z", "1.5", "a", "-2", "b"]);
+        assert_eq!(
+            run(&store, &["ZRANGE", "z", "0", "-1", "WITHSCORES"]),
+            "*4\r\n$1\r\nb\r\n$2\r\n-2\r\n$1\r\na\r\n$3\r\n1.5\r\n"
+        );
+    }
+
+    #[test]
+    fn zrangebyscore_honours_exclusive_and_infinite_bounds() {
+        let store = Mutex::new(Store::with_seed(1));
+        run(
+            &store,
+            &["ZADD", "z", "1", "a", "2", "b", "3", "c", "inf", "top"],
+        );
+        assert_eq!(
+            run(&store, &["ZRANGEBYSCORE", "z", "(1", "3"]),
+            "*2\r\n$1\r\nb\r\n$1\r\nc\r\n"
+        );
+        assert_eq!(
+            run(&store, &["ZRANGEBYSCORE", "z", "(1", "(3"]),
+            "*1\r\n$1\r\nb\r\n"
+        );
+        assert_eq!(run(&store, &["ZRANGEBYSCORE", "z", "1", "(1"]), "*0\r\n");
+        assert_eq!(
+            run(&store, &["ZRANGEBYSCORE", "z", "-inf", "+inf"]),
+            "*4\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\nc\r\n$3\r\ntop\r\n"
+        );
+        assert_eq!(
+            run(&store, &["ZRANGEBYSCORE", "z", "(3", "+inf"]),
+            "*1\r\n$3\r\ntop\r\n"
+        );
+        assert!(Command::parse(&[b"ZRANGEBYSCORE", b"z", b"(x", b"1"]).is_err());
+    }
+
+    #[test]
+    fn zrem_counts_only_members_that_were_there() {
+        let store = Mutex::new(Store::with_seed(1));
+        run(&store, &["ZADD", "z", "1", "a", "2", "b"]);
+        assert_eq!(run(&store, &["ZREM", "z", "nope"]), ":0\r\n");
+        assert_eq!(run(&store, &["ZREM", "z", "a", "nope"]), ":1\r\n");
+        assert_eq!(run(&store, &["ZREM", "missing", "a"]), ":0\r\n");
+        assert_eq!(run(&store, &["ZREM", "z", "b"]), ":1\r\n");
+        assert_eq!(run(&store, &["EXISTS", "z"]), ":0\r\n");
+    }
+
+    #[test]
+    fn object_encoding_of_strings_depends_on_length_and_content() {
+        let store = Mutex::new(Store::with_seed(1));
+        let long = "x".repeat(45);
+        run(&store, &["SET", "int", "12345"]);
+        run(&store, &["SET", "short", "hello"]);
+        run(&store, &["SET", "long", &long]);
+
+        assert_eq!(run(&store, &["OBJECT", "ENCODING", "int"]), "$3\r\nint\r\n");
+        assert_eq!(
+            run(&store, &["OBJECT", "ENCODING", "short"]),
+            "$6\r\nembstr\r\n"
+        );
+        assert_eq!(
+            run(&store, &["OBJECT", "ENCODING", "long"]),
+            "$3\r\nraw\r\n"
+        );
+        assert_eq!(run(&store, &["OBJECT", "ENCODING", "missing"]), NULL_BULK);
+    }
+
+    #[test]
+    fn hgetall_is_an_array_in_resp2_and_a_map_in_resp3() {
+        let store = Mutex::new(Store::with_seed(1));
+        run(&store, &["HSET", "h", "f", "v"]);
+        let command = Command::parse(&[b"HGETALL", b"h"]).unwrap();
+        let resp2 = command.execute(&mut store.try_lock().unwrap());
+        assert_eq!(resp2, b"*2\r\n$1\r\nf\r\n$1\r\nv\r\n");
+        assert_eq!(command.resp3_reply(resp2), b"%1\r\n$1\r\nf\r\n$1\r\nv\r\n");
+
+        let missing = Command::parse(&[b"HGETALL", b"none"]).unwrap();
+        let resp2 = missing.execute(&mut store.try_lock().unwrap());
+        assert_eq!(resp2, b"*0\r\n");
+        assert_eq!(missing.resp3_reply(resp2), b"%0\r\n");
+    }
+
+    #[test]
+    fn incr_treats_a_missing_key_as_zero() {
+        let store = Mutex::new(Store::with_seed(1));
+        assert_eq!(run(&store, &["INCR", "a"]), ":1\r\n");
+        assert_eq!(run(&store, &["INCRBY", "b", "7"]), ":7\r\n");
+        assert_eq!(run(&store, &["DECR", "c"]), ":-1\r\n");
+
+        run(&store, &["SET", "text", "abc"]);
+        assert_eq!(
+            run(&store, &["INCR", "text"]),
+            format!("-ERR {}\r\n", NOT_AN_INTEGER)
+        );
+        assert_eq!(run(&store, &["GET", "text"]), "$3\r\nabc\r\n");
+    }
+
+    #[test]
+    fn ttl_replies_for_keys_with_and_without_a_ttl_and_missing_keys() {
+        let store = Mutex::new(Store::with_seed(1));
+        run(&store, &["SET", "timed", "v", "EX", "50"]);
+        run(&store, &["SET", "plain", "v"]);
+        assert_eq!(run(&store, &["TTL", "timed"]), ":50\r\n");
+        assert_eq!(run(&store, &["TTL", "plain"]), ":-1\r\n");
+        assert_eq!(run(&store, &["TTL", "missing"]), ":-2\r\n");
+    }
+
+    #[test]
+    fn copy_duplicates_a_list_independently() {
+        let store = Mutex::new(Store::with_seed(1));
+        run(&store, &["RPUSH", "src", "a", "b"]);
+        assert_eq!(run(&store, &["COPY", "src", "dst"]), ":1\r\n");
+        run(&store, &["RPUSH", "dst", "c"]);
+        assert_eq!(run(&store, &["LLEN", "src"]), ":2\r\n");
+        assert_eq!(run(&store, &["LLEN", "dst"]), ":3\r\n");
+        assert_eq!(run(&store, &["COPY", "missing", "x"]), ":0\r\n");
+    }
+
+    #[test]
+    fn copy_overwrites_only_with_replace() {
+        let store = Mutex::new(Store::with_seed(1));
+        run(&store, &["RPUSH", "src", "a"]);
+        run(&store, &["SET", "dst", "keep"]);
+        assert_eq!(run(&store, &["COPY", "src", "dst"]), ":0\r\n");
+        assert_eq!(run(&store, &["GET", "dst"]), "$4\r\nkeep\r\n");
+
+        assert_eq!(run(&store, &["COPY", "src", "dst", "REPLACE"]), ":1\r\n");
+        assert_eq!(run(&store, &["TYPE", "dst"]), "+list\r\n");
+        assert_eq!(
+            run(&store, &["LRANGE", "dst", "0", "-1"]),
+            "*1\r\n$1\r\na\r\n"
+        );
+    }
+
+    #[test]
+    fn touch_counts_the_keys_that_exist() {
+        let store = Mutex::new(Store::with_seed(1));
+        run(&store, &["SET", "a", "1"]);
+        run(&store, &["SET", "b", "2"]);
+        assert_eq!(run(&store, &["TOUCH", "a", "missing", "b"]), ":2\r\n");
+        assert_eq!(run(&store, &["TOUCH", "missing"]), ":0\r\n");
+    }
+
+    #[test]
+    fn unlink_deletes_several_keys_at_once() {
+        let store = Mutex::new(Store::with_seed(1));
+        run(&store, &["MSET", "a", "1", "b", "2", "c", "3"]);
+        assert_eq!(run(&store, &["UNLINK", "a", "b", "missing"]), ":2\r\n");
+        assert_eq!(run(&store, &["EXISTS", "a", "b", "c"]), ":1\r\n");
+    }
+
+    #[test]
+    fn del_counts_only_the_keys_it_removed() {
+        let store = Mutex::new(Store::with_seed(1));
+        run(&store, &["MSET", "a", "1", "b", "2", "c", "3"]);
+        assert_eq!(run(&store, &["DEL", "a", "missing", "c", "a"]), ":2\r\n");
+        assert_eq!(run(&store, &["EXISTS", "b"]), ":1\r\n");
+        assert_eq!(run(&store, &["DEL", "missing", "other"]), ":0\r\n");
+    }
+
+    #[test]
+    fn exists_counts_repeated_keys_each_time() {
+        let store = Mutex::new(Store::with_seed(1));
+        run(&store, &["SET", "a", "1"]);
+        assert_eq!(run(&store, &["EXISTS", "a", "a", "missing", "a"]), ":3\r\n");
+    }
+
+    #[test]
+    fn reply_size_matches_the_reply_that_gets_built() {
+        let store = Mutex::new(Store::with_seed(1));
+        run(&store, &["SET", "s", "some text"]);
+        run(&store, &["RPUSH", "l", "a", "bb", "ccc", "dddd"]);
+        run(&store, &["HSET", "h", "field", "value"]);
+        run(&store, &["HSET", "h", "f2", "a longer value"]);
+        run(&store, &["SADD", "set", "x", "yy", "zzz"]);
+
+        let sized: &[&[&str]] = &[
+            &["GET", "s"],
+            &["MGET", "s", "missing", "l", "s"],
+            &["LRANGE", "l", "0", "-1"],
+            &["LRANGE", "l", "-3", "1"],
+            &["LRANGE", "l", "5", "9"],
+            &["HGETALL", "h"],
+            &["HKEYS", "h"],
+            &["HVALS", "h"],
+            &["SMEMBERS", "set"],
+        ];
+        for words in sized {
+            let parts: Vec<&[u8]> = words.iter().map(|word| word.as_bytes()).collect();
+            let command = Command::parse(&parts).unwrap();
+            let size = command.reply_size(&store.try_lock().unwrap());
+            assert_eq!(size, Some(run_bytes(&store, &parts).len()), "{:?}", words);
+        }
+
+        // Nil and error replies are never too large to send.
+        let not_sized: &[&[&str]] = &[
+            &["GET", "missing"],
+            &["GET", "l"],
+            &["HKEYS", "s"],
+            &["SET", "k", "v"],
+        ];
+        for words in not_sized {
+            let parts: Vec<&[u8]> = words.iter().map(|word| word.as_bytes()).collect();
+            let command = Command::parse(&parts).unwrap();
+            assert_eq!(command.reply_size(&store.try_lock().unwrap()), None);
         }
     }
 
-    pub fn is_quit(&self) -> bool {
-        matches!(self, Command::Quit)
+    #[test]
+    fn sscan_accepts_the_largest_cursor_and_count() {
+        let store = Mutex::new(Store::with_seed(1));
+        run(&store, &["SADD", "s", "a", "b"]);
+        assert_eq!(
+            run(
+                &store,
+                &["SSCAN", "s", "1", "COUNT", "18446744073709551615"]
+            ),
+            "*2\r\n$1\r\n0\r\n*1\r\n$1\r\nb\r\n"
+        );
+        assert_eq!(
+            run(
+                &store,
+                &["SSCAN", "s", "18446744073709551615", "COUNT", "5"]
+            ),
+            "*2\r\n$1\r\n0\r\n*0\r\n"
+        );
     }
 }
@@ -0,0 +1,80 @@
+/// Whether `text` matches the Redis-style glob `pattern`: `*` matches any run
+/// of characters, `?` any single character, `[abc]`, `[a-z]` and `[^abc]` a
+/// character class, and `\` escapes the character after it.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    matches_from(&pattern, &text)
+}
+
+fn matches_from(pattern: &[char], text: &[char]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Where to resume after the most recent `*` if the rest fails to match.
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        let step = match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p + 1, t));
+                p += 1;
+                continue;
+            }
+            Some('?') => Some(1),
+            Some('[') => match_class(&pattern[p..], text[t]),
+            Some('\\') if p + 1 < pattern.len() => (pattern[p + 1] == text[t]).then_some(2),
+            Some(&c) => (c == text[t]).then_some(1),
+            None => None,
+        };
+
+        match step {
+            Some(width) => {
+                p += width;
+                t += 1;
+            }
+            None => match backtrack {
+                Some((star_p, star_t)) => {
+                    p = star_p;
+                    t = star_t + 1;
+                    backtrack = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Matches `c` against the class at the start of `pattern`, returning the
+/// class's width in the pattern on success. An unterminated `[` is taken
+/// literally.
+fn match_class(pattern: &[char], c: char) -> Option<usize> {
+    let Some(close) = pattern.iter().skip(1).position(|&ch| ch == ']') else {
+        return (c == '[').then_some(1);
+    };
+    let close = close + 1;
+    let mut class = &pattern[1..close];
+    let negated = class.first() == Some(&'^');
+    if negated {
+        class = &class[1..];
+    }
+
+    let mut found = false;
+    let mut i = 0;
+    while i < class.len() {
+        if i + 2 < class.len() && class[i + 1] == '-' {
+            let (lo, hi) = if class[i] <= class[i + 2] {
+                (class[i], class[i + 2])
+            } else {
+                (class[i + 2], class[i])
+            };
+            found |= (lo..=hi).contains(&c);
+            i += 3;
+        } else {
+            found |= class[i] == c;
+            i += 1;
+        }
+    }
+
+    (found != negated).then_some(close + 1)
+}
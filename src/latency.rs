@@ -0,0 +1,91 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Samples kept per event, matching Redis' latency history length.
+const HISTORY_LEN: usize = 160;
+
+/// Executions at or below this are not considered spikes.
+const DEFAULT_THRESHOLD: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone, Copy)]
+pub struct LatencySample {
+    pub timestamp: u64,
+    pub millis: u64,
+}
+
+#[derive(Debug, Default)]
+struct EventHistory {
+    samples: VecDeque<LatencySample>,
+    max: u64,
+}
+
+pub struct LatencyMonitor {
+    threshold: Duration,
+    events: HashMap<String, EventHistory>,
+}
+
+impl LatencyMonitor {
+    pub fn new() -> Self {
+        LatencyMonitor {
+            threshold: DEFAULT_THRESHOLD,
+            events: HashMap::new(),
+        }
+    }
+
+    /// Records `elapsed` against `event` if it exceeds the threshold.
+    pub fn record(&mut self, event: &str, elapsed: Duration) {
+        if elapsed <= self.threshold {
+            return;
+        }
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let millis = elapsed.as_millis() as u64;
+
+        let history = self.events.entry(event.to_string()).or_default();
+        if history.samples.len() == HISTORY_LEN {
+            history.samples.pop_front();
+        }
+        history
+            .samples
+            .push_back(LatencySample { timestamp, millis });
+        history.max = history.max.max(millis);
+    }
+
+    pub fn history(&self, event: &str) -> Vec<LatencySample> {
+        match self.events.get(event) {
+            Some(history) => history.samples.iter().copied().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Latest sample and all-time max for every event, sorted by event name.
+    pub fn latest(&self) -> Vec<(String, LatencySample, u64)> {
+        let mut latest: Vec<_> = self
+            .events
+            .iter()
+            .filter_map(|(event, history)| {
+                let sample = *history.samples.back()?;
+                Some((event.clone(), sample, history.max))
+            })
+            .collect();
+        latest.sort_by(|a, b| a.0.cmp(&b.0));
+        latest
+    }
+
+    /// Clears the named events, or all of them when `events` is empty.
+    /// Returns the number of events that were reset.
+    pub fn reset(&mut self, events: &[String]) -> usize {
+        if events.is_empty() {
+            let count = self.events.len();
+            self.events.clear();
+            return count;
+        }
+        events
+            .iter()
+            .filter(|event| self.events.remove(*event).is_some())
+            .count()
+    }
+}
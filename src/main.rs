@@ -1,13 +1,137 @@
+use std::collections::HashSet;
+use std::time::Duration;
+
+mod aof;
+mod command;
+mod glob;
+mod latency;
+mod rdb;
+mod script;
 mod server;
 mod store;
-mod command;
 mod value;
 
 #[tokio::main]
 async fn main() {
     println!("Starting Redis server...");
-    let result = server::run("127.0.0.1:6379").await;
+    let result = match config() {
+        Ok(config) => server::run(config).await.map_err(|e| e.to_string()),
+        Err(e) => Err(e),
+    };
     if let Err(e) = result {
         eprintln!("Error: {}", e);
     }
-}
\ No newline at end of file
+}
+
+/// Gathers the server configuration from the command line and environment.
+fn config() -> Result<server::ServerConfig, String> {
+    let tls = match (
+        std::env::var("KEYVAL_TLS_CERT"),
+        std::env::var("KEYVAL_TLS_KEY"),
+    ) {
+        (Ok(cert), Ok(key)) => Some(server::TlsPaths { cert, key }),
+        _ => None,
+    };
+    let (bind, port) = address()?;
+    Ok(server::ServerConfig {
+        bind,
+        port,
+        tls,
+        rng_seed: rng_seed()?,
+        databases: databases()?,
+        access: server::Access {
+            disabled_commands: disabled_commands()?,
+            requirepass: flag_or_env("--requirepass", "KEYVAL_REQUIREPASS")?,
+        },
+        aof_path: flag_or_env("--aof", "KEYVAL_AOF")?,
+        snapshot_path: flag_or_env("--dbfilename", "KEYVAL_DBFILENAME")?
+            .unwrap_or_else(|| "dump.rdb".to_string()),
+        limits: limits()?,
+    })
+}
+
+/// Value of the command-line `flag` (given as `flag <value>`), falling back
+/// to the environment variable `env`.
+fn flag_or_env(flag: &str, env: &str) -> Result<Option<String>, String> {
+    let args: Vec<String> = std::env::args().collect();
+    match args.iter().position(|a| a == flag) {
+        Some(i) => match args.get(i + 1) {
+            Some(value) => Ok(Some(value.clone())),
+            None => Err(format!("{} requires a value", flag)),
+        },
+        None => Ok(std::env::var(env).ok()),
+    }
+}
+
+/// Reads the address to listen on from `--bind` (default 127.0.0.1) and
+/// `--port` (default 6379), falling back to `KEYVAL_BIND` and `KEYVAL_PORT`.
+fn address() -> Result<(String, u16), String> {
+    let bind = flag_or_env("--bind", "KEYVAL_BIND")?.unwrap_or_else(|| "127.0.0.1".to_string());
+    let port = match flag_or_env("--port", "KEYVAL_PORT")? {
+        Some(port) => port
+            .parse::<u16>()
+            .map_err(|_| format!("Invalid port: {}", port))?,
+        None => 6379,
+    };
+    Ok((bind, port))
+}
+
+/// Reads the number of databases from `--databases` (default 16), falling
+/// back to `KEYVAL_DATABASES`.
+fn databases() -> Result<usize, String> {
+    match flag_or_env("--databases", "KEYVAL_DATABASES")? {
+        Some(n) => n
+            .parse::<usize>()
+            .ok()
+            .filter(|&n| n > 0)
+            .ok_or_else(|| format!("Invalid databases: {}", n)),
+        None => Ok(store::DEFAULT_DATABASES),
+    }
+}
+
+/// Reads the RNG seed from `--rng-seed <n>`, falling back to `KEYVAL_RNG_SEED`.
+fn rng_seed() -> Result<Option<u64>, String> {
+    match flag_or_env("--rng-seed", "KEYVAL_RNG_SEED")? {
+        Some(seed) => seed
+            .parse::<u64>()
+            .map(Some)
+            .map_err(|_| format!("Invalid RNG seed: {}", seed)),
+        None => Ok(None),
+    }
+}
+
+/// Reads a comma-separated list of commands to refuse from
+/// `--disable-commands`, falling back to `KEYVAL_DISABLE_COMMANDS`.
+fn disabled_commands() -> Result<HashSet<String>, String> {
+    let list = flag_or_env("--disable-commands", "KEYVAL_DISABLE_COMMANDS")?;
+    Ok(list
+        .unwrap_or_default()
+        .split(',')
+        .map(|name| name.trim().to_uppercase())
+        .filter(|name| !name.is_empty())
+        .collect())
+}
+
+/// Reads the connection limits from `--maxclients` (default 10000) and
+/// `--timeout` in seconds (default 0, never), falling back to
+/// `KEYVAL_MAXCLIENTS` and `KEYVAL_TIMEOUT`.
+fn limits() -> Result<server::Limits, String> {
+    let max_clients = match flag_or_env("--maxclients", "KEYVAL_MAXCLIENTS")? {
+        Some(n) => n
+            .parse::<usize>()
+            .ok()
+            .filter(|&n| n > 0)
+            .ok_or_else(|| format!("Invalid maxclients: {}", n))?,
+        None => 10000,
+    };
+    let idle_timeout = match flag_or_env("--timeout", "KEYVAL_TIMEOUT")? {
+        Some(secs) => secs
+            .parse::<u64>()
+            .map_err(|_| format!("Invalid timeout: {}", secs))?,
+        None => 0,
+    };
+    Ok(server::Limits {
+        max_clients,
+        idle_timeout: (idle_timeout > 0).then(|| Duration::from_secs(idle_timeout)),
+    })
+}
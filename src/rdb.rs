@@ -0,0 +1,254 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{Error, ErrorKind, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::value::{StreamEntry, StreamId, Value, ZSet};
+
+/// Start of every snapshot file; the trailing digits are the format version.
+const MAGIC: &[u8] = b"KEYVAL01";
+
+const OP_SELECT_DB: u8 = 0xFE;
+const OP_EXPIRY_MS: u8 = 0xFC;
+const OP_EOF: u8 = 0xFF;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_HASH: u8 = 2;
+const TYPE_SET: u8 = 3;
+const TYPE_STREAM: u8 = 4;
+const TYPE_ZSET: u8 = 5;
+
+/// One database's keys as saved in a snapshot, each with its expiry deadline
+/// if it has one.
+pub type Database = Vec<(String, Value, Option<SystemTime>)>;
+
+/// Writes `databases`, given by index, to `path`. The snapshot is written to
+/// a temporary file first and renamed over `path`, so a crash mid-save never
+/// leaves a half-written snapshot behind.
+pub fn save(path: &str, databases: &[(usize, Database)]) -> std::io::Result<()> {
+    let tmp = format!("{}.tmp", path);
+    let mut file = std::fs::File::create(&tmp)?;
+    file.write_all(&encode(databases))?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path)
+}
+
+/// Reads the snapshot at `path`; `None` if there is no such file.
+pub fn load(path: &str) -> std::io::Result<Option<Vec<(usize, Database)>>> {
+    match std::fs::read(path) {
+        Ok(bytes) => decode(&bytes)
+            .map(Some)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", path, e))),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// The snapshot file contents for `databases`, also sent to replicas as
+/// their initial copy of the data.
+pub fn encode(databases: &[(usize, Database)]) -> Vec<u8> {
+    let mut out = MAGIC.to_vec();
+    for (index, keys) in databases {
+        out.push(OP_SELECT_DB);
+        put_len(&mut out, *index);
+        for (key, value, expiry) in keys {
+            if let Some(deadline) = expiry {
+                let ms = deadline
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_millis() as u64);
+                out.push(OP_EXPIRY_MS);
+                out.extend_from_slice(&ms.to_le_bytes());
+            }
+            put_value(&mut out, key, value);
+        }
+    }
+    out.push(OP_EOF);
+    out
+}
+
+fn put_value(out: &mut Vec<u8>, key: &str, value: &Value) {
+    let kind = match value {
+        Value::String(_) => TYPE_STRING,
+        Value::List(_) => TYPE_LIST,
+        Value::Hash(_) => TYPE_HASH,
+        Value::Set(_) => TYPE_SET,
+        Value::Stream(_) => TYPE_STREAM,
+        Value::ZSet(_) => TYPE_ZSET,
+    };
+    out.push(kind);
+    put_str(out, key);
+
+    match value {
+        Value::String(bytes) => put_bytes(out, bytes),
+        Value::List(items) => {
+            put_len(out, items.len());
+            items.iter().for_each(|item| put_str(out, item));
+        }
+        Value::Set(members) => {
+            put_len(out, members.len());
+            members.iter().for_each(|member| put_str(out, member));
+        }
+        Value::Hash(fields) => {
+            put_len(out, fields.len());
+            for (field, value) in fields {
+                put_str(out, field);
+                put_str(out, value);
+            }
+        }
+        Value::Stream(entries) => {
+            put_len(out, entries.len());
+            for (id, fields) in entries {
+                out.extend_from_slice(&id.ms.to_le_bytes());
+                out.extend_from_slice(&id.seq.to_le_bytes());
+                put_len(out, fields.len());
+                for (field, value) in fields {
+                    put_str(out, field);
+                    put_str(out, value);
+                }
+            }
+        }
+        Value::ZSet(members) => {
+            put_len(out, members.len());
+            for (member, score) in members.iter() {
+                put_str(out, member);
+                out.extend_from_slice(&score.to_bits().to_le_bytes());
+            }
+        }
+    }
+}
+
+fn put_len(out: &mut Vec<u8>, len: usize) {
+    out.extend_from_slice(&(len as u64).to_le_bytes());
+}
+
+fn put_str(out: &mut Vec<u8>, s: &str) {
+    put_bytes(out, s.as_bytes());
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    put_len(out, bytes.len());
+    out.extend_from_slice(bytes);
+}
+
+fn decode(bytes: &[u8]) -> Result<Vec<(usize, Database)>, String> {
+    let mut reader = Reader { bytes, pos: 0 };
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err("not a snapshot file".to_string());
+    }
+
+    let mut databases: Vec<(usize, Database)> = Vec::new();
+    let mut expiry = None;
+    loop {
+        match reader.u8()? {
+            OP_EOF => return Ok(databases),
+            OP_SELECT_DB => databases.push((reader.len()?, Vec::new())),
+            OP_EXPIRY_MS => {
+                expiry = Some(UNIX_EPOCH + Duration::from_millis(reader.u64()?));
+            }
+            kind => {
+                let Some((_, keys)) = databases.last_mut() else {
+                    return Err("key before any database".to_string());
+                };
+                let key = reader.string()?;
+                let value = reader.value(kind)?;
+                keys.push((key, value, expiry.take()));
+            }
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, n: usize) -> Result<&[u8], String> {
+        let end = self
+            .pos
+            .checked_add(n)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or("unexpected end of file")?;
+        let slice = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        let bytes = self.take(8)?;
+        Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn len(&mut self) -> Result<usize, String> {
+        usize::try_from(self.u64()?).map_err(|_| "length out of range".to_string())
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, String> {
+        let len = self.len()?;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        String::from_utf8(self.bytes()?).map_err(|_| "invalid UTF-8".to_string())
+    }
+
+    fn value(&mut self, kind: u8) -> Result<Value, String> {
+        Ok(match kind {
+            TYPE_STRING => Value::from(self.bytes()?),
+            TYPE_LIST => {
+                let len = self.len()?;
+                let items = (0..len)
+                    .map(|_| self.string())
+                    .collect::<Result<VecDeque<_>, _>>()?;
+                Value::from(items)
+            }
+            TYPE_SET => {
+                let len = self.len()?;
+                let members = (0..len)
+                    .map(|_| self.string())
+                    .collect::<Result<HashSet<_>, _>>()?;
+                Value::from(members)
+            }
+            TYPE_HASH => {
+                let len = self.len()?;
+                let fields = (0..len)
+                    .map(|_| Ok((self.string()?, self.string()?)))
+                    .collect::<Result<HashMap<_, _>, String>>()?;
+                Value::from(fields)
+            }
+            TYPE_STREAM => {
+                let len = self.len()?;
+                let entries = (0..len)
+                    .map(|_| self.stream_entry())
+                    .collect::<Result<Vec<_>, _>>()?;
+                Value::from(entries)
+            }
+            TYPE_ZSET => {
+                let len = self.len()?;
+                let mut members = ZSet::default();
+                for _ in 0..len {
+                    let member = self.string()?;
+                    members.insert(member, f64::from_bits(self.u64()?));
+                }
+                Value::from(members)
+            }
+            _ => return Err(format!("unknown value type {}", kind)),
+        })
+    }
+
+    fn stream_entry(&mut self) -> Result<StreamEntry, String> {
+        let id = StreamId {
+            ms: self.u64()?,
+            seq: self.u64()?,
+        };
+        let len = self.len()?;
+        let fields = (0..len)
+            .map(|_| Ok((self.string()?, self.string()?)))
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok((id, fields))
+    }
+}
@@ -0,0 +1,34 @@
+use std::sync::Arc;
+
+use crate::command::store_error;
+use crate::store::{Store, StoreError};
+
+/// A named server-side script, run by `EVAL`/`EVALSHA` under the store lock
+/// so that everything it does is atomic. It receives the `KEYS` and `ARGV`
+/// given to the call and returns the complete RESP reply.
+pub type Script = Arc<dyn Fn(&mut Store, &[String], &[String]) -> String + Send + Sync>;
+
+/// Registers the scripts every server starts with.
+pub fn register_builtins(store: &mut Store) {
+    store.register_script("rate_limit", rate_limit);
+}
+
+/// `EVALSHA rate_limit 1 key window`: counts a hit against `key`, opening a
+/// window of `window` seconds on the first hit, and replies with the number
+/// of hits so far in the current window.
+fn rate_limit(store: &mut Store, keys: &[String], args: &[String]) -> String {
+    let ([key], [window]) = (keys, args) else {
+        return "-ERR rate_limit takes 1 key and 1 argument\r\n".to_string();
+    };
+    let Ok(window) = window.parse::<u64>() else {
+        return store_error(StoreError::NotAnInteger);
+    };
+    if window == 0 {
+        return "-ERR invalid expire time in 'rate_limit' script\r\n".to_string();
+    }
+
+    match store.incr_by_with_ttl(key, 1, window) {
+        Ok(hits) => format!(":{}\r\n", hits),
+        Err(err) => store_error(err),
+    }
+}
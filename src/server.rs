@@ -1,60 +1,1715 @@
+use std::collections::HashSet;
+use std::panic::{self, AssertUnwindSafe};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::Mutex;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+    BufWriter,
+};
+use tokio::net::TcpListener;
+use tokio::signal;
+use tokio::sync::{
+    Mutex, OwnedSemaphorePermit, RwLock, RwLockReadGuard, RwLockWriteGuard, Semaphore, broadcast,
+    mpsc,
+};
+use tokio::time::{self, Duration, Instant};
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
 
-use crate::command::{Command, DB};
-use crate::store::Store;
+use crate::aof::{self, AofBuffer};
+use crate::command::{
+    Command, DB, INTERNAL_ERROR, NULL_ARRAY, PROTOCOL_ERROR, ParseError, REPLY_TOO_LARGE,
+    WRONGTYPE, array_header_len, bulk_len, cap_reply, is_protocol_garbage, is_write, string_array,
+};
+use crate::store::{Stats, Store, resolve_range};
 
-pub async fn run(addr: &str) -> std::io::Result<()> {
-    let listener = TcpListener::bind(addr).await?;
-    let db: DB = Arc::new(Mutex::new(Store::new()));
+/// Elements read per lock acquisition by a long LRANGE. The lock is released
+/// between chunks so other clients are not stalled behind one big read; the
+/// cost is that writes may interleave, so the reply is not a single snapshot.
+const LRANGE_CHUNK: usize = 1024;
 
+/// Expired keys removed per lock acquisition by the active expiry cycle.
+const ACTIVE_EXPIRE_BATCH: usize = 20;
+
+/// How often queued writes are flushed and synced to the append-only file,
+/// as with Redis' `appendfsync everysec`. A crash loses at most about this
+/// much.
+const AOF_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Source of the ids that tell connections apart, e.g. as pub/sub subscribers.
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Most elements a multi-bulk request may declare, as in Redis.
+const MAX_MULTIBULK_LEN: usize = 1024 * 1024;
+/// Largest bulk string a request may carry, as in Redis.
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+
+/// Sent to a connection turned away because `max_clients` are connected.
+const MAX_CLIENTS_ERROR: &str = "-ERR max number of clients reached\r\n";
+
+pub struct TlsPaths {
+    pub cert: String,
+    pub key: String,
+}
+
+/// Bounds on the connections the server keeps open.
+pub struct Limits {
+    /// Most connections served at once; any more are told so and closed.
+    pub max_clients: usize,
+    /// How long a connection may go without sending anything before it is
+    /// closed, if at all.
+    pub idle_timeout: Option<Duration>,
+}
+
+/// Who may run what.
+pub struct Access {
+    /// Commands refused as if unknown, by upper-case name.
+    pub disabled_commands: HashSet<String>,
+    /// Password connections must give with AUTH before anything else.
+    pub requirepass: Option<String>,
+}
+
+/// Everything `run` needs to start serving.
+pub struct ServerConfig {
+    /// Address to listen on.
+    pub bind: String,
+    /// Port to listen on; 0 picks a free one, which is printed at startup.
+    pub port: u16,
+    pub tls: Option<TlsPaths>,
+    /// Seed for randomized commands, for reproducible runs.
+    pub rng_seed: Option<u64>,
+    /// Number of logical databases `SELECT` can switch between.
+    pub databases: usize,
+    pub access: Access,
+    /// Append-only file to replay and log writes to, if any.
+    pub aof_path: Option<String>,
+    /// File snapshots are loaded from and saved to.
+    pub snapshot_path: String,
+    pub limits: Limits,
+}
+
+pub async fn run(config: ServerConfig) -> std::io::Result<()> {
+    let listener = TcpListener::bind((config.bind.as_str(), config.port)).await?;
+    println!("Listening on {}", listener.local_addr()?);
+    serve_listener(listener, config).await
+}
+
+/// Serves clients accepted on `listener` until a client or signal asks the
+/// server to stop. `bind` and `port` in `config` are not used, since the
+/// listener is already bound.
+async fn serve_listener(listener: TcpListener, config: ServerConfig) -> std::io::Result<()> {
+    let ServerConfig {
+        tls,
+        rng_seed,
+        databases,
+        access,
+        aof_path,
+        snapshot_path,
+        limits,
+        ..
+    } = config;
+    let mut store = match rng_seed {
+        Some(seed) => Store::with_seed(seed),
+        None => Store::new(),
+    };
+    store.set_databases(databases);
+    store.set_snapshot_path(&snapshot_path);
+    // The append-only file is the more complete record, so as in Redis the
+    // snapshot is only loaded without one.
+    if aof_path.is_none() && store.load_snapshot()? {
+        println!("Loaded snapshot from {}", snapshot_path);
+    }
+    let shutdown = store.shutdown_signal();
+    let stats = store.stats();
+    let db: DB = Arc::new(RwLock::new(store));
+
+    let aof = match aof_path.as_deref() {
+        Some(path) => {
+            let replayed = replay_aof(&db, path).await?;
+            println!("Replayed {} commands from {}", replayed, path);
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .await?;
+            db.write().await.enable_aof();
+            let file = Arc::new(Mutex::new(file));
+            tokio::spawn(maintain_aof(db.clone(), file.clone(), path.to_string()));
+            Some(file)
+        }
+        None => None,
+    };
+    tokio::spawn(expire_keys(db.clone()));
+
+    let access = Arc::new(access);
+    let acceptor = match tls {
+        Some(paths) => Some(load_tls_acceptor(&paths)?),
+        None => None,
+    };
+
+    let clients = Arc::new(Semaphore::new(limits.max_clients));
+    let terminated = termination_signal();
+    tokio::pin!(terminated);
     loop {
-        let (socket, _) = listener.accept().await?;
+        let (socket, _) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = shutdown.notified() => {
+                println!("Shutdown requested by client, exiting");
+                break;
+            }
+            signal = &mut terminated => {
+                println!("Received {}, exiting", signal?);
+                break;
+            }
+        };
+        stats.connections_received.fetch_add(1, Ordering::Relaxed);
+        let permit = clients.clone().try_acquire_owned().ok();
+        let idle_timeout = limits.idle_timeout;
         let db = db.clone();
+        let stats = stats.clone();
+        let acceptor = acceptor.clone();
+        let access = access.clone();
         tokio::spawn(async move {
-            if let Err(e) = handle_connection(socket, db).await {
+            stats.connected_clients.fetch_add(1, Ordering::Relaxed);
+            let result = match acceptor {
+                Some(acceptor) => match acceptor.accept(socket).await {
+                    Ok(stream) => {
+                        handle_connection(stream, permit, db, access, stats.clone(), idle_timeout)
+                            .await
+                    }
+                    Err(e) => Err(e),
+                },
+                None => {
+                    handle_connection(socket, permit, db, access, stats.clone(), idle_timeout).await
+                }
+            };
+            stats.connected_clients.fetch_sub(1, Ordering::Relaxed);
+            if let Err(e) = result {
                 eprintln!("Error handling connection: {}", e);
             }
         });
     }
+
+    // Commands run under the store lock, so once it is taken no command is
+    // left half-applied and everything they changed is in the flush.
+    match &aof {
+        Some(file) => flush_aof(&db, file).await,
+        None => {
+            drop(db.write().await);
+            Ok(())
+        }
+    }
 }
 
-async fn handle_connection(mut socket: TcpStream, db: DB) -> std::io::Result<()> {
-    let (reader, mut writer) = socket.split();
-    let mut buffer = BufReader::new(reader);
+/// Resolves when the process is asked to stop with Ctrl-C or, on Unix,
+/// SIGTERM, giving the signal's name.
+async fn termination_signal() -> std::io::Result<&'static str> {
+    #[cfg(unix)]
+    {
+        let mut sigterm = signal::unix::signal(signal::unix::SignalKind::terminate())?;
+        tokio::select! {
+            result = signal::ctrl_c() => result.map(|()| "SIGINT"),
+            _ = sigterm.recv() => Ok("SIGTERM"),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        signal::ctrl_c().await.map(|()| "SIGINT")
+    }
+}
+
+/// Runs an active expiry cycle over every database each `hz`th of a second.
+/// A cycle keeps sweeping a database while batches come back full, releasing
+/// the lock between them so a mass expiry does not stall clients.
+async fn expire_keys(db: DB) {
+    loop {
+        let (interval, databases) = {
+            let store = db.write().await;
+            (store.expire_interval(), store.databases())
+        };
+        time::sleep(interval).await;
+        for index in 0..databases {
+            while lock_db(&db, index, false)
+                .await
+                .expire_sweep(ACTIVE_EXPIRE_BATCH)
+                == ACTIVE_EXPIRE_BATCH
+            {
+                tokio::task::yield_now().await;
+            }
+        }
+    }
+}
+
+/// Locks the store with database `index` selected and lookups refreshing
+/// access times unless `no_touch`. Every lock taken on a connection's behalf
+/// goes through here or `read_db`, since another connection may have left
+/// different settings behind.
+async fn lock_db(db: &DB, index: usize, no_touch: bool) -> RwLockWriteGuard<'_, Store> {
+    let mut store = db.write().await;
+    store.select(index);
+    store.set_no_touch(no_touch);
+    store
+}
+
+/// Like `lock_db`, but shares the store with other readers. Only if another
+/// connection left different settings behind does it take the exclusive lock,
+/// just long enough to put them back.
+async fn read_db(db: &DB, index: usize, no_touch: bool) -> RwLockReadGuard<'_, Store> {
+    let store = db.read().await;
+    if store.selected_db() == index && store.no_touch() == no_touch {
+        return store;
+    }
+    drop(store);
+    lock_db(db, index, no_touch).await.downgrade()
+}
+
+/// Rebuilds the store by running every command in the append-only file at
+/// `path`, returning how many there were. A missing file is an empty one. A
+/// command cut off by a crash mid-write is dropped with a warning.
+async fn replay_aof(db: &DB, path: &str) -> std::io::Result<usize> {
+    let file = match File::open(path).await {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    let mut reader = BufReader::new(file);
     let mut line = String::new();
+    let mut store = db.write().await;
+    let mut replayed = 0;
 
     loop {
-        line.clear();
+        let words = match read_request(&mut reader, &mut line).await {
+            Ok(Some(words)) => words,
+            Ok(None) => break,
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                eprintln!("{}: ignoring truncated command at end of file", path);
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+        let words: Vec<&[u8]> = words.iter().map(Vec::as_slice).collect();
+        let command = Command::parse(&words).map_err(|e| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{}: {}", path, e))
+        })?;
+        execute_guarded(&command, &mut store);
+        replayed += 1;
+    }
+
+    store.select(0);
+    Ok(replayed)
+}
+
+/// Writes the queued changes to the append-only file every
+/// `AOF_FLUSH_INTERVAL`, and rewrites the file at `path` when BGREWRITEAOF
+/// asks for it.
+async fn maintain_aof(db: DB, file: Arc<Mutex<File>>, path: String) {
+    let rewrite = db.write().await.aof_rewrite_signal();
+    loop {
+        tokio::select! {
+            _ = time::sleep(AOF_FLUSH_INTERVAL) => {
+                if let Err(e) = flush_aof(&db, &file).await {
+                    eprintln!("Error writing append-only file: {}", e);
+                }
+            }
+            _ = rewrite.notified() => {
+                match rewrite_aof(&db, &file, &path).await {
+                    Ok(()) => println!("Background append only file rewriting finished"),
+                    Err(e) => eprintln!("Background append only file rewriting failed: {}", e),
+                }
+                db.write().await.finish_aof_rewrite();
+            }
+        }
+    }
+}
+
+/// Replaces the append-only file at `path` with one command per key, built
+/// from a snapshot taken under the store lock. Changes queued before the
+/// snapshot go to the old file first, so it stays complete if the rewrite
+/// fails; those queued after it wait, behind the file lock, for the new one.
+/// The new file is written beside the old and renamed over it.
+async fn rewrite_aof(db: &DB, file: &Mutex<File>, path: &str) -> std::io::Result<()> {
+    let mut file = file.lock().await;
+    let (pending, snapshot) = {
+        let mut store = db.write().await;
+        (store.take_aof(), store.aof_rewrite_snapshot())
+    };
+    file.write_all(&pending).await?;
+    file.sync_data().await?;
+
+    let contents = tokio::task::spawn_blocking(move || aof::rewrite(&snapshot))
+        .await
+        .map_err(std::io::Error::other)?;
+    let tmp = format!("{}.rewrite.tmp", path);
+    let mut rewritten = File::create(&tmp).await?;
+    rewritten.write_all(&contents).await?;
+    rewritten.sync_all().await?;
+    tokio::fs::rename(&tmp, path).await?;
+    // The handle follows the file through the rename, and sits at its end.
+    *file = rewritten;
+    Ok(())
+}
+
+/// Appends the queued changes to `file` and syncs it. The file lock is taken
+/// before the changes are, so concurrent flushes land in order.
+async fn flush_aof(db: &DB, file: &Mutex<File>) -> std::io::Result<()> {
+    let mut file = file.lock().await;
+    let pending = db.write().await.take_aof();
+    if pending.is_empty() {
+        return Ok(());
+    }
+    file.write_all(&pending).await?;
+    file.sync_data().await
+}
+
+fn load_tls_acceptor(paths: &TlsPaths) -> std::io::Result<TlsAcceptor> {
+    let invalid = |e: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, e);
+
+    let certs = CertificateDer::pem_file_iter(&paths.cert)
+        .map_err(|e| invalid(format!("{}: {}", paths.cert, e)))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| invalid(format!("{}: {}", paths.cert, e)))?;
+    let key = PrivateKeyDer::from_pem_file(&paths.key)
+        .map_err(|e| invalid(format!("{}: {}", paths.key, e)))?;
+
+    let config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| invalid(e.to_string()))?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// State a connection keeps between commands.
+struct Client {
+    id: u64,
+    selected_db: usize,
+    /// Set by CLIENT NO-TOUCH: the connection's lookups leave access times
+    /// alone.
+    no_touch: bool,
+    /// Channels the connection is subscribed to, in the order it joined them.
+    subscriptions: Vec<String>,
+    /// Queue of published messages waiting to be written to the connection.
+    outbox: mpsc::UnboundedSender<String>,
+    /// The MULTI block being queued, if any.
+    transaction: Option<Transaction>,
+    idle_timeout: Option<Duration>,
+    /// RESP version negotiated with HELLO: 2 until the client asks for 3.
+    protocol: i64,
+    /// Whether the connection may run commands: from the start if no
+    /// password is required, otherwise once AUTH succeeds.
+    authenticated: bool,
+}
+
+/// Commands queued between MULTI and EXEC, each with the words it was
+/// parsed from.
+#[derive(Default)]
+struct Transaction {
+    queue: Vec<(Command, Vec<Vec<u8>>)>,
+    /// Set once a command is rejected while queueing, which dooms the EXEC.
+    failed: bool,
+}
+
+/// Serves one connection, or turns it away if no `permit` was left for it.
+async fn handle_connection<S>(
+    mut socket: S,
+    permit: Option<OwnedSemaphorePermit>,
+    db: DB,
+    access: Arc<Access>,
+    stats: Arc<Stats>,
+    idle_timeout: Option<Duration>,
+) -> std::io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let Some(_permit) = permit else {
+        socket.write_all(MAX_CLIENTS_ERROR.as_bytes()).await?;
+        return socket.shutdown().await;
+    };
+
+    let (reader, writer) = tokio::io::split(socket);
+    let (requests_tx, requests) = mpsc::channel(1);
+    let reader = tokio::spawn(read_requests(reader, requests_tx));
+    let (outbox, messages) = mpsc::unbounded_channel();
+    let mut client = Client {
+        id: NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed),
+        selected_db: 0,
+        no_touch: false,
+        subscriptions: Vec::new(),
+        outbox,
+        transaction: None,
+        idle_timeout,
+        protocol: 2,
+        authenticated: access.requirepass.is_none(),
+    };
+
+    let result = serve(
+        &mut client,
+        requests,
+        messages,
+        BufWriter::new(writer),
+        &db,
+        &access,
+        &stats,
+    )
+    .await;
+
+    // Anything pipelined after the last command, such as after a QUIT, is
+    // dropped unread.
+    reader.abort();
+    if !client.subscriptions.is_empty() {
+        let mut store = db.write().await;
+        for channel in &client.subscriptions {
+            store.unsubscribe(channel, client.id);
+        }
+    }
+    result
+}
 
-        let bytes = buffer.read_line(&mut line).await?;
-        if bytes == 0 {
+/// Reads requests on their own task and passes them on, so the connection
+/// can wait on its next request and on published messages at the same time.
+/// Stops at end of input or after passing on a read error.
+async fn read_requests<R>(reader: R, requests: mpsc::Sender<std::io::Result<Vec<Vec<u8>>>>)
+where
+    R: AsyncRead + Unpin,
+{
+    let mut buffer = BufReader::new(reader);
+    let mut line = String::new();
+    while let Some(request) = read_request(&mut buffer, &mut line).await.transpose() {
+        let failed = request.is_err();
+        if requests.send(request).await.is_err() || failed {
             break;
         }
+    }
+}
+
+async fn serve<W>(
+    client: &mut Client,
+    mut requests: mpsc::Receiver<std::io::Result<Vec<Vec<u8>>>>,
+    mut messages: mpsc::UnboundedReceiver<String>,
+    mut writer: W,
+    db: &DB,
+    access: &Access,
+    stats: &Stats,
+) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    loop {
+        // Replies are buffered and only flushed once no further request is
+        // already waiting, so a pipelined batch goes out in one write.
+        if requests.is_empty() {
+            writer.flush().await?;
+        }
+
+        let idle = async {
+            match client.idle_timeout {
+                // As in Redis, subscribers are waiting on messages rather
+                // than idle, so they are never timed out.
+                Some(timeout) if client.subscriptions.is_empty() => time::sleep(timeout).await,
+                _ => std::future::pending().await,
+            }
+        };
+        let request = tokio::select! {
+            request = requests.recv() => request,
+            Some(message) = messages.recv() => {
+                writer.write_all(message.as_bytes()).await?;
+                continue;
+            }
+            _ = idle => break,
+        };
+        let words = match request {
+            Some(Ok(words)) => words,
+            None => break,
+            // Rather than answering every line of an HTTP request or binary
+            // blob with its own error, say so once and hang up.
+            Some(Err(e)) if e.kind() == std::io::ErrorKind::InvalidData => {
+                writer.write_all(PROTOCOL_ERROR.as_bytes()).await?;
+                writer.flush().await?;
+                writer.shutdown().await?;
+                return Ok(());
+            }
+            Some(Err(e)) => return Err(e),
+        };
+        let words: Vec<&[u8]> = words.iter().map(Vec::as_slice).collect();
+
+        // Disabled commands are refused before parsing so they look exactly
+        // like commands the server does not know.
+        if let Some(name) = words.first()
+            && access
+                .disabled_commands
+                .contains(&String::from_utf8_lossy(name).to_uppercase())
+        {
+            if let Some(transaction) = &mut client.transaction {
+                transaction.failed = true;
+            }
+            let err = ParseError::unknown_command(&words);
+            writer
+                .write_all(format!("-ERR {}\r\n", err).as_bytes())
+                .await?;
+            continue;
+        }
 
-        let command = match Command::parse(&line) {
+        let command = match Command::parse(&words) {
             Ok(cmd) => cmd,
             Err(err) => {
+                if let Some(transaction) = &mut client.transaction {
+                    transaction.failed = true;
+                }
                 writer
-                    .write_all(format!("ERR {}\n", err).as_bytes())
+                    .write_all(format!("-ERR {}\r\n", err).as_bytes())
                     .await?;
                 continue;
             }
         };
 
-        let mut store = db.lock().await;
-        let response = command.execute(&mut store);
+        stats.commands_processed.fetch_add(1, Ordering::Relaxed);
 
-        writer.write_all(response.as_bytes()).await?;
+        if !client.authenticated && !command.allowed_unauthenticated() {
+            writer
+                .write_all(b"-NOAUTH Authentication required.\r\n")
+                .await?;
+            continue;
+        }
 
-        if command.is_quit() {
-            break;
+        if let Some(transaction) = &mut client.transaction
+            && !command.controls_transaction()
+        {
+            let words = words.iter().map(|word| word.to_vec()).collect();
+            transaction.queue.push((command, words));
+            writer.write_all(b"+QUEUED\r\n").await?;
+            continue;
+        }
+
+        // Nothing buffered may wait on a command that can take a while.
+        if command.block_timeout().is_some()
+            || matches!(
+                command,
+                Command::DebugSleep(_) | Command::DebugLockedSleep(_)
+            )
+        {
+            writer.flush().await?;
+        }
+
+        let subscribed = !client.subscriptions.is_empty();
+        let response = match (&command, command.block_timeout()) {
+            (_, _) if subscribed && !command.allowed_while_subscribed() => format!(
+                "-ERR Can't execute '{}': only SUBSCRIBE / UNSUBSCRIBE / PING / QUIT are allowed in this context\r\n",
+                String::from_utf8_lossy(words[0]).to_lowercase()
+            )
+            .into_bytes(),
+            (Command::Ping, _) if subscribed => b"*2\r\n$4\r\npong\r\n$0\r\n\r\n".to_vec(),
+            (Command::Multi, _) if client.transaction.is_some() => {
+                b"-ERR MULTI calls can not be nested\r\n".to_vec()
+            }
+            (Command::Multi, _) => {
+                client.transaction = Some(Transaction::default());
+                b"+OK\r\n".to_vec()
+            }
+            (Command::Exec, _) => match client.transaction.take() {
+                Some(transaction) => exec(db, client, transaction).await,
+                None => b"-ERR EXEC without MULTI\r\n".to_vec(),
+            },
+            (Command::Discard, _) => match client.transaction.take() {
+                Some(_) => b"+OK\r\n".to_vec(),
+                None => b"-ERR DISCARD without MULTI\r\n".to_vec(),
+            },
+            (Command::Auth(password), _) => auth(client, access, password).into_bytes(),
+            (Command::Hello(version), _) => hello(client, *version).into_bytes(),
+            (Command::PSync, _) => return replicate(db, &mut requests, &mut writer, stats).await,
+            (Command::Subscribe(channels), _) => subscribe(db, client, channels).await.into_bytes(),
+            (Command::Unsubscribe(channels), _) => {
+                unsubscribe(db, client, channels).await.into_bytes()
+            }
+            (Command::LRange(key, start, end), _) => {
+                lrange_chunked(db, client.selected_db, client.no_touch, key, *start, *end).await
+            }
+            (Command::DebugSleep(duration), _) => {
+                time::sleep(*duration).await;
+                b"+OK\r\n".to_vec()
+            }
+            (Command::DebugLockedSleep(duration), _) => {
+                let _store = lock_db(db, client.selected_db, client.no_touch).await;
+                time::sleep(*duration).await;
+                b"+OK\r\n".to_vec()
+            }
+            (_, Some(timeout)) => {
+                execute_blocking(
+                    db,
+                    client.selected_db,
+                    client.no_touch,
+                    command.clone(),
+                    timeout,
+                )
+                .await
+            }
+            (_, None) if command.reads_only() => {
+                let store = read_db(db, client.selected_db, client.no_touch).await;
+                let max = store.proto_max_bulk_len();
+                if over_budget(&store, &command, max) {
+                    REPLY_TOO_LARGE.into()
+                } else {
+                    cap_reply(run_read(&store, &command), max)
+                }
+            }
+            (_, None) => {
+                let mut store = lock_db(db, client.selected_db, client.no_touch).await;
+                let max = store.proto_max_bulk_len();
+                if over_budget(&store, &command, max) {
+                    REPLY_TOO_LARGE.into()
+                } else {
+                    let response = run_command(&mut store, &command, &words);
+                    client.selected_db = store.selected_db();
+                    client.no_touch = store.no_touch();
+                    cap_reply(response, max)
+                }
+            }
+        };
+        let response = if client.protocol == 3 {
+            command.resp3_reply(response)
+        } else {
+            response
+        };
+
+        writer.write_all(&response).await?;
+
+        if command.closes_connection() {
+            writer.flush().await?;
+            writer.shutdown().await?;
+            return Ok(());
         }
     }
 
     writer.flush().await?;
     Ok(())
 }
+
+/// Runs `command`, parsed from `words`, against the locked store, recording
+/// its latency and logging it as a change if it was a successful write.
+fn run_command(store: &mut Store, command: &Command, words: &[&[u8]]) -> Vec<u8> {
+    let started = Instant::now();
+    let response = execute_guarded(command, store);
+    store.latency().record("command", started.elapsed());
+    // Failed writes changed nothing, so only successes are logged.
+    let name = String::from_utf8_lossy(words[0]).to_uppercase();
+    if is_write(&name) && !response.starts_with(b"-") {
+        store.emit_change(&name, &words[1..]);
+    }
+    response
+}
+
+/// `run_command` for a command that `reads_only`, run under a shared lock.
+/// Reads change nothing, so there is nothing to log.
+fn run_read(store: &Store, command: &Command) -> Vec<u8> {
+    let started = Instant::now();
+    let response =
+        catch_panic(|| command.execute_read(store)).unwrap_or_else(|| INTERNAL_ERROR.into());
+    store.latency().record("command", started.elapsed());
+    response
+}
+
+/// Whether `command`'s reply is known, before it runs, to take up more than
+/// `budget` bytes. Only commands that send stored values back whole can be
+/// sized ahead, and none of them write, so refusing one leaves nothing
+/// undone.
+fn over_budget(store: &Store, command: &Command, budget: usize) -> bool {
+    command.reply_size(store).is_some_and(|size| size > budget)
+}
+
+/// Turns the connection into a replication feed: sends a full snapshot, then
+/// every write applied after it, each preceded by a SELECT when it lands in
+/// another database. Ends when the replica disconnects or falls so far
+/// behind that it would have to resync. The snapshot is in this server's own
+/// format, so only another KeyVal server can load it.
+async fn replicate<W>(
+    db: &DB,
+    requests: &mut mpsc::Receiver<std::io::Result<Vec<Vec<u8>>>>,
+    writer: &mut W,
+    stats: &Stats,
+) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    // Subscribing under the same lock the snapshot is taken with means no
+    // write falls between the two.
+    let (header, snapshot, mut changes) = {
+        let store = db.write().await;
+        let snapshot = store.encode_snapshot();
+        let header = format!(
+            "+FULLRESYNC {} 0\r\n${}\r\n",
+            store.replid(),
+            snapshot.len()
+        );
+        (header, snapshot, store.subscribe_changes())
+    };
+    writer.write_all(header.as_bytes()).await?;
+    writer.write_all(&snapshot).await?;
+    writer.flush().await?;
+
+    stats.connected_replicas.fetch_add(1, Ordering::Relaxed);
+    let result = async {
+        // The feed is encoded exactly like the append-only file.
+        let mut feed = AofBuffer::default();
+        loop {
+            tokio::select! {
+                change = changes.recv() => match change {
+                    Ok(change) => {
+                        let args: Vec<&[u8]> = change.args.iter().map(Vec::as_slice).collect();
+                        feed.record(change.db, &change.command, &args);
+                        writer.write_all(&feed.take()).await?;
+                        if changes.is_empty() {
+                            writer.flush().await?;
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        eprintln!("Replica fell {} writes behind, disconnecting it", missed);
+                        return Ok(());
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                },
+                // Replicas may send REPLCONF ACK; nothing is tracked from it.
+                request = requests.recv() => match request {
+                    Some(Ok(_)) => {}
+                    Some(Err(e)) => return Err(e),
+                    None => return Ok(()),
+                },
+            }
+        }
+    }
+    .await;
+    stats.connected_replicas.fetch_sub(1, Ordering::Relaxed);
+    result
+}
+
+/// Checks `password` against the configured one and, if it matches, lets the
+/// connection run commands.
+fn auth(client: &mut Client, access: &Access, password: &str) -> String {
+    let Some(expected) = &access.requirepass else {
+        return "-ERR AUTH <password> called without any password configured for the default user. Are you sure your configuration is correct?\r\n".to_string();
+    };
+    if constant_time_eq(password.as_bytes(), expected.as_bytes()) {
+        client.authenticated = true;
+        "+OK\r\n".to_string()
+    } else {
+        "-WRONGPASS invalid username-password pair or user is disabled.\r\n".to_string()
+    }
+}
+
+/// Compares `a` and `b` in time that depends only on their lengths, so a
+/// wrong password's timing says nothing about how much of it was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Switches the connection to RESP `version` if one is given, and replies
+/// with the server's details: a map under RESP3, a flat array of pairs under
+/// RESP2.
+fn hello(client: &mut Client, version: Option<i64>) -> String {
+    match version {
+        Some(version @ (2 | 3)) => client.protocol = version,
+        Some(_) => return "-NOPROTO unsupported protocol version\r\n".to_string(),
+        None => {}
+    }
+
+    let fields = [
+        ("server", "$6\r\nkeyval\r\n".to_string()),
+        (
+            "version",
+            format!(
+                "${}\r\n{}\r\n",
+                env!("CARGO_PKG_VERSION").len(),
+                env!("CARGO_PKG_VERSION")
+            ),
+        ),
+        ("proto", format!(":{}\r\n", client.protocol)),
+        ("id", format!(":{}\r\n", client.id)),
+        ("mode", "$10\r\nstandalone\r\n".to_string()),
+        ("role", "$6\r\nmaster\r\n".to_string()),
+        ("modules", "*0\r\n".to_string()),
+    ];
+    let header = if client.protocol == 3 {
+        format!("%{}\r\n", fields.len())
+    } else {
+        format!("*{}\r\n", fields.len() * 2)
+    };
+    fields.iter().fold(header, |reply, (name, value)| {
+        reply + &format!("${}\r\n{}\r\n", name.len(), name) + value
+    })
+}
+
+/// Runs a transaction's commands back to back under one lock, so no other
+/// client's command can land in between, and replies with the array of
+/// their replies. A command failing at run time does not stop the rest, as
+/// in Redis. A transaction that had a command rejected while queueing is
+/// discarded instead.
+async fn exec(db: &DB, client: &mut Client, transaction: Transaction) -> Vec<u8> {
+    if transaction.failed {
+        return b"-EXECABORT Transaction discarded because of previous errors.\r\n".to_vec();
+    }
+
+    let mut store = lock_db(db, client.selected_db, client.no_touch).await;
+    let max = store.proto_max_bulk_len();
+    let mut reply = format!("*{}\r\n", transaction.queue.len()).into_bytes();
+    // Once the replies add up to more than `proto-max-bulk-len`, the rest of
+    // the commands still run, since a transaction is all or nothing, but
+    // their replies are dropped, and those whose size is known ahead are not
+    // built at all.
+    let mut too_large = false;
+    for (command, words) in &transaction.queue {
+        let budget = if too_large {
+            0
+        } else {
+            max.saturating_sub(reply.len())
+        };
+        if over_budget(&store, command, budget) {
+            too_large = true;
+            continue;
+        }
+        let words: Vec<&[u8]> = words.iter().map(Vec::as_slice).collect();
+        let response = match command {
+            // The transaction holds the lock throughout, so both sleeps keep it.
+            Command::DebugSleep(duration) | Command::DebugLockedSleep(duration) => {
+                time::sleep(*duration).await;
+                b"+OK\r\n".to_vec()
+            }
+            _ => run_command(&mut store, command, &words),
+        };
+        if too_large {
+            continue;
+        }
+        if client.protocol == 3 {
+            reply.extend(command.resp3_reply(response));
+        } else {
+            reply.extend(response);
+        }
+        too_large = reply.len() > max;
+    }
+    client.selected_db = store.selected_db();
+    client.no_touch = store.no_touch();
+    if too_large {
+        REPLY_TOO_LARGE.into()
+    } else {
+        reply
+    }
+}
+
+/// Subscribes the connection to `channels`, confirming each one along with
+/// the number of channels the connection is now subscribed to.
+async fn subscribe(db: &DB, client: &mut Client, channels: &[String]) -> String {
+    let mut store = db.write().await;
+    let mut reply = String::new();
+    for channel in channels {
+        if !client.subscriptions.contains(channel) {
+            store.subscribe(channel, client.id, client.outbox.clone());
+            client.subscriptions.push(channel.clone());
+        }
+        reply += &subscription_reply("subscribe", Some(channel), client.subscriptions.len());
+    }
+    reply
+}
+
+/// Unsubscribes the connection from `channels`, or from every channel when
+/// none are named, confirming each one as `subscribe` does.
+async fn unsubscribe(db: &DB, client: &mut Client, channels: &[String]) -> String {
+    let channels = if channels.is_empty() {
+        client.subscriptions.clone()
+    } else {
+        channels.to_vec()
+    };
+    if channels.is_empty() {
+        return subscription_reply("unsubscribe", None, 0);
+    }
+
+    let mut store = db.write().await;
+    let mut reply = String::new();
+    for channel in &channels {
+        store.unsubscribe(channel, client.id);
+        client.subscriptions.retain(|c| c != channel);
+        reply += &subscription_reply("unsubscribe", Some(channel), client.subscriptions.len());
+    }
+    reply
+}
+
+fn subscription_reply(kind: &str, channel: Option<&str>, count: usize) -> String {
+    let channel = match channel {
+        Some(channel) => format!("${}\r\n{}\r\n", channel.len(), channel),
+        None => "$-1\r\n".to_string(),
+    };
+    format!(
+        "*3\r\n${}\r\n{}\r\n{}:{}\r\n",
+        kind.len(),
+        kind,
+        channel,
+        count
+    )
+}
+
+/// Reads one request, either a RESP multi-bulk array or an inline command,
+/// and returns its words; `None` at end of input. Malformed framing and input
+/// that is clearly not meant for this server are reported as `InvalidData`.
+async fn read_request<R>(reader: &mut R, line: &mut String) -> std::io::Result<Option<Vec<Vec<u8>>>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    line.clear();
+    if reader.read_line(line).await? == 0 {
+        return Ok(None);
+    }
+
+    if let Some(count) = line.strip_prefix('*') {
+        let count = parse_length(count, MAX_MULTIBULK_LEN)?;
+        let mut words = Vec::with_capacity(count);
+        for _ in 0..count {
+            words.push(read_bulk(reader).await?);
+        }
+        return Ok(Some(words));
+    }
+
+    if is_protocol_garbage(line) {
+        return Err(protocol_error());
+    }
+    Ok(Some(
+        line.split_whitespace()
+            .map(|word| word.as_bytes().to_vec())
+            .collect(),
+    ))
+}
+
+/// Reads one `$<len>` bulk string of a multi-bulk request. Its contents are
+/// taken verbatim, spaces and all; whether they must be text is up to the
+/// command.
+async fn read_bulk<R>(reader: &mut R) -> std::io::Result<Vec<u8>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut header = String::new();
+    reader.read_line(&mut header).await?;
+    let len = match header.strip_prefix('$') {
+        Some(len) => parse_length(len, MAX_BULK_LEN)?,
+        None => return Err(protocol_error()),
+    };
+
+    let mut data = vec![0; len + 2];
+    reader.read_exact(&mut data).await?;
+    if !data.ends_with(b"\r\n") {
+        return Err(protocol_error());
+    }
+    data.truncate(len);
+    Ok(data)
+}
+
+/// Parses the length in a `*` or `$` header line, rejecting anything above
+/// `max`.
+fn parse_length(header: &str, max: usize) -> std::io::Result<usize> {
+    header
+        .trim_end_matches(['\r', '\n'])
+        .parse::<usize>()
+        .ok()
+        .filter(|&len| len <= max)
+        .ok_or_else(protocol_error)
+}
+
+fn protocol_error() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, "protocol error")
+}
+
+/// Runs `command`, turning a panic in its handler into an error reply so one
+/// buggy command cannot take the connection, or other clients, down with it.
+fn execute_guarded(command: &Command, store: &mut Store) -> Vec<u8> {
+    catch_panic(|| command.execute(store)).unwrap_or_else(|| INTERNAL_ERROR.into())
+}
+
+/// Runs `f`, returning `None` if it panics.
+fn catch_panic<T>(f: impl FnOnce() -> T) -> Option<T> {
+    panic::catch_unwind(AssertUnwindSafe(f)).ok()
+}
+
+/// Re-runs a blocking command every time the store signals new data, until it
+/// has something other than a null array to return or `timeout` runs out. A
+/// zero timeout blocks indefinitely.
+async fn execute_blocking(
+    db: &DB,
+    index: usize,
+    no_touch: bool,
+    mut command: Command,
+    timeout: Duration,
+) -> Vec<u8> {
+    let deadline = (!timeout.is_zero()).then(|| Instant::now() + timeout);
+
+    loop {
+        let mut store = lock_db(db, index, no_touch).await;
+        command.resolve_stream_ids(&mut store);
+        let response = execute_guarded(&command, &mut store);
+        if response != NULL_ARRAY.as_bytes() {
+            return cap_reply(response, store.proto_max_bulk_len());
+        }
+
+        // Register for the wakeup before releasing the lock so an XADD that
+        // lands in between is not missed.
+        let notify = store.stream_notify();
+        let notified = notify.notified();
+        drop(store);
+
+        match deadline {
+            Some(deadline) => {
+                if time::timeout_at(deadline, notified).await.is_err() {
+                    return NULL_ARRAY.into();
+                }
+            }
+            None => notified.await,
+        }
+    }
+}
+
+/// LRANGE that takes the store lock once per `LRANGE_CHUNK` elements and
+/// yields in between, instead of holding it for the whole range. Gives up as
+/// soon as the reply would exceed `proto-max-bulk-len`. As for any other
+/// command, a panic in the store becomes an error reply, and the time spent
+/// holding the lock is recorded as the command's latency.
+async fn lrange_chunked(
+    db: &DB,
+    index: usize,
+    no_touch: bool,
+    key: &str,
+    start: i64,
+    end: i64,
+) -> Vec<u8> {
+    let store = lock_db(db, index, no_touch).await;
+    let started = Instant::now();
+    let max = store.proto_max_bulk_len();
+    let len = catch_panic(|| store.llen(key));
+    let mut busy = started.elapsed();
+    // Negative indices are resolved against the length when the read starts;
+    // the chunks then address absolute positions.
+    let (mut from, end) = match len {
+        Some(Ok(len)) => match resolve_range(start, end, len) {
+            Some(range) => range,
+            None => return record_latency(store, busy, string_array(&[]).into_bytes()),
+        },
+        Some(Err(_)) => return record_latency(store, busy, WRONGTYPE.into()),
+        None => return record_latency(store, busy, INTERNAL_ERROR.into()),
+    };
+    drop(store);
+
+    let mut elements = Vec::new();
+    // Bytes the elements take up in the reply, framing included.
+    let mut size = 0;
+    loop {
+        tokio::task::yield_now().await;
+        let to = end.min(from + LRANGE_CHUNK - 1);
+        let mut store = lock_db(db, index, no_touch).await;
+        let started = Instant::now();
+        let chunk = catch_panic(|| store.lrange(key, from as i64, to as i64));
+        busy += started.elapsed();
+        let chunk = match chunk {
+            Some(Ok(chunk)) => chunk,
+            Some(Err(_)) if elements.is_empty() => {
+                return record_latency(store, busy, WRONGTYPE.into());
+            }
+            // The key changed type between chunks, so the list ends here.
+            Some(Err(_)) => Vec::new(),
+            None => return record_latency(store, busy, INTERNAL_ERROR.into()),
+        };
+
+        let done = to == end || chunk.len() < to - from + 1;
+        size += chunk.iter().map(|v| bulk_len(v.len())).sum::<usize>();
+        elements.extend(chunk);
+        // Exact once done; until then the reply can only grow.
+        if array_header_len(elements.len()) + size > max {
+            return record_latency(store, busy, REPLY_TOO_LARGE.into());
+        }
+        if done {
+            store.latency().record("command", busy);
+            drop(store);
+            return string_array(&elements).into_bytes();
+        }
+        from = to + 1;
+    }
+}
+
+/// Records `busy`, the time a command spent holding the lock, as its latency
+/// and passes its `reply` through.
+fn record_latency(store: RwLockWriteGuard<'_, Store>, busy: Duration, reply: Vec<u8>) -> Vec<u8> {
+    store.latency().record("command", busy);
+    reply
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpStream;
+
+    /// A path in the temp directory unique to this process and `name`.
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("keyval-{}-{}", std::process::id(), name))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    /// A config for a server with no persistence, no password and no limits
+    /// to speak of.
+    fn test_config() -> ServerConfig {
+        ServerConfig {
+            bind: "127.0.0.1".to_string(),
+            port: 0,
+            tls: None,
+            rng_seed: Some(1),
+            databases: 16,
+            access: Access {
+                disabled_commands: HashSet::new(),
+                requirepass: None,
+            },
+            aof_path: None,
+            snapshot_path: temp_path("missing.rdb"),
+            limits: Limits {
+                max_clients: 100,
+                idle_timeout: None,
+            },
+        }
+    }
+
+    /// Starts a server with `config` on a free local port, returning its
+    /// address and the task running it.
+    async fn start(
+        config: ServerConfig,
+    ) -> (
+        std::net::SocketAddr,
+        tokio::task::JoinHandle<std::io::Result<()>>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        (addr, tokio::spawn(serve_listener(listener, config)))
+    }
+
+    async fn send(stream: &mut TcpStream, words: &[&str]) {
+        let mut request = format!("*{}\r\n", words.len());
+        for word in words {
+            request += &format!("${}\r\n{}\r\n", word.len(), word);
+        }
+        stream.write_all(request.as_bytes()).await.unwrap();
+    }
+
+    /// Reads exactly as many bytes as `reply` has and checks they match.
+    async fn expect(stream: &mut TcpStream, reply: &str) {
+        let mut buffer = vec![0; reply.len()];
+        time::timeout(Duration::from_secs(5), stream.read_exact(&mut buffer))
+            .await
+            .expect("timed out waiting for a reply")
+            .unwrap();
+        assert_eq!(String::from_utf8_lossy(&buffer), reply);
+    }
+
+    /// Reads one bulk string reply and returns its contents.
+    async fn read_bulk_reply(stream: &mut TcpStream) -> String {
+        let mut header = Vec::new();
+        while !header.ends_with(b"\r\n") {
+            header.push(stream.read_u8().await.unwrap());
+        }
+        let header = String::from_utf8(header).unwrap();
+        let len: usize = header.trim_end()[1..].parse().unwrap();
+        let mut body = vec![0; len + 2];
+        stream.read_exact(&mut body).await.unwrap();
+        body.truncate(len);
+        String::from_utf8(body).unwrap()
+    }
+
+    /// Runs each command against `store` as a connection would.
+    fn run_all(store: &mut RwLockWriteGuard<'_, Store>, commands: &[&[&str]]) {
+        for words in commands {
+            let words: Vec<&[u8]> = words.iter().map(|word| word.as_bytes()).collect();
+            let command = Command::parse(&words).unwrap();
+            run_command(store, &command, &words);
+        }
+    }
+
+    /// Every key in every database with a description of its value and
+    /// whether it has a TTL, in a stable order.
+    fn contents(store: &mut Store) -> Vec<(usize, String, String, bool)> {
+        let selected = store.selected_db();
+        let mut contents = Vec::new();
+        for index in 0..store.databases() {
+            store.select(index);
+            let mut keys = store.keys("*");
+            keys.sort();
+            for key in keys {
+                let (value, ttl) = store.debug_object(&key).unwrap();
+                contents.push((index, key, value.describe(usize::MAX), ttl.is_some()));
+            }
+        }
+        store.select(selected);
+        contents
+    }
+
+    #[tokio::test]
+    async fn replaying_the_aof_rebuilds_the_store() {
+        let db: DB = Arc::new(RwLock::new(Store::with_seed(1)));
+        let mut store = db.write().await;
+        store.enable_aof();
+        run_all(
+            &mut store,
+            &[
+                &["SET", "s", "v"],
+                &["RPUSH", "l", "a", "b", "c"],
+                &["LPOP", "l"],
+                &["HSET", "h", "f", "v"],
+                &["SADD", "set", "x", "y"],
+                &["ZADD", "z", "1.5", "m"],
+                &["INCR", "n"],
+                &["INCR", "n"],
+                &["SET", "gone", "v"],
+                &["DEL", "gone"],
+                &["EXPIRE", "s", "100"],
+                &["SELECT", "2"],
+                &["SET", "other", "db"],
+            ],
+        );
+        let path = temp_path("replay.aof");
+        std::fs::write(&path, store.take_aof()).unwrap();
+
+        let copy: DB = Arc::new(RwLock::new(Store::with_seed(1)));
+        let replayed = replay_aof(&copy, &path).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(replayed, 15);
+        let expected = contents(&mut store);
+        assert_eq!(expected.len(), 7);
+        assert_eq!(contents(&mut *copy.write().await), expected);
+    }
+
+    #[tokio::test]
+    async fn published_messages_reach_every_subscriber_over_tcp() {
+        let (addr, _server) = start(test_config()).await;
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        let mut publisher = TcpStream::connect(addr).await.unwrap();
+
+        for subscriber in [&mut first, &mut second] {
+            send(subscriber, &["SUBSCRIBE", "news"]).await;
+            expect(subscriber, "*3\r\n$9\r\nsubscribe\r\n$4\r\nnews\r\n:1\r\n").await;
+        }
+        send(&mut publisher, &["PUBLISH", "news", "hello"]).await;
+        expect(&mut publisher, ":2\r\n").await;
+        send(&mut publisher, &["PUBLISH", "other", "ignored"]).await;
+        expect(&mut publisher, ":0\r\n").await;
+
+        for subscriber in [&mut first, &mut second] {
+            expect(
+                subscriber,
+                "*3\r\n$7\r\nmessage\r\n$4\r\nnews\r\n$5\r\nhello\r\n",
+            )
+            .await;
+        }
+
+        send(&mut second, &["UNSUBSCRIBE", "news"]).await;
+        expect(
+            &mut second,
+            "*3\r\n$11\r\nunsubscribe\r\n$4\r\nnews\r\n:0\r\n",
+        )
+        .await;
+        send(&mut publisher, &["PUBLISH", "news", "again"]).await;
+        expect(&mut publisher, ":1\r\n").await;
+        expect(
+            &mut first,
+            "*3\r\n$7\r\nmessage\r\n$4\r\nnews\r\n$5\r\nagain\r\n",
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn exec_runs_the_queued_commands_in_order() {
+        let (addr, _server) = start(test_config()).await;
+        let mut client = TcpStream::connect(addr).await.unwrap();
+
+        send(&mut client, &["MULTI"]).await;
+        expect(&mut client, "+OK\r\n").await;
+        send(&mut client, &["SET", "n", "10"]).await;
+        expect(&mut client, "+QUEUED\r\n").await;
+        send(&mut client, &["INCR", "n"]).await;
+        expect(&mut client, "+QUEUED\r\n").await;
+        send(&mut client, &["EXEC"]).await;
+        expect(&mut client, "*2\r\n+OK\r\n:11\r\n").await;
+        send(&mut client, &["GET", "n"]).await;
+        expect(&mut client, "$2\r\n11\r\n").await;
+    }
+
+    #[tokio::test]
+    async fn discard_drops_the_queued_writes() {
+        let (addr, _server) = start(test_config()).await;
+        let mut client = TcpStream::connect(addr).await.unwrap();
+
+        send(&mut client, &["MULTI"]).await;
+        expect(&mut client, "+OK\r\n").await;
+        send(&mut client, &["SET", "k", "v"]).await;
+        expect(&mut client, "+QUEUED\r\n").await;
+        send(&mut client, &["DISCARD"]).await;
+        expect(&mut client, "+OK\r\n").await;
+        send(&mut client, &["EXISTS", "k"]).await;
+        expect(&mut client, ":0\r\n").await;
+        send(&mut client, &["EXEC"]).await;
+        expect(&mut client, "-ERR EXEC without MULTI\r\n").await;
+    }
+
+    #[tokio::test]
+    async fn info_counts_processed_commands() {
+        let (addr, _server) = start(test_config()).await;
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        async fn processed(client: &mut TcpStream) -> u64 {
+            send(client, &["INFO", "stats"]).await;
+            let report = read_bulk_reply(client).await;
+            report
+                .lines()
+                .find_map(|line| line.strip_prefix("total_commands_processed:"))
+                .unwrap()
+                .parse()
+                .unwrap()
+        }
+
+        let before = processed(&mut client).await;
+        send(&mut client, &["SET", "k", "v"]).await;
+        expect(&mut client, "+OK\r\n").await;
+        send(&mut client, &["GET", "k"]).await;
+        expect(&mut client, "$1\r\nv\r\n").await;
+        send(&mut client, &["PING"]).await;
+        expect(&mut client, "+PONG\r\n").await;
+        // The second INFO counts itself too.
+        assert_eq!(processed(&mut client).await, before + 4);
+    }
+
+    #[tokio::test]
+    async fn shutdown_makes_the_server_return_ok() {
+        let (addr, server) = start(test_config()).await;
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        send(&mut client, &["SET", "k", "v"]).await;
+        expect(&mut client, "+OK\r\n").await;
+
+        send(&mut client, &["SHUTDOWN"]).await;
+        expect(&mut client, "+OK\r\n").await;
+        let result = time::timeout(Duration::from_secs(5), server)
+            .await
+            .expect("server did not stop")
+            .unwrap();
+        assert!(result.is_ok(), "{:?}", result);
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn connections_past_maxclients_are_turned_away() {
+        let mut config = test_config();
+        config.limits.max_clients = 1;
+        let (addr, _server) = start(config).await;
+
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        send(&mut first, &["PING"]).await;
+        expect(&mut first, "+PONG\r\n").await;
+
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        expect(&mut second, "-ERR max number of clients reached\r\n").await;
+        let mut rest = Vec::new();
+        second.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+
+        drop(first);
+        // The slot is freed once the server notices the first one is gone.
+        let mut third = loop {
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            send(&mut client, &["PING"]).await;
+            let mut reply = [0; 7];
+            client.read_exact(&mut reply).await.unwrap();
+            if &reply == b"+PONG\r\n" {
+                break client;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        };
+        send(&mut third, &["PING"]).await;
+        expect(&mut third, "+PONG\r\n").await;
+    }
+
+    #[tokio::test]
+    async fn idle_connections_are_closed_after_the_timeout() {
+        let mut config = test_config();
+        config.limits.idle_timeout = Some(Duration::from_millis(200));
+        let (addr, _server) = start(config).await;
+
+        let mut busy = TcpStream::connect(addr).await.unwrap();
+        let mut silent = TcpStream::connect(addr).await.unwrap();
+        for _ in 0..4 {
+            time::sleep(Duration::from_millis(100)).await;
+            send(&mut busy, &["PING"]).await;
+            expect(&mut busy, "+PONG\r\n").await;
+        }
+
+        let mut rest = Vec::new();
+        let read = time::timeout(Duration::from_secs(5), silent.read_to_end(&mut rest)).await;
+        assert_eq!(read.expect("silent connection was not closed").unwrap(), 0);
+        send(&mut busy, &["PING"]).await;
+        expect(&mut busy, "+PONG\r\n").await;
+    }
+
+    #[tokio::test]
+    async fn auth_gates_every_command_until_the_password_matches() {
+        let mut config = test_config();
+        config.access.requirepass = Some("secret".to_string());
+        let (addr, _server) = start(config).await;
+        let mut client = TcpStream::connect(addr).await.unwrap();
+
+        send(&mut client, &["SET", "k", "v"]).await;
+        expect(&mut client, "-NOAUTH Authentication required.\r\n").await;
+        send(&mut client, &["AUTH", "wrong"]).await;
+        expect(
+            &mut client,
+            "-WRONGPASS invalid username-password pair or user is disabled.\r\n",
+        )
+        .await;
+        send(&mut client, &["GET", "k"]).await;
+        expect(&mut client, "-NOAUTH Authentication required.\r\n").await;
+
+        send(&mut client, &["AUTH", "secret"]).await;
+        expect(&mut client, "+OK\r\n").await;
+        send(&mut client, &["SET", "k", "v"]).await;
+        expect(&mut client, "+OK\r\n").await;
+        send(&mut client, &["GET", "k"]).await;
+        expect(&mut client, "$1\r\nv\r\n").await;
+    }
+
+    #[tokio::test]
+    async fn pipelined_commands_get_their_replies_in_order() {
+        let (addr, _server) = start(test_config()).await;
+        let mut client = TcpStream::connect(addr).await.unwrap();
+
+        client
+            .write_all(
+                b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n\
+                  *2\r\n$4\r\nINCR\r\n$1\r\nn\r\n\
+                  *2\r\n$3\r\nGET\r\n$1\r\nk\r\n",
+            )
+            .await
+            .unwrap();
+        expect(&mut client, "+OK\r\n:1\r\n$1\r\nv\r\n").await;
+    }
+
+    #[tokio::test]
+    async fn serves_on_the_port_the_os_assigns() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        assert_ne!(addr.port(), 0);
+        tokio::spawn(serve_listener(listener, test_config()));
+
+        let mut client = TcpStream::connect(("127.0.0.1", addr.port()))
+            .await
+            .unwrap();
+        send(&mut client, &["PING"]).await;
+        expect(&mut client, "+PONG\r\n").await;
+    }
+
+    #[tokio::test]
+    async fn malformed_requests_get_exact_error_replies() {
+        let (addr, _server) = start(test_config()).await;
+        let mut client = TcpStream::connect(addr).await.unwrap();
+
+        send(&mut client, &["QWERTYUIOP", "a", "b"]).await;
+        expect(
+            &mut client,
+            "-ERR unknown command 'QWERTYUIOP', with args beginning with: 'a' 'b' \r\n",
+        )
+        .await;
+        send(&mut client, &["GET"]).await;
+        expect(
+            &mut client,
+            "-ERR wrong number of arguments for 'get' command\r\n",
+        )
+        .await;
+        send(&mut client, &["INCRBY", "n", "ten"]).await;
+        expect(
+            &mut client,
+            "-ERR value is not an integer or out of range\r\n",
+        )
+        .await;
+        // The errors above leave the connection usable.
+        send(&mut client, &["PING"]).await;
+        expect(&mut client, "+PONG\r\n").await;
+
+        // A request that is not RESP at all is refused once and hung up on.
+        client.write_all(b"*x\r\n").await.unwrap();
+        expect(&mut client, PROTOCOL_ERROR).await;
+        assert_eq!(client.read_u8().await.ok(), None);
+    }
+
+    #[tokio::test]
+    async fn replicas_get_the_keyspace_then_later_writes() {
+        let writes: &[&[&str]] = &[
+            &["SET", "a", "1"],
+            &["RPUSH", "l", "x", "y"],
+            &["SELECT", "3"],
+            &["SET", "b", "2"],
+        ];
+        let (addr, _server) = start(test_config()).await;
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        for words in writes {
+            send(&mut client, words).await;
+        }
+        expect(&mut client, "+OK\r\n:2\r\n+OK\r\n+OK\r\n").await;
+
+        let mut replica = TcpStream::connect(addr).await.unwrap();
+        send(&mut replica, &["REPLCONF", "listening-port", "6380"]).await;
+        expect(&mut replica, "+OK\r\n").await;
+        send(&mut replica, &["PSYNC", "?", "-1"]).await;
+        let mut line = Vec::new();
+        while !line.ends_with(b"\r\n") {
+            line.push(replica.read_u8().await.unwrap());
+        }
+        let line = String::from_utf8(line).unwrap();
+        assert!(line.starts_with("+FULLRESYNC ") && line.ends_with(" 0\r\n"));
+
+        // The snapshot is a bulk string without the trailing CRLF.
+        let mut header = Vec::new();
+        while !header.ends_with(b"\r\n") {
+            header.push(replica.read_u8().await.unwrap());
+        }
+        let len: usize = String::from_utf8(header).unwrap().trim_end()[1..]
+            .parse()
+            .unwrap();
+        let mut snapshot = vec![0; len];
+        replica.read_exact(&mut snapshot).await.unwrap();
+        let path = temp_path("replica.rdb");
+        std::fs::write(&path, &snapshot).unwrap();
+        let mut copy = Store::with_seed(1);
+        copy.set_snapshot_path(&path);
+        assert!(copy.load_snapshot().unwrap());
+        std::fs::remove_file(&path).unwrap();
+
+        let expected: DB = Arc::new(RwLock::new(Store::with_seed(1)));
+        let mut expected = expected.write().await;
+        run_all(&mut expected, writes);
+        assert_eq!(contents(&mut copy), contents(&mut expected));
+
+        send(&mut client, &["SET", "c", "3"]).await;
+        expect(&mut client, "+OK\r\n").await;
+        expect(
+            &mut replica,
+            "*2\r\n$6\r\nSELECT\r\n$1\r\n3\r\n*3\r\n$3\r\nSET\r\n$1\r\nc\r\n$1\r\n3\r\n",
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn chunked_lrange_is_capped_at_its_exact_reply_size() {
+        let (addr, _server) = start(test_config()).await;
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let mut push = vec!["RPUSH", "l"];
+        push.extend(std::iter::repeat_n("x", 3000));
+        send(&mut client, &push).await;
+        expect(&mut client, ":3000\r\n").await;
+
+        // Three chunks of `$1\r\nx\r\n` behind a `*3000\r\n` header.
+        let reply = "*3000\r\n".to_string() + &"$1\r\nx\r\n".repeat(3000);
+        assert_eq!(reply.len(), 21007);
+        send(
+            &mut client,
+            &["CONFIG", "SET", "proto-max-bulk-len", "21007"],
+        )
+        .await;
+        expect(&mut client, "+OK\r\n").await;
+        send(&mut client, &["LRANGE", "l", "0", "-1"]).await;
+        expect(&mut client, &reply).await;
+
+        send(
+            &mut client,
+            &["CONFIG", "SET", "proto-max-bulk-len", "21006"],
+        )
+        .await;
+        expect(&mut client, "+OK\r\n").await;
+        send(&mut client, &["LRANGE", "l", "0", "-1"]).await;
+        expect(&mut client, REPLY_TOO_LARGE).await;
+        send(&mut client, &["LRANGE", "l", "-2", "-1"]).await;
+        expect(&mut client, "*2\r\n$1\r\nx\r\n$1\r\nx\r\n").await;
+    }
+
+    #[tokio::test]
+    async fn oversized_replies_are_refused_before_they_are_built() {
+        let (addr, _server) = start(test_config()).await;
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        send(&mut client, &["SET", "a", "0123456789"]).await;
+        expect(&mut client, "+OK\r\n").await;
+        send(&mut client, &["HSET", "h", "field", "0123456789"]).await;
+        expect(&mut client, ":1\r\n").await;
+
+        // GET a is `$10\r\n0123456789\r\n`, 17 bytes.
+        send(&mut client, &["CONFIG", "SET", "proto-max-bulk-len", "16"]).await;
+        expect(&mut client, "+OK\r\n").await;
+        send(&mut client, &["GET", "a"]).await;
+        expect(&mut client, REPLY_TOO_LARGE).await;
+        send(&mut client, &["HGETALL", "h"]).await;
+        expect(&mut client, REPLY_TOO_LARGE).await;
+
+        // Each GET fits, but the transaction's reply, 43 bytes, does not. The
+        // write in between is still applied.
+        send(&mut client, &["CONFIG", "SET", "proto-max-bulk-len", "42"]).await;
+        expect(&mut client, "+OK\r\n").await;
+        for words in [
+            &["MULTI"][..],
+            &["GET", "a"],
+            &["SET", "b", "x"],
+            &["GET", "a"],
+        ] {
+            send(&mut client, words).await;
+        }
+        expect(&mut client, "+OK\r\n+QUEUED\r\n+QUEUED\r\n+QUEUED\r\n").await;
+        send(&mut client, &["EXEC"]).await;
+        expect(&mut client, REPLY_TOO_LARGE).await;
+        send(&mut client, &["GET", "b"]).await;
+        expect(&mut client, "$1\r\nx\r\n").await;
+
+        send(&mut client, &["CONFIG", "SET", "proto-max-bulk-len", "43"]).await;
+        expect(&mut client, "+OK\r\n").await;
+        for words in [
+            &["MULTI"][..],
+            &["GET", "a"],
+            &["SET", "b", "x"],
+            &["GET", "a"],
+        ] {
+            send(&mut client, words).await;
+        }
+        expect(&mut client, "+OK\r\n+QUEUED\r\n+QUEUED\r\n+QUEUED\r\n").await;
+        send(&mut client, &["EXEC"]).await;
+        expect(
+            &mut client,
+            "*3\r\n$10\r\n0123456789\r\n+OK\r\n$10\r\n0123456789\r\n",
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn reads_share_the_store_while_writes_wait_for_them() {
+        let db: DB = Arc::new(RwLock::new(Store::with_seed(1)));
+        {
+            let mut store = db.write().await;
+            run_all(&mut store, &[&["SET", "k", "v"], &["SELECT", "1"]]);
+        }
+        let get = Command::parse(&[b"GET", b"k"]).unwrap();
+
+        // The store was left on database 1, so the first reader puts database
+        // 0 back before sharing it.
+        let first = read_db(&db, 0, false).await;
+        let second = time::timeout(Duration::from_secs(1), read_db(&db, 0, false))
+            .await
+            .expect("a second reader does not wait on the first");
+        assert_eq!(run_read(&first, &get), b"$1\r\nv\r\n");
+        assert_eq!(run_read(&second, &get), b"$1\r\nv\r\n");
+
+        assert!(
+            time::timeout(Duration::from_millis(50), lock_db(&db, 0, false))
+                .await
+                .is_err()
+        );
+        drop((first, second));
+        assert!(
+            time::timeout(Duration::from_secs(1), lock_db(&db, 0, false))
+                .await
+                .is_ok()
+        );
+    }
+}
@@ -1,365 +1,3017 @@
-use std::collections::{HashMap, VecDeque};
-use std::time::{Duration, Instant};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use crate::value::Value;
+use rand::rngs::StdRng;
+use rand::seq::IteratorRandom;
+use rand::{Rng, SeedableRng};
+use tokio::sync::{Notify, broadcast, mpsc};
 
+use crate::aof::AofBuffer;
+use crate::command::key_args;
+use crate::glob::glob_match;
+use crate::latency::LatencyMonitor;
+use crate::rdb::{self, Database};
+use crate::script::{self, Script};
+use crate::value::{ScoreBound, StreamEntry, StreamId, Value, ZSet};
+
+/// Changes a subscriber may fall behind by before it starts missing them.
+const CHANGE_BACKLOG: usize = 1024;
+
+/// Longest string Redis stores inline with its object header, reported by
+/// OBJECT ENCODING as `embstr` rather than `raw`.
+const EMBSTR_MAX_LEN: usize = 44;
+
+/// Number of logical databases `SELECT` can switch between unless configured
+/// otherwise, as in Redis.
+pub const DEFAULT_DATABASES: usize = 16;
+
+/// Latest deadline a key may be given, in milliseconds since the Unix epoch.
+/// Redis keeps expiry times as signed 64-bit milliseconds, so neither can go
+/// further.
+pub const MAX_DEADLINE_MS: u64 = i64::MAX as u64;
+
+/// A write applied to the store, as the command words that reproduce it.
+#[derive(Debug, Clone)]
+pub struct Change {
+    /// Database the write was applied to.
+    pub db: usize,
+    pub command: String,
+    pub args: Vec<Vec<u8>>,
+}
+
+/// Server-wide counters reported by INFO. They are atomics so connections
+/// can update them without taking the store lock.
+pub struct Stats {
+    pub started: Instant,
+    pub connected_clients: AtomicU64,
+    /// Connections currently being fed writes after a PSYNC.
+    pub connected_replicas: AtomicU64,
+    pub connections_received: AtomicU64,
+    pub commands_processed: AtomicU64,
+}
+
+/// Why a typed operation on a key failed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StoreError {
+    /// The key holds a value of another type.
+    WrongType,
+    /// The stored value is not an integer, or the result would overflow.
+    NotAnInteger,
+    /// The string would grow past `proto-max-bulk-len`.
+    TooLarge,
+    /// An XADD id is not past the last entry of the stream.
+    StreamIdTooSmall,
+}
+
+/// When a SET is allowed to take effect.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SetCondition {
+    /// `NX`: only if the key does not exist.
+    IfMissing,
+    /// `XX`: only if the key already exists.
+    IfExists,
+}
+
+/// The `EX`/`PX` and `NX`/`XX` options of a SET.
+#[derive(Debug, Clone, Default)]
+pub struct SetOptions {
+    pub ttl: Option<Duration>,
+    pub condition: Option<SetCondition>,
+}
+
+/// The end of a list LMOVE pops from or pushes onto.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ListEnd {
+    Left,
+    Right,
+}
+
+/// How `SINTER`, `SUNION` and `SDIFF` combine their sets.
+#[derive(Debug, Clone, Copy)]
+pub enum SetOp {
+    Inter,
+    Union,
+    /// Members of the first set that are in none of the others.
+    Diff,
+}
+
+/// The keys of a database that is not currently selected.
+#[derive(Default)]
+struct Keyspace {
+    data: Dict,
+    expiry: Deadlines,
+    promoted: HashSet<String>,
+    accessed: Mutex<HashMap<String, Instant>>,
+}
+
+/// The values of one database. Its keys are also kept ordered by
+/// `scan_hash`, so `SCAN` can resume from a cursor without visiting the keys
+/// before it.
+#[derive(Default)]
+struct Dict {
+    values: HashMap<String, Value>,
+    scan_order: BTreeSet<(u64, String)>,
+}
+
+impl Dict {
+    fn get(&self, key: &str) -> Option<&Value> {
+        self.values.get(key)
+    }
+
+    fn get_mut(&mut self, key: &str) -> Option<&mut Value> {
+        self.values.get_mut(key)
+    }
+
+    fn contains_key(&self, key: &str) -> bool {
+        self.values.contains_key(key)
+    }
+
+    fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    fn keys(&self) -> impl Iterator<Item = &String> {
+        self.values.keys()
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (&String, &Value)> {
+        self.values.iter()
+    }
+
+    fn insert(&mut self, key: String, value: Value) -> Option<Value> {
+        if !self.values.contains_key(&key) {
+            self.scan_order.insert((scan_hash(&key), key.clone()));
+        }
+        self.values.insert(key, value)
+    }
+
+    /// The value at `key`, inserting `empty()` first if there is none.
+    fn get_or_insert_with(&mut self, key: &str, empty: impl FnOnce() -> Value) -> &mut Value {
+        if !self.values.contains_key(key) {
+            self.insert(key.to_string(), empty());
+        }
+        self.values.get_mut(key).unwrap()
+    }
+
+    fn remove(&mut self, key: &str) -> Option<Value> {
+        let value = self.values.remove(key)?;
+        self.scan_order.remove(&(scan_hash(key), key.to_string()));
+        Some(value)
+    }
+
+    fn clear(&mut self) {
+        self.values.clear();
+        self.scan_order.clear();
+    }
+
+    /// Every key whose `scan_hash` is at least `cursor`, with its hash, in
+    /// `SCAN` order.
+    fn scan_from(&self, cursor: u64) -> impl Iterator<Item = (u64, &String)> {
+        self.scan_order
+            .range((cursor, String::new())..)
+            .map(|(hash, key)| (*hash, key))
+    }
+}
+
+/// The deadlines of one database's keys. They are also kept ordered by time,
+/// so the expiry sweep can find the lapsed ones without visiting every key
+/// that has a TTL.
+#[derive(Default)]
+struct Deadlines {
+    by_key: HashMap<String, SystemTime>,
+    by_time: BTreeSet<(SystemTime, String)>,
+}
+
+impl Deadlines {
+    fn get(&self, key: &str) -> Option<&SystemTime> {
+        self.by_key.get(key)
+    }
+
+    fn len(&self) -> usize {
+        self.by_key.len()
+    }
+
+    fn keys(&self) -> impl Iterator<Item = &String> {
+        self.by_key.keys()
+    }
+
+    fn values(&self) -> impl Iterator<Item = &SystemTime> {
+        self.by_key.values()
+    }
+
+    fn insert(&mut self, key: String, deadline: SystemTime) -> Option<SystemTime> {
+        let previous = self.by_key.insert(key.clone(), deadline);
+        if let Some(previous) = previous {
+            self.by_time.remove(&(previous, key.clone()));
+        }
+        self.by_time.insert((deadline, key));
+        previous
+    }
+
+    fn remove(&mut self, key: &str) -> Option<SystemTime> {
+        let deadline = self.by_key.remove(key)?;
+        self.by_time.remove(&(deadline, key.to_string()));
+        Some(deadline)
+    }
+
+    fn clear(&mut self) {
+        self.by_key.clear();
+        self.by_time.clear();
+    }
+
+    /// Keys whose deadline is before `now`, earliest first.
+    fn lapsed(&self, now: SystemTime) -> impl Iterator<Item = &String> {
+        self.by_time
+            .iter()
+            .take_while(move |(deadline, _)| now > *deadline)
+            .map(|(_, key)| key)
+    }
+}
+
+/// The keyspace of the selected database lives directly in `data`, `expiry`,
+/// `promoted` and `accessed`; the others wait in `parked` until selected, so every
+/// operation works on the selected database without knowing there are
+/// others.
 pub struct Store {
-    data: HashMap<String, Value>,
-    expiry: HashMap<String, Instant>,
+    data: Dict,
+    expiry: Deadlines,
+    db: usize,
+    parked: Vec<Keyspace>,
+    active_expire: bool,
+    latency: Mutex<LatencyMonitor>,
+    rng: StdRng,
+    stream_notify: Arc<Notify>,
+    changes: broadcast::Sender<Change>,
+    shutdown: Arc<Notify>,
+    scripts: HashMap<String, Script>,
+    list_max_listpack_size: usize,
+    hash_max_listpack_entries: usize,
+    hz: usize,
+    set_max_intset_entries: usize,
+    set_max_listpack_entries: usize,
+    zset_max_listpack_entries: usize,
+    promoted: HashSet<String>,
+    /// When each key was last read or written, for OBJECT IDLETIME. Reads
+    /// running under a shared lock refresh it too, hence the `Mutex`.
+    accessed: Mutex<HashMap<String, Instant>>,
+    /// Whether lookups leave access times alone, for a connection that ran
+    /// CLIENT NO-TOUCH ON. Connections share the store, so like the selected
+    /// database each one sets its own whenever it takes the lock.
+    no_touch: bool,
+    proto_max_bulk_len: usize,
+    aof: Option<AofBuffer>,
+    /// Each pub/sub channel's subscribers, by client id.
+    channels: HashMap<String, HashMap<u64, mpsc::UnboundedSender<String>>>,
+    snapshot_path: String,
+    bgsave_in_progress: Arc<AtomicBool>,
+    /// Signalled by BGREWRITEAOF for the task that owns the append-only file.
+    aof_rewrite: Arc<Notify>,
+    aof_rewrite_in_progress: bool,
+    /// Random 40-character ID replicas are told they are syncing from.
+    replid: String,
+    stats: Arc<Stats>,
 }
 
 impl Store {
     pub fn new() -> Self {
-        Store {
-            data: HashMap::new(),
-            expiry: HashMap::new(),
-        }
+        Store::with_rng(StdRng::from_os_rng())
     }
 
-    pub fn set(&mut self, key: &str, value: &Value) {
-        self.data.insert(key.to_string(), value.clone());
-        self.expiry.remove(key);
+    /// Creates a store whose randomized commands are reproducible for a given
+    /// seed.
+    pub fn with_seed(seed: u64) -> Self {
+        Store::with_rng(StdRng::seed_from_u64(seed))
     }
 
-    pub fn get(&mut self, key: &str) -> Option<Value> {
-        if let Some(expiry_time) = self.expiry.get(key) {
-            if Instant::now() > *expiry_time {
-                self.data.remove(key);
-                self.expiry.remove(key);
-                return None;
-            }
+    fn with_rng(mut rng: StdRng) -> Self {
+        let replid = (0..40)
+            .map(|_| char::from_digit(rng.random_range(0..16), 16).unwrap())
+            .collect();
+        let mut store = Store {
+            data: Dict::default(),
+            expiry: Deadlines::default(),
+            db: 0,
+            parked: (0..DEFAULT_DATABASES)
+                .map(|_| Keyspace::default())
+                .collect(),
+            active_expire: true,
+            latency: Mutex::new(LatencyMonitor::new()),
+            rng,
+            stream_notify: Arc::new(Notify::new()),
+            changes: broadcast::channel(CHANGE_BACKLOG).0,
+            shutdown: Arc::new(Notify::new()),
+            scripts: HashMap::new(),
+            list_max_listpack_size: 128,
+            hash_max_listpack_entries: 128,
+            hz: 10,
+            set_max_intset_entries: 512,
+            set_max_listpack_entries: 128,
+            zset_max_listpack_entries: 128,
+            promoted: HashSet::new(),
+            accessed: Mutex::default(),
+            no_touch: false,
+            proto_max_bulk_len: 512 * 1024 * 1024,
+            aof: None,
+            channels: HashMap::new(),
+            snapshot_path: "dump.rdb".to_string(),
+            bgsave_in_progress: Arc::new(AtomicBool::new(false)),
+            aof_rewrite: Arc::new(Notify::new()),
+            aof_rewrite_in_progress: false,
+            replid,
+            stats: Arc::new(Stats {
+                started: Instant::now(),
+                connected_clients: AtomicU64::new(0),
+                connected_replicas: AtomicU64::new(0),
+                connections_received: AtomicU64::new(0),
+                commands_processed: AtomicU64::new(0),
+            }),
+        };
+        script::register_builtins(&mut store);
+        store
+    }
+
+    /// Switches to database `index`, returning false if there is no such
+    /// database. Connections share the store, so each one re-selects its own
+    /// database whenever it takes the lock.
+    pub fn select(&mut self, index: usize) -> bool {
+        if index >= self.databases() {
+            return false;
+        }
+        if index != self.db {
+            self.parked[self.db] = Keyspace {
+                data: std::mem::take(&mut self.data),
+                expiry: std::mem::take(&mut self.expiry),
+                promoted: std::mem::take(&mut self.promoted),
+                accessed: std::mem::take(&mut self.accessed),
+            };
+            let next = std::mem::take(&mut self.parked[index]);
+            self.data = next.data;
+            self.expiry = next.expiry;
+            self.promoted = next.promoted;
+            self.accessed = next.accessed;
+            self.db = index;
         }
-        self.data.get(key).cloned()
+        true
     }
 
-    pub fn del(&mut self, key: &str) -> bool {
-        self.expiry.remove(key);
-        self.data.remove(key).is_some()
+    pub fn selected_db(&self) -> usize {
+        self.db
     }
 
-    pub fn expire(&mut self, key: &str, duration: u64) -> bool {
-        if self.data.contains_key(key) {
-            self.expiry.insert(
-                key.to_string(),
-                Instant::now() + Duration::from_secs(duration),
-            );
-            true
-        } else {
-            false
-        }
+    pub fn set_no_touch(&mut self, no_touch: bool) {
+        self.no_touch = no_touch;
     }
 
-    pub fn ttl(&mut self, key: &str) -> Option<i64> {
-        if let Some(expiry_time) = self.expiry.get(key) {
-            if Instant::now() < *expiry_time {
-                return Some(expiry_time.duration_since(Instant::now()).as_secs() as i64);
-            }
+    pub fn no_touch(&self) -> bool {
+        self.no_touch
+    }
+
+    /// Deletes every key in the selected database.
+    pub fn flush_db(&mut self) {
+        self.data.clear();
+        self.expiry.clear();
+        self.promoted.clear();
+        self.accessed_mut().clear();
+    }
 
-            self.data.remove(key);
-            self.expiry.remove(key);
-            return Some(-1);
+    /// Deletes every key in every database.
+    pub fn flush_all(&mut self) {
+        self.flush_db();
+        for keyspace in &mut self.parked {
+            *keyspace = Keyspace::default();
         }
+    }
+
+    /// Number of logical databases.
+    pub fn databases(&self) -> usize {
+        self.parked.len()
+    }
 
-        if self.data.contains_key(key) {
-            Some(-1)
+    /// Sets the number of logical databases, which must be at least 1. Meant
+    /// for startup: shrinking drops the databases past the new count.
+    pub fn set_databases(&mut self, count: usize) {
+        self.parked.resize_with(count, Keyspace::default);
+    }
+
+    /// Sets the file SAVE and BGSAVE write the snapshot to.
+    pub fn set_snapshot_path(&mut self, path: &str) {
+        self.snapshot_path = path.to_string();
+    }
+
+    /// The keys and deadlines of database `index`, whether or not it is
+    /// selected.
+    fn keyspace(&self, index: usize) -> (&Dict, &Deadlines) {
+        if index == self.db {
+            (&self.data, &self.expiry)
         } else {
-            Some(-2)
+            (&self.parked[index].data, &self.parked[index].expiry)
         }
     }
 
-    pub fn incr_by(&mut self, key: &str, by: i64) -> Option<i64> {
-        let current = self.get(key)?;
-        if !current.is_string() {
-            return None;
-        }
-        let current = current.as_string().unwrap();
+    /// Every database encoded as a snapshot file, for a replica's initial
+    /// sync.
+    pub fn encode_snapshot(&self) -> Vec<u8> {
+        rdb::encode(&self.snapshot())
+    }
 
-        match current.parse::<i64>() {
-            Ok(n) => {
-                let new_value = n + by;
-                self.set(key, &Value::from(new_value.to_string()));
-                Some(new_value)
-            }
-            Err(_) => None,
-        }
+    /// A copy of every non-empty database, by index, for saving.
+    fn snapshot(&self) -> Vec<(usize, Database)> {
+        (0..self.databases())
+            .map(|index| {
+                let (data, expiry) = self.keyspace(index);
+                let keys: Database = data
+                    .iter()
+                    .map(|(key, value)| (key.clone(), value.clone(), expiry.get(key).copied()))
+                    .collect();
+                (index, keys)
+            })
+            .filter(|(_, keys)| !keys.is_empty())
+            .collect()
     }
 
-    pub fn lpush(&mut self, key: &str, value: Vec<String>) -> usize {
-        let current = self.get(key);
-        let mut list = match current {
-            Some(val) => {
-                if val.is_list() {
-                    val.as_list().unwrap().clone()
-                } else {
-                    VecDeque::new()
-                }
+    /// Writes a snapshot of every database to the snapshot file.
+    pub fn save(&self) -> std::io::Result<()> {
+        rdb::save(&self.snapshot_path, &self.snapshot())
+    }
+
+    /// Copies the dataset and writes the copy to the snapshot file on a
+    /// blocking task, so the store stays available while it is written.
+    /// Returns false if a background save is already running.
+    pub fn background_save(&self) -> bool {
+        if self.bgsave_in_progress.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+        let snapshot = self.snapshot();
+        let path = self.snapshot_path.clone();
+        let in_progress = self.bgsave_in_progress.clone();
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = rdb::save(&path, &snapshot) {
+                eprintln!("Background save failed: {}", e);
             }
-            _ => VecDeque::new(),
+            in_progress.store(false, Ordering::SeqCst);
+        });
+        true
+    }
+
+    /// Replaces every database with the contents of the snapshot file.
+    /// Returns false, leaving the store untouched, if there is no snapshot.
+    /// Keys whose deadline passed while the snapshot sat on disk are dropped.
+    pub fn load_snapshot(&mut self) -> std::io::Result<bool> {
+        let Some(databases) = rdb::load(&self.snapshot_path)? else {
+            return Ok(false);
         };
 
-        for v in value.clone() {
-            list.push_front(v);
+        let selected = self.db;
+        self.flush_all();
+        let now = SystemTime::now();
+        for (index, keys) in databases {
+            if !self.select(index) {
+                continue;
+            }
+            for (key, value, expiry) in keys {
+                match expiry {
+                    Some(deadline) if deadline <= now => continue,
+                    Some(deadline) => {
+                        self.expiry.insert(key.clone(), deadline);
+                    }
+                    None => {}
+                }
+                self.data.insert(key.clone(), value);
+                self.touch(&key);
+                self.note_growth(&key);
+            }
         }
+        self.select(selected);
+        Ok(true)
+    }
 
-        let len = list.len();
-        self.set(key, &Value::from(list));
+    /// The shared RNG every randomized command must draw from, so a fixed
+    /// seed makes their output deterministic.
+    pub fn rng(&mut self) -> &mut StdRng {
+        &mut self.rng
+    }
 
-        len
+    /// Signalled whenever an entry is appended to any stream, waking blocked
+    /// XREAD callers so they can re-check their streams.
+    pub fn stream_notify(&self) -> Arc<Notify> {
+        self.stream_notify.clone()
     }
 
-    pub fn rpush(&mut self, key: &str, value: Vec<String>) -> usize {
-        let current = self.get(key);
-        let mut list = match current {
-            Some(val) => {
-                if val.is_list() {
-                    val.as_list().unwrap().clone()
-                } else {
-                    VecDeque::new()
-                }
+    /// Makes `script` callable as `EVAL`/`EVALSHA name`, replacing any script
+    /// already registered under that name.
+    pub fn register_script<F>(&mut self, name: &str, script: F)
+    where
+        F: Fn(&mut Store, &[String], &[String]) -> String + Send + Sync + 'static,
+    {
+        self.scripts.insert(name.to_string(), Arc::new(script));
+    }
+
+    pub fn script(&self, name: &str) -> Option<Script> {
+        self.scripts.get(name).cloned()
+    }
+
+    pub fn replid(&self) -> &str {
+        &self.replid
+    }
+
+    pub fn stats(&self) -> Arc<Stats> {
+        self.stats.clone()
+    }
+
+    /// Live keys and keys with a TTL in every database that has any, by
+    /// database index.
+    pub fn keyspace_info(&self) -> Vec<(usize, usize, usize)> {
+        let now = SystemTime::now();
+        (0..self.databases())
+            .filter_map(|index| {
+                let (data, expiry) = self.keyspace(index);
+                let expired = expiry.values().filter(|deadline| now > **deadline).count();
+                let keys = data.len() - expired;
+                (keys > 0).then(|| (index, keys, expiry.len() - expired))
+            })
+            .collect()
+    }
+
+    /// Signalled once a client asks the server to shut down.
+    pub fn shutdown_signal(&self) -> Arc<Notify> {
+        self.shutdown.clone()
+    }
+
+    pub fn request_shutdown(&self) {
+        self.shutdown.notify_one();
+    }
+
+    /// Subscribes to every write applied from now on, including the DELs of
+    /// keys removed by expiry. A subscriber more than `CHANGE_BACKLOG` changes
+    /// behind gets `RecvError::Lagged` and has to resynchronise.
+    pub fn subscribe_changes(&self) -> broadcast::Receiver<Change> {
+        self.changes.subscribe()
+    }
+
+    /// Queues every message later published to `channel` on `outbox`, the
+    /// queue of client `client`.
+    pub fn subscribe(&mut self, channel: &str, client: u64, outbox: mpsc::UnboundedSender<String>) {
+        self.channels
+            .entry(channel.to_string())
+            .or_default()
+            .insert(client, outbox);
+    }
+
+    pub fn unsubscribe(&mut self, channel: &str, client: u64) {
+        if let Some(subscribers) = self.channels.get_mut(channel) {
+            subscribers.remove(&client);
+            if subscribers.is_empty() {
+                self.channels.remove(channel);
             }
-            _ => VecDeque::new(),
-        };
+        }
+    }
 
-        for v in value {
-            list.push_back(v.to_string());
+    /// Every channel with at least one subscriber whose name matches the glob
+    /// `pattern`, sorted.
+    pub fn channels(&self, pattern: &str) -> Vec<String> {
+        let mut channels: Vec<String> = self
+            .channels
+            .keys()
+            .filter(|channel| glob_match(pattern, channel))
+            .cloned()
+            .collect();
+        channels.sort();
+        channels
+    }
+
+    /// Number of clients subscribed to `channel`.
+    pub fn subscriber_count(&self, channel: &str) -> usize {
+        self.channels.get(channel).map_or(0, HashMap::len)
+    }
+
+    /// Queues `frame` for every subscriber of `channel` and returns how many
+    /// there were. Subscribers whose connection has gone are dropped.
+    pub fn publish(&mut self, channel: &str, frame: &str) -> usize {
+        let Some(subscribers) = self.channels.get_mut(channel) else {
+            return 0;
+        };
+        subscribers.retain(|_, outbox| outbox.send(frame.to_string()).is_ok());
+        let delivered = subscribers.len();
+        if delivered == 0 {
+            self.channels.remove(channel);
         }
+        delivered
+    }
 
-        let len = list.len();
-        self.set(key, &Value::from(list));
+    /// Starts queueing every change for the append-only file.
+    pub fn enable_aof(&mut self) {
+        self.aof = Some(AofBuffer::default());
+    }
 
-        len
+    /// The changes queued for the append-only file since the last call.
+    pub fn take_aof(&mut self) -> Vec<u8> {
+        self.aof.as_mut().map(AofBuffer::take).unwrap_or_default()
     }
 
-    pub fn lpop(&mut self, key: &str) -> Option<String> {
-        let current = self.get(key);
-        if let Some(val) = current {
-            if !val.is_list() {
-                return None;
-            }
-            let mut list = val.as_list().unwrap().clone();
-            let value = list.pop_front();
+    pub fn aof_enabled(&self) -> bool {
+        self.aof.is_some()
+    }
 
-            self.set(key, &Value::from(list));
-            value
-        } else {
-            None
+    /// Signalled once a client asks for the append-only file to be rewritten.
+    pub fn aof_rewrite_signal(&self) -> Arc<Notify> {
+        self.aof_rewrite.clone()
+    }
+
+    /// Asks for the append-only file to be rewritten in the background.
+    /// Returns false if a rewrite is already running.
+    pub fn request_aof_rewrite(&mut self) -> bool {
+        if self.aof_rewrite_in_progress {
+            return false;
         }
+        self.aof_rewrite_in_progress = true;
+        self.aof_rewrite.notify_one();
+        true
     }
-    pub fn rpop(&mut self, key: &str) -> Option<String> {
-        let current = self.get(key);
-        if let Some(val) = current {
-            if !val.is_list() {
-                return None;
-            }
-            let mut list = val.as_list().unwrap().clone();
-            let value = list.pop_back();
 
-            self.set(key, &Value::from(list));
-            value
-        } else {
-            None
+    /// A copy of every database for the rewritten append-only file. Changes
+    /// queued from here on follow the copy, so they start with a SELECT.
+    pub fn aof_rewrite_snapshot(&mut self) -> Vec<(usize, Database)> {
+        if let Some(aof) = &mut self.aof {
+            aof.reset_db();
         }
+        self.snapshot()
+    }
+
+    pub fn finish_aof_rewrite(&mut self) {
+        self.aof_rewrite_in_progress = false;
     }
 
-    pub fn llen(&mut self, key: &str) -> Option<usize> {
-        let current = self.get(key);
-        if let Some(val) = current {
-            if !val.is_list() {
-                return None;
+    /// Publishes a write to change-stream subscribers, if there are any, and
+    /// queues it for the append-only file.
+    pub fn emit_change(&mut self, command: &str, args: &[&[u8]]) {
+        if let Some(aof) = &mut self.aof {
+            aof.record(self.db, command, args);
+            // A relative TTL would restart when the file is replayed, so pin
+            // every key the write left with a TTL to its absolute deadline.
+            // Scripts find their keys by parsing and are not covered.
+            for key in key_args(command, args) {
+                if let Some(deadline) = std::str::from_utf8(key)
+                    .ok()
+                    .and_then(|key| self.expiry.get(key))
+                {
+                    let ms = deadline
+                        .duration_since(UNIX_EPOCH)
+                        .map_or(0, |d| d.as_millis());
+                    aof.record(self.db, "PEXPIREAT", &[key, ms.to_string().as_bytes()]);
+                }
             }
-            let list = val.as_list().unwrap();
-            Some(list.len())
-        } else {
-            None
         }
+
+        if self.changes.receiver_count() == 0 {
+            return;
+        }
+        let _ = self.changes.send(Change {
+            db: self.db,
+            command: command.to_string(),
+            args: args.iter().map(|arg| arg.to_vec()).collect(),
+        });
     }
 
-    pub fn lindex(&mut self, key: &str, index: usize) -> Option<String> {
-        let current = self.get(key);
-        if let Some(val) = current {
-            if !val.is_list() {
-                return None;
-            }
-            let list = val.as_list().unwrap();
+    /// The latency monitor, behind its own lock so that recording a command's
+    /// latency needs only shared access to the store.
+    pub fn latency(&self) -> MutexGuard<'_, LatencyMonitor> {
+        self.latency.lock().unwrap_or_else(PoisonError::into_inner)
+    }
 
-            if index < list.len() {
-                return Some(list[index].clone());
-            }
+    pub fn config_get(&self, name: &str) -> Option<String> {
+        match name.to_lowercase().as_str() {
+            "list-max-listpack-size" => Some(self.list_max_listpack_size.to_string()),
+            "hash-max-listpack-entries" => Some(self.hash_max_listpack_entries.to_string()),
+            "hz" => Some(self.hz.to_string()),
+            "set-max-intset-entries" => Some(self.set_max_intset_entries.to_string()),
+            "set-max-listpack-entries" => Some(self.set_max_listpack_entries.to_string()),
+            "zset-max-listpack-entries" => Some(self.zset_max_listpack_entries.to_string()),
+            "proto-max-bulk-len" => Some(self.proto_max_bulk_len.to_string()),
+            _ => None,
         }
-        None
     }
 
-    pub fn lset(&mut self, key: &str, index: usize, value: String) -> bool {
-        let current = self.get(key);
-        if let Some(val) = current {
-            if !val.is_list() {
-                return false;
+    pub fn config_set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        let setting = match name.to_lowercase().as_str() {
+            "list-max-listpack-size" => &mut self.list_max_listpack_size,
+            "hash-max-listpack-entries" => &mut self.hash_max_listpack_entries,
+            "hz" => &mut self.hz,
+            "set-max-intset-entries" => &mut self.set_max_intset_entries,
+            "set-max-listpack-entries" => &mut self.set_max_listpack_entries,
+            "zset-max-listpack-entries" => &mut self.zset_max_listpack_entries,
+            "proto-max-bulk-len" => &mut self.proto_max_bulk_len,
+            _ => {
+                return Err(format!(
+                    "Unknown option or number of arguments for CONFIG SET - '{}'",
+                    name
+                ));
             }
-            let mut list = val.as_list().unwrap().clone();
-            if index < list.len() {
-                list[index] = value;
-                self.set(key, &Value::from(list));
-                return true;
+        };
+        *setting = value
+            .parse::<usize>()
+            .map_err(|_| format!("Invalid argument '{}' for CONFIG SET '{}'", value, name))?;
+        Ok(())
+    }
+
+    /// Time between active expiry cycles, derived from `hz` as in Redis and
+    /// clamped to its 1..=500 range.
+    pub fn expire_interval(&self) -> Duration {
+        Duration::from_millis(1000 / self.hz.clamp(1, 500) as u64)
+    }
+
+    /// Largest reply, in bytes, the server will send for a single command.
+    pub fn proto_max_bulk_len(&self) -> usize {
+        self.proto_max_bulk_len
+    }
+
+    /// The encoding Redis would report for `key`. Lists, hashes, sets and
+    /// sorted sets switch to their large encoding once they grow past the
+    /// configured threshold and keep it even if they shrink again, until the
+    /// key is deleted. Small sets of integers report `intset`, other small
+    /// sets `listpack`. Strings report `int` if they hold an integer, and
+    /// otherwise `embstr` or `raw` by length, as Redis would store them.
+    pub fn object_encoding(&mut self, key: &str) -> Option<&'static str> {
+        let promoted = self.promoted.contains(key);
+        let intset_entries = self.set_max_intset_entries;
+        let encoding = match self.get_ref(key)? {
+            Value::String(s) if s.len() <= 20 && parse_int(s).is_some() => "int",
+            Value::String(s) if s.len() <= EMBSTR_MAX_LEN => "embstr",
+            Value::String(_) => "raw",
+            Value::List(_) if promoted => "quicklist",
+            Value::List(_) => "listpack",
+            Value::Hash(_) if promoted => "hashtable",
+            Value::Hash(_) => "listpack",
+            Value::Set(_) if promoted => "hashtable",
+            Value::Set(members) if members.len() <= intset_entries && is_intset(members) => {
+                "intset"
             }
+            Value::Set(_) => "listpack",
+            Value::Stream(_) => "stream",
+            Value::ZSet(_) if promoted => "skiplist",
+            Value::ZSet(_) => "listpack",
+        };
+        Some(encoding)
+    }
+
+    /// Marks `key` as promoted to its large encoding if it has outgrown the
+    /// configured threshold. Called after every operation that can grow a
+    /// list, hash, set or sorted set.
+    fn note_growth(&mut self, key: &str) {
+        let outgrown = match self.data.get(key) {
+            Some(Value::List(l)) => l.len() > self.list_max_listpack_size,
+            Some(Value::Hash(h)) => h.len() > self.hash_max_listpack_entries,
+            // Only sets between the two limits need their members checked.
+            Some(Value::Set(members)) => {
+                members.len() > self.set_max_listpack_entries
+                    && (members.len() > self.set_max_intset_entries || !is_intset(members))
+            }
+            Some(Value::ZSet(z)) => z.len() > self.zset_max_listpack_entries,
+            _ => false,
+        };
+        if outgrown {
+            self.promoted.insert(key.to_string());
         }
-        false
     }
 
-    pub fn lrange(&mut self, key: &str, start: usize, end: usize) -> Option<Vec<String>> {
-        if start > end {
-            return None;
+    pub fn set_active_expire(&mut self, enabled: bool) {
+        self.active_expire = enabled;
+    }
+
+    /// Removes up to `max_keys` keys whose deadline has passed, unless active
+    /// expiry has been paused with `DEBUG SET-ACTIVE-EXPIRE 0`. Returns the
+    /// number purged; a full batch means more expired keys are likely left.
+    pub fn expire_sweep(&mut self, max_keys: usize) -> usize {
+        if !self.active_expire {
+            return 0;
         }
 
-        let current = self.get(key);
-        if let Some(val) = current {
-            if !val.is_list() {
-                return None;
-            }
-            let list = val.as_list().unwrap();
+        let expired: Vec<String> = self
+            .expiry
+            .lapsed(SystemTime::now())
+            .take(max_keys)
+            .cloned()
+            .collect();
 
-            if start < list.len() && end < list.len() {
-                return Some(list.range(start..=end).cloned().collect());
-            }
+        for key in &expired {
+            self.del(key);
+            self.emit_change("DEL", &[key.as_bytes()]);
         }
-        None
+        expired.len()
     }
 
-    pub fn lrem(&mut self, key: &str, count: i64, value: String) -> usize {
-        let current = self.get(key);
-        if let Some(val) = current {
-            if !val.is_list() {
-                return 0;
+    /// Checks the store's internal invariants and describes every violation
+    /// found. Keys are `String`s, so they are valid UTF-8 by construction.
+    pub fn consistency_check(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        for key in self.expiry.keys() {
+            if !self.data.contains_key(key) {
+                problems.push(format!("expiry set for missing key '{}'", key));
             }
-            let mut list = val.as_list().unwrap().clone();
-
-            let mut removed_count = 0;
-            if count > 0 {
-                while let Some(pos) = list.iter().position(|x| *x == value) {
-                    list.remove(pos);
-                    removed_count += 1;
-                    if removed_count == count as usize {
-                        break;
-                    }
-                }
-            } else if count < 0 {
-                while let Some(pos) = list.iter().rposition(|x| *x == value) {
-                    list.remove(pos);
-                    removed_count += 1;
-                    if removed_count == (-count) as usize {
-                        break;
-                    }
-                }
-            } else {
-                removed_count = list.iter().filter(|x| **x == value).count();
-                list.retain(|x| *x != value);
+        }
+        for key in &self.promoted {
+            if !self.data.contains_key(key) {
+                problems.push(format!("encoding promoted for missing key '{}'", key));
+            }
+        }
+        for (key, value) in self.data.iter() {
+            let empty = match value {
+                Value::List(l) => l.is_empty(),
+                Value::Hash(h) => h.is_empty(),
+                Value::Set(s) => s.is_empty(),
+                Value::ZSet(z) => z.is_empty(),
+                Value::String(_) | Value::Stream(_) => false,
+            };
+            if empty {
+                problems.push(format!("empty {} left at key '{}'", value.type_name(), key));
             }
+        }
+        problems.sort();
+        problems
+    }
+
+    /// Looks a key up without triggering lazy expiry, returning the value and
+    /// the time left before its deadline (zero if it has already passed).
+    pub fn debug_object(&self, key: &str) -> Option<(&Value, Option<Duration>)> {
+        let value = self.data.get(key)?;
+        let remaining = self.expiry.get(key).map(|deadline| {
+            deadline
+                .duration_since(SystemTime::now())
+                .unwrap_or(Duration::ZERO)
+        });
+        Some((value, remaining))
+    }
 
-            self.set(key, &Value::from(list));
-            return removed_count;
+    pub fn set(&mut self, key: &str, value: &Value) {
+        if value.is_string() {
+            self.promoted.remove(key);
         }
-        0
+        self.data.insert(key.to_string(), value.clone());
+        self.touch(key);
+        self.expiry.remove(key);
     }
 
-    pub fn hset(&mut self, key: &str, field: &str, value: &str) -> bool {
-        let current = self.get(key);
-        let mut hash = match current {
-            Some(val) => {
-                if val.is_hash() {
-                    val.as_hash().unwrap().clone()
-                } else {
-                    HashMap::new()
-                }
-            }
-            _ => HashMap::new(),
-        };
+    /// SET with its options applied atomically: the write only happens if the
+    /// condition holds, and the TTL is set in the same step. Returns whether
+    /// the value was written.
+    pub fn set_with_options(&mut self, key: &str, value: &Value, options: &SetOptions) -> bool {
+        let exists = self.exists(key);
+        match options.condition {
+            Some(SetCondition::IfMissing) if exists => return false,
+            Some(SetCondition::IfExists) if !exists => return false,
+            _ => {}
+        }
 
-        let res = match hash.insert(field.to_string(), value.to_string()) {
-            Some(_) => true,
-            None => false,
-        };
+        self.set(key, value);
+        if let Some(ttl) = options.ttl {
+            self.expiry.insert(key.to_string(), deadline_after(ttl));
+        }
+        true
+    }
 
-        self.set(key, &Value::from(hash));
+    /// Sets every key/value pair in one step. Like SET, this clears any TTL
+    /// the keys had.
+    pub fn mset(&mut self, pairs: &[(String, Vec<u8>)]) {
+        for (key, value) in pairs {
+            self.set(key, &Value::from(value.clone()));
+        }
+    }
 
-        res
+    pub fn get(&mut self, key: &str) -> Option<Value> {
+        self.get_ref(key).cloned()
     }
 
-    pub fn hget(&mut self, key: &str, field: &str) -> Option<String> {
-        let current = self.get(key);
-        if let Some(val) = current {
-            if !val.is_hash() {
-                return None;
-            }
-            let hash = val.as_hash().unwrap();
+    /// The string held at `key`, or WRONGTYPE if it holds something else.
+    pub fn get_string(&self, key: &str) -> Result<Option<Vec<u8>>, StoreError> {
+        Ok(self.read(key, Value::as_string)?.cloned())
+    }
 
-            return hash.get(field).cloned();
-        }
-        None
+    /// Length of the string held at `key`; 0 if there is none.
+    pub fn strlen(&self, key: &str) -> Result<usize, StoreError> {
+        Ok(self.read(key, Value::as_string)?.map_or(0, Vec::len))
     }
 
-    pub fn hdel(&mut self, key: &str, field: &str) -> bool {
-        let current = self.get(key);
-        if let Some(val) = current {
-            if !val.is_hash() {
-                return false;
-            }
-            let mut hash = val.as_hash().unwrap().clone();
+    /// Sets `key` to `value` and returns the string it held before. Like SET,
+    /// this clears the key's TTL.
+    pub fn get_set(&mut self, key: &str, value: &[u8]) -> Result<Option<Vec<u8>>, StoreError> {
+        let old = self.typed(key, Value::as_string)?.cloned();
+        self.set(key, &Value::from(value.to_vec()));
+        Ok(old)
+    }
 
-            let res = hash.remove(field).is_some();
-            self.set(key, &Value::from(hash));
+    /// Deletes `key` and returns the string it held.
+    pub fn get_del(&mut self, key: &str) -> Result<Option<Vec<u8>>, StoreError> {
+        let old = self.typed(key, Value::as_string)?.cloned();
+        if old.is_some() {
+            self.del(key);
+        }
+        Ok(old)
+    }
 
-            return res;
+    /// Like `get`, including lazy expiry, but borrows the value instead of
+    /// cloning it.
+    fn get_ref(&mut self, key: &str) -> Option<&Value> {
+        if let Some(expiry_time) = self.expiry.get(key)
+            && SystemTime::now() > *expiry_time
+        {
+            self.del(key);
+            self.emit_change("DEL", &[key.as_bytes()]);
+            return None;
+        }
+        if !self.no_touch && self.data.contains_key(key) {
+            self.touch(key);
         }
-        false
+        self.data.get(key)
     }
 
-    pub fn hlen(&mut self, key: &str) -> Option<usize> {
-        let current = self.get(key);
-        if let Some(val) = current {
-            if !val.is_hash() {
-                return None;
-            }
-            let hash = val.as_hash().unwrap();
-            return Some(hash.len());
+    /// The value at `key` unless it is past its deadline, in which case it
+    /// reads as missing but is left for the next write or the sweeper to
+    /// delete. Leaves the access time alone.
+    fn live(&self, key: &str) -> Option<&Value> {
+        let lapsed = self
+            .expiry
+            .get(key)
+            .is_some_and(|deadline| SystemTime::now() > *deadline);
+        if lapsed { None } else { self.data.get(key) }
+    }
+
+    /// Like `get_ref`, but for a command running under a shared lock: a key
+    /// past its deadline reads as missing without being deleted.
+    fn read_ref(&self, key: &str) -> Option<&Value> {
+        let value = self.live(key)?;
+        if !self.no_touch {
+            self.touch_shared(key);
         }
-        None
+        Some(value)
     }
 
-    pub fn hget_all(&mut self, key: &str) -> Option<HashMap<String, String>> {
-        let current = self.get(key);
-        if let Some(val) = current {
-            if !val.is_hash() {
-                return None;
-            }
-            let hash = val.as_hash().unwrap();
-            return Some(hash.clone());
+    /// Like `typed`, but through `read_ref`.
+    fn read<T>(&self, key: &str, view: fn(&Value) -> Option<&T>) -> Result<Option<&T>, StoreError> {
+        match self.read_ref(key) {
+            Some(value) => view(value).map(Some).ok_or(StoreError::WrongType),
+            None => Ok(None),
         }
-        None
     }
 
-    pub fn hincr_by(&mut self, key: &str, field: &str, by: i64) -> Option<i64> {
-        let current = self.get(key);
-        if let Some(val) = current {
-            if !val.is_hash() {
-                return None;
+    /// The live value at `key`, borrowed so it can be measured without being
+    /// copied.
+    pub fn peek(&self, key: &str) -> Option<&Value> {
+        self.live(key)
+    }
+
+    /// Records that `key` was just read or written.
+    fn touch(&mut self, key: &str) {
+        stamp(self.accessed_mut(), key);
+    }
+
+    /// `touch` for a reader that shares the store with others.
+    fn touch_shared(&self, key: &str) {
+        stamp(
+            &mut self.accessed.lock().unwrap_or_else(PoisonError::into_inner),
+            key,
+        );
+    }
+
+    /// Access times, for a caller that has the store to itself and so needs
+    /// no lock to reach them.
+    fn accessed_mut(&mut self) -> &mut HashMap<String, Instant> {
+        self.accessed
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Seconds since `key` was last read or written, `None` if it does not
+    /// exist. Unlike every other lookup, this leaves the access time alone.
+    pub fn idle_time(&self, key: &str) -> Option<u64> {
+        self.live(key)?;
+        let accessed = self.accessed.lock().unwrap_or_else(PoisonError::into_inner);
+        Some(
+            accessed
+                .get(key)
+                .map_or(0, |accessed| accessed.elapsed().as_secs()),
+        )
+    }
+
+    /// Number of live keys in the selected database. Keys past their deadline
+    /// are not counted but are left for expiry to remove, so DBSIZE never
+    /// writes.
+    pub fn dbsize(&self) -> usize {
+        let now = SystemTime::now();
+        let expired = self
+            .expiry
+            .values()
+            .filter(|deadline| now > **deadline)
+            .count();
+        self.data.len() - expired
+    }
+
+    /// Every live key matching the glob `pattern`. This walks the whole
+    /// keyspace, so it is O(n) in the number of keys and holds the store lock
+    /// throughout; prefer SCAN on large datasets.
+    pub fn keys(&self, pattern: &str) -> Vec<String> {
+        let now = SystemTime::now();
+        self.data
+            .keys()
+            .filter(|key| self.expiry.get(key).is_none_or(|deadline| now <= *deadline))
+            .filter(|key| glob_match(pattern, key))
+            .cloned()
+            .collect()
+    }
+
+    /// A live key chosen uniformly at random, or `None` if there are none.
+    /// The map cannot be indexed, so this reservoir-samples the keys in one
+    /// pass: O(n) in the number of keys, without collecting them.
+    pub fn random_key(&mut self) -> Option<String> {
+        let now = SystemTime::now();
+        self.data
+            .keys()
+            .filter(|key| self.expiry.get(key).is_none_or(|deadline| now <= *deadline))
+            .choose(&mut self.rng)
+            .cloned()
+    }
+
+    /// One `SCAN` page: up to `count` keys, taken in order of a fixed hash of
+    /// their name starting from `cursor`, and the cursor of the next page (0
+    /// once the scan is done). The keys are kept in that order as they are
+    /// added, so a page costs O(`count`) however many keys there are.
+    ///
+    /// The cursor is a position in hash space rather than in the key set, so
+    /// adding or deleting keys between calls cannot shift it: every key that
+    /// exists for the whole scan is returned exactly once. Keys sharing a hash
+    /// are never split across pages, so a page may exceed `count` slightly.
+    ///
+    /// As in Redis, expired keys and `pattern` filter the page after it is
+    /// taken, so a page may come back short, or empty, while the cursor is
+    /// not yet 0.
+    pub fn scan(&self, cursor: u64, count: usize, pattern: Option<&str>) -> (u64, Vec<String>) {
+        let now = SystemTime::now();
+        let mut keys = Vec::new();
+        let mut last_hash = None;
+        for (taken, (hash, key)) in self.data.scan_from(cursor).enumerate() {
+            if taken >= count && last_hash != Some(hash) {
+                return (hash, keys);
             }
-            let mut hash = val.as_hash().unwrap().clone();
-            
-            if let Some(value) = hash.get_mut(field) {
-                match value.parse::<i64>() {
-                    Ok(n) => {
-                        let new_value = n + by;
-                        *value = new_value.to_string();
-                        self.set(key, &Value::from(hash));
-                        return Some(new_value);
-                    }
-                    Err(_) => return None,
-                }
-            } else {
-                hash.insert(field.to_string(), by.to_string());
-                self.set(key, &Value::from(hash));
-                return Some(by);
+            last_hash = Some(hash);
+
+            let live = self.expiry.get(key).is_none_or(|deadline| now <= *deadline);
+            if live && pattern.is_none_or(|pattern| glob_match(pattern, key)) {
+                keys.push(key.clone());
             }
         }
-        None
+        (0, keys)
+    }
+
+    /// Like `get_ref`, but borrows the value mutably so collection commands
+    /// can change it in place.
+    fn get_mut(&mut self, key: &str) -> Option<&mut Value> {
+        self.get_ref(key)?;
+        self.data.get_mut(key)
+    }
+
+    /// The value at `key` seen through `view` (such as `Value::as_list`):
+    /// `None` if the key does not exist, `WrongType` if it holds another type.
+    fn typed<T>(
+        &mut self,
+        key: &str,
+        view: fn(&Value) -> Option<&T>,
+    ) -> Result<Option<&T>, StoreError> {
+        match self.get_ref(key) {
+            Some(value) => view(value).map(Some).ok_or(StoreError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    /// Like `typed`, but borrows the value mutably.
+    fn typed_mut<T>(
+        &mut self,
+        key: &str,
+        view: fn(&mut Value) -> Option<&mut T>,
+    ) -> Result<Option<&mut T>, StoreError> {
+        match self.get_mut(key) {
+            Some(value) => view(value).map(Some).ok_or(StoreError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    /// Like `typed_mut`, but first stores `empty()` at `key` if it does not
+    /// exist, so the caller always gets a value to write to.
+    fn typed_or_insert<T>(
+        &mut self,
+        key: &str,
+        empty: impl FnOnce() -> Value,
+        view: fn(&mut Value) -> Option<&mut T>,
+    ) -> Result<&mut T, StoreError> {
+        // Drop the key if it has expired so it is recreated from scratch.
+        self.get_ref(key);
+        self.touch(key);
+        let value = self.data.get_or_insert_with(key, empty);
+        view(value).ok_or(StoreError::WrongType)
+    }
+
+    /// Deletes `key` if it holds a list, hash or set with no elements left, as
+    /// Redis never keeps empty collections around.
+    fn del_if_empty(&mut self, key: &str) {
+        let empty = match self.data.get(key) {
+            Some(Value::List(l)) => l.is_empty(),
+            Some(Value::Hash(h)) => h.is_empty(),
+            Some(Value::Set(s)) => s.is_empty(),
+            Some(Value::ZSet(z)) => z.is_empty(),
+            _ => false,
+        };
+        if empty {
+            self.del(key);
+        }
+    }
+
+    /// The type name TYPE reports for `key`, or `None` if it does not exist.
+    pub fn key_type(&self, key: &str) -> Option<&'static str> {
+        self.read_ref(key).map(Value::type_name)
+    }
+
+    /// Whether `key` holds a live value, applying lazy expiry but without
+    /// cloning the value.
+    pub fn exists(&mut self, key: &str) -> bool {
+        self.get_ref(key).is_some()
+    }
+
+    /// Like `exists`, but leaves a lapsed key for the sweeper to delete, so it
+    /// needs only shared access.
+    pub fn contains(&self, key: &str) -> bool {
+        self.read_ref(key).is_some()
+    }
+
+    /// Like `exists`, but refreshes the access time even under NO-TOUCH,
+    /// since that is what TOUCH is for.
+    pub fn touch_existing(&mut self, key: &str) -> bool {
+        let exists = self.exists(key);
+        if exists {
+            self.touch(key);
+        }
+        exists
+    }
+
+    pub fn del(&mut self, key: &str) -> bool {
+        self.expiry.remove(key);
+        self.promoted.remove(key);
+        self.accessed_mut().remove(key);
+        self.data.remove(key).is_some()
+    }
+
+    /// Deletes `key` only if it holds the string `expected`.
+    pub fn compare_and_delete(&mut self, key: &str, expected: &[u8]) -> bool {
+        match self.get_ref(key) {
+            Some(Value::String(current)) if current == expected => self.del(key),
+            _ => false,
+        }
+    }
+
+    /// Overwrites `key` with `value` only if it currently holds the string
+    /// `expected`. Like SET, a successful swap clears any expiry.
+    pub fn compare_and_set(&mut self, key: &str, expected: &[u8], value: &[u8]) -> bool {
+        match self.get_ref(key) {
+            Some(Value::String(current)) if current == expected => {
+                self.set(key, &Value::from(value.to_vec()));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Moves `old` to `new`, overwriting it. The TTL moves with the value,
+    /// and whatever TTL `new` had is dropped. Returns false if `old` does not
+    /// exist.
+    pub fn rename(&mut self, old: &str, new: &str) -> bool {
+        // Expire `old` lazily first so a dead key is not resurrected.
+        self.get_ref(old);
+        let Some(value) = self.data.remove(old) else {
+            return false;
+        };
+        let expiry = self.expiry.remove(old);
+        let promoted = self.promoted.remove(old);
+        self.accessed_mut().remove(old);
+
+        self.del(new);
+        self.data.insert(new.to_string(), value);
+        self.touch(new);
+        if let Some(expiry) = expiry {
+            self.expiry.insert(new.to_string(), expiry);
+        }
+        if promoted {
+            self.promoted.insert(new.to_string());
+        }
+        true
+    }
+
+    /// Copies the value at `src`, with its TTL and encoding, to `dst`. Fails
+    /// if `src` does not exist, or if `dst` does and `replace` is false.
+    pub fn copy(&mut self, src: &str, dst: &str, replace: bool) -> bool {
+        let Some(value) = self.get(src) else {
+            return false;
+        };
+        if !replace && self.exists(dst) {
+            return false;
+        }
+        let expiry = self.expiry.get(src).copied();
+        let promoted = self.promoted.contains(src);
+
+        self.del(dst);
+        self.data.insert(dst.to_string(), value);
+        self.touch(dst);
+        if let Some(expiry) = expiry {
+            self.expiry.insert(dst.to_string(), expiry);
+        }
+        if promoted {
+            self.promoted.insert(dst.to_string());
+        }
+        true
+    }
+
+    /// Moves `key`, with its TTL and encoding, from the selected database to
+    /// database `db`, which must be another one. Fails if `key` does not
+    /// exist here or already exists there. Every database is behind the one
+    /// store lock, so the move is atomic.
+    pub fn move_key(&mut self, key: &str, db: usize) -> bool {
+        let source = self.db;
+        if !self.exists(key) {
+            return false;
+        }
+        self.select(db);
+        let taken = self.exists(key);
+        self.select(source);
+        if taken {
+            return false;
+        }
+
+        let value = self.data.remove(key).unwrap();
+        let expiry = self.expiry.remove(key);
+        let promoted = self.promoted.remove(key);
+        self.accessed_mut().remove(key);
+
+        self.select(db);
+        self.data.insert(key.to_string(), value);
+        self.touch(key);
+        if let Some(expiry) = expiry {
+            self.expiry.insert(key.to_string(), expiry);
+        }
+        if promoted {
+            self.promoted.insert(key.to_string());
+        }
+        self.select(source);
+        true
+    }
+
+    /// Moves `old` to `new`, overwriting it, and gives `new` a TTL of `ms`
+    /// milliseconds in the same step. Returns false if `old` does not exist.
+    pub fn rename_with_ttl(&mut self, old: &str, new: &str, ms: u64) -> bool {
+        if !self.rename(old, new) {
+            return false;
+        }
+        self.expiry
+            .insert(new.to_string(), deadline_after(Duration::from_millis(ms)));
+        true
+    }
+
+    pub fn expire(&mut self, key: &str, duration: u64) -> bool {
+        self.pexpire(key, duration.saturating_mul(1000))
+    }
+
+    /// Sets a TTL of `millis` milliseconds on `key`, if it exists.
+    pub fn pexpire(&mut self, key: &str, millis: u64) -> bool {
+        if self.exists(key) {
+            self.expiry.insert(
+                key.to_string(),
+                deadline_after(Duration::from_millis(millis)),
+            );
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Makes `key` permanent, returning whether it had a TTL to remove.
+    pub fn persist(&mut self, key: &str) -> bool {
+        self.get_ref(key).is_some() && self.expiry.remove(key).is_some()
+    }
+
+    /// Sets `key` to expire at `millis` milliseconds after the Unix epoch, if
+    /// it exists. A time already past deletes the key straight away.
+    pub fn pexpire_at(&mut self, key: &str, millis: u64) -> bool {
+        if !self.exists(key) {
+            return false;
+        }
+        let deadline = UNIX_EPOCH + Duration::from_millis(millis);
+        if deadline <= SystemTime::now() {
+            self.del(key);
+        } else {
+            self.expiry.insert(key.to_string(), deadline);
+        }
+        true
+    }
+
+    /// Like `expire`, but moves the deadline by a random amount of up to
+    /// `jitter` seconds either way, so keys given the same TTL together do
+    /// not all expire in the same instant.
+    pub fn expire_with_jitter(&mut self, key: &str, duration: u64, jitter: u64) -> bool {
+        if !self.exists(key) {
+            return false;
+        }
+
+        let spread = jitter.saturating_mul(1000);
+        let offset = self.rng().random_range(0..=spread.saturating_mul(2));
+        let millis = duration
+            .saturating_mul(1000)
+            .saturating_add(offset)
+            .saturating_sub(spread);
+        self.expiry.insert(
+            key.to_string(),
+            deadline_after(Duration::from_millis(millis)),
+        );
+        true
+    }
+
+    /// Time left before `key` expires in seconds, rounded to the nearest
+    /// second as Redis does, `-1` if it has no expiry and `-2` if it does not
+    /// exist. A key past its deadline counts as missing but is left for the
+    /// next access or the sweeper to delete.
+    pub fn ttl(&self, key: &str) -> i64 {
+        match self.expiry.get(key) {
+            Some(deadline) => deadline
+                .duration_since(SystemTime::now())
+                .map_or(-2, |remaining| {
+                    i64::try_from((remaining.as_millis() + 500) / 1000).unwrap_or(i64::MAX)
+                }),
+            None if self.data.contains_key(key) => -1,
+            None => -2,
+        }
+    }
+
+    /// Time left before `key` expires in milliseconds, `-1` if it has no
+    /// expiry and `-2` if it does not exist.
+    pub fn pttl(&mut self, key: &str) -> i64 {
+        if !self.exists(key) {
+            return -2;
+        }
+        match self.expiry.get(key) {
+            Some(deadline) => deadline
+                .duration_since(SystemTime::now())
+                .map(|d| i64::try_from(d.as_millis()).unwrap_or(i64::MAX))
+                .unwrap_or(0),
+            None => -1,
+        }
+    }
+
+    /// Absolute Unix deadline of `key` in milliseconds, `-1` if it has no
+    /// expiry and `-2` if it does not exist.
+    pub fn expire_time(&mut self, key: &str) -> i64 {
+        if !self.exists(key) {
+            return -2;
+        }
+        match self.expiry.get(key) {
+            Some(deadline) => deadline
+                .duration_since(UNIX_EPOCH)
+                .map(|d| i64::try_from(d.as_millis()).unwrap_or(i64::MAX))
+                .unwrap_or(0),
+            None => -1,
+        }
+    }
+
+    /// Appends `suffix` to the string at `key`, creating it if needed, and
+    /// returns the new length. The key keeps its TTL.
+    pub fn append(&mut self, key: &str, suffix: &[u8]) -> Result<usize, StoreError> {
+        let value =
+            self.typed_or_insert(key, || Value::String(Vec::new()), Value::as_string_mut)?;
+        value.extend_from_slice(suffix);
+        Ok(value.len())
+    }
+
+    /// Bytes `start..=end` of the string at `key`, where negative offsets
+    /// count from the end as resolved by `resolve_range`.
+    pub fn get_range(&mut self, key: &str, start: i64, end: i64) -> Result<Vec<u8>, StoreError> {
+        let Some(value) = self.typed(key, Value::as_string)? else {
+            return Ok(Vec::new());
+        };
+        Ok(match resolve_range(start, end, value.len()) {
+            Some((start, end)) => value[start..=end].to_vec(),
+            None => Vec::new(),
+        })
+    }
+
+    /// Overwrites the string at `key` with `value` from byte `offset` on,
+    /// creating the key if needed and padding with zero bytes up to `offset`.
+    /// Returns the new length; an empty `value` changes nothing. The key keeps
+    /// its TTL.
+    pub fn set_range(
+        &mut self,
+        key: &str,
+        offset: usize,
+        value: &[u8],
+    ) -> Result<usize, StoreError> {
+        if value.is_empty() {
+            return Ok(self.typed(key, Value::as_string)?.map_or(0, Vec::len));
+        }
+        let end = offset
+            .checked_add(value.len())
+            .filter(|&end| end <= self.proto_max_bulk_len)
+            .ok_or(StoreError::TooLarge)?;
+
+        let current =
+            self.typed_or_insert(key, || Value::String(Vec::new()), Value::as_string_mut)?;
+        if current.len() < end {
+            current.resize(end, 0);
+        }
+        current[offset..end].copy_from_slice(value);
+        Ok(current.len())
+    }
+
+    /// Bit `offset` of the string at `key`, counting from the most significant
+    /// bit of the first byte. Bits past the end, or of a missing key, are 0.
+    pub fn getbit(&mut self, key: &str, offset: u64) -> Result<bool, StoreError> {
+        let Some(value) = self.typed(key, Value::as_string)? else {
+            return Ok(false);
+        };
+        let byte = usize::try_from(offset / 8).ok().and_then(|i| value.get(i));
+        Ok(byte.is_some_and(|byte| byte & (0x80 >> (offset % 8)) != 0))
+    }
+
+    /// Sets or clears bit `offset` of the string at `key`, numbered as in
+    /// `getbit`, growing the string with zero bytes or creating it as needed.
+    /// Returns the bit's old value. The key keeps its TTL.
+    pub fn setbit(&mut self, key: &str, offset: u64, on: bool) -> Result<bool, StoreError> {
+        let index = usize::try_from(offset / 8)
+            .ok()
+            .filter(|&index| index < self.proto_max_bulk_len)
+            .ok_or(StoreError::TooLarge)?;
+        let mask = 0x80 >> (offset % 8);
+
+        let bytes =
+            self.typed_or_insert(key, || Value::String(Vec::new()), Value::as_string_mut)?;
+        if bytes.len() <= index {
+            bytes.resize(index + 1, 0);
+        }
+        let old = bytes[index];
+        if on {
+            bytes[index] |= mask;
+        } else {
+            bytes[index] &= !mask;
+        }
+        Ok(old & mask != 0)
+    }
+
+    /// Number of set bits in the string at `key`, 0 if it does not exist.
+    pub fn bitcount(&mut self, key: &str) -> Result<usize, StoreError> {
+        Ok(self.typed(key, Value::as_string)?.map_or(0, |value| {
+            value.iter().map(|byte| byte.count_ones() as usize).sum()
+        }))
+    }
+
+    /// Adds `by` to the integer stored at `key`, taking a missing key as 0.
+    /// Fails if the value is not an integer or the sum would overflow.
+    pub fn incr_by(&mut self, key: &str, by: i64) -> Result<i64, StoreError> {
+        let Some(current) = self.typed_mut(key, Value::as_string_mut)? else {
+            self.set(key, &Value::from(by.to_string()));
+            return Ok(by);
+        };
+
+        let new_value = parse_int(current)
+            .and_then(|n| n.checked_add(by))
+            .ok_or(StoreError::NotAnInteger)?;
+        // Update the value in place rather than through `set`, which would
+        // drop the key's TTL.
+        *current = new_value.to_string().into_bytes();
+        Ok(new_value)
+    }
+
+    /// Increments `key` by `by`, or creates it holding `by` with a TTL of
+    /// `seconds` if it does not exist. An existing key keeps its current TTL,
+    /// which makes this the fixed-window rate limiter primitive.
+    pub fn incr_by_with_ttl(
+        &mut self,
+        key: &str,
+        by: i64,
+        seconds: u64,
+    ) -> Result<i64, StoreError> {
+        if self.exists(key) {
+            return self.incr_by(key, by);
+        }
+
+        self.set(key, &Value::from(by.to_string()));
+        self.expire(key, seconds);
+        Ok(by)
+    }
+
+    /// Pushes onto the head of the list at `key` in place, creating it if
+    /// needed, so the list is never copied out of and back into the map.
+    pub fn lpush(&mut self, key: &str, value: Vec<String>) -> Result<usize, StoreError> {
+        let list =
+            self.typed_or_insert(key, || Value::from(VecDeque::new()), Value::as_list_mut)?;
+        for v in value {
+            list.push_front(v);
+        }
+
+        let len = list.len();
+        self.note_growth(key);
+
+        Ok(len)
+    }
+
+    pub fn rpush(&mut self, key: &str, value: Vec<String>) -> Result<usize, StoreError> {
+        let list =
+            self.typed_or_insert(key, || Value::from(VecDeque::new()), Value::as_list_mut)?;
+        for v in value {
+            list.push_back(v);
+        }
+
+        let len = list.len();
+        self.note_growth(key);
+
+        Ok(len)
+    }
+
+    pub fn lpop(&mut self, key: &str) -> Result<Option<String>, StoreError> {
+        let value = self
+            .typed_mut(key, Value::as_list_mut)?
+            .and_then(VecDeque::pop_front);
+        self.del_if_empty(key);
+        Ok(value)
+    }
+
+    pub fn rpop(&mut self, key: &str) -> Result<Option<String>, StoreError> {
+        let value = self
+            .typed_mut(key, Value::as_list_mut)?
+            .and_then(VecDeque::pop_back);
+        self.del_if_empty(key);
+        Ok(value)
+    }
+
+    /// Pops an element from the `from` end of the list at `src` and pushes it
+    /// onto the `to` end of the list at `dst`, creating it if needed. `None`
+    /// if `src` does not exist. `src` and `dst` may be the same list, which
+    /// rotates it in place.
+    pub fn lmove(
+        &mut self,
+        src: &str,
+        dst: &str,
+        from: ListEnd,
+        to: ListEnd,
+    ) -> Result<Option<String>, StoreError> {
+        // Type-check the destination first so a WRONGTYPE leaves the source
+        // untouched.
+        self.typed(dst, Value::as_list)?;
+        let Some(list) = self.typed_mut(src, Value::as_list_mut)? else {
+            return Ok(None);
+        };
+        let Some(value) = (match from {
+            ListEnd::Left => list.pop_front(),
+            ListEnd::Right => list.pop_back(),
+        }) else {
+            return Ok(None);
+        };
+
+        let list =
+            self.typed_or_insert(dst, || Value::from(VecDeque::new()), Value::as_list_mut)?;
+        match to {
+            ListEnd::Left => list.push_front(value.clone()),
+            ListEnd::Right => list.push_back(value.clone()),
+        }
+        // Only now, so rotating a one-element list does not delete it and
+        // lose its TTL on the way.
+        self.del_if_empty(src);
+        self.note_growth(dst);
+
+        Ok(Some(value))
+    }
+
+    /// Pops up to `count` elements from the head of the list at `key`, in
+    /// pop order. `None` if the key does not exist.
+    pub fn lpop_n(&mut self, key: &str, count: usize) -> Result<Option<Vec<String>>, StoreError> {
+        let popped = self
+            .typed_mut(key, Value::as_list_mut)?
+            .map(|list| list.drain(..count.min(list.len())).collect());
+        self.del_if_empty(key);
+        Ok(popped)
+    }
+
+    /// Like `lpop_n`, but from the tail.
+    pub fn rpop_n(&mut self, key: &str, count: usize) -> Result<Option<Vec<String>>, StoreError> {
+        let popped = self.typed_mut(key, Value::as_list_mut)?.map(|list| {
+            let start = list.len().saturating_sub(count);
+            list.drain(start..).rev().collect()
+        });
+        self.del_if_empty(key);
+        Ok(popped)
+    }
+
+    /// Length of the list at `key`, `0` if it does not exist.
+    pub fn llen(&self, key: &str) -> Result<usize, StoreError> {
+        Ok(self.read(key, Value::as_list)?.map_or(0, VecDeque::len))
+    }
+
+    /// Element `index` of the list at `key`, where a negative index counts
+    /// from the tail. `None` if the key is missing or the index is out of
+    /// range.
+    pub fn lindex(&self, key: &str, index: i64) -> Result<Option<String>, StoreError> {
+        Ok(self.read(key, Value::as_list)?.and_then(|list| {
+            let index = resolve_index(index, list.len())?;
+            list.get(index).cloned()
+        }))
+    }
+
+    /// Replaces element `index` of the list at `key`, indexed as in
+    /// `lindex`. False if there is no such element.
+    pub fn lset(&mut self, key: &str, index: i64, value: String) -> Result<bool, StoreError> {
+        let Some(list) = self.typed_mut(key, Value::as_list_mut)? else {
+            return Ok(false);
+        };
+        match resolve_index(index, list.len()) {
+            Some(index) => {
+                list[index] = value;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Elements `start..=end` of the list at `key`, where negative indices
+    /// count from the tail as resolved by `resolve_range`. Empty if the key is
+    /// missing or the range selects nothing.
+    pub fn lrange(&mut self, key: &str, start: i64, end: i64) -> Result<Vec<String>, StoreError> {
+        let Some(list) = self.typed(key, Value::as_list)? else {
+            return Ok(Vec::new());
+        };
+        Ok(match resolve_range(start, end, list.len()) {
+            Some((start, end)) => list.range(start..=end).cloned().collect(),
+            None => Vec::new(),
+        })
+    }
+
+    pub fn lrem(&mut self, key: &str, count: i64, value: String) -> Result<usize, StoreError> {
+        let Some(list) = self.typed_mut(key, Value::as_list_mut)? else {
+            return Ok(0);
+        };
+
+        // A count of 0 removes every match; otherwise at most |count|, with
+        // `unsigned_abs` keeping i64::MIN from overflowing.
+        let limit = match count {
+            0 => usize::MAX,
+            n => usize::try_from(n.unsigned_abs()).unwrap_or(usize::MAX),
+        };
+        let mut removed_count = 0;
+        if count >= 0 {
+            list.retain(|x| {
+                let remove = removed_count < limit && *x == value;
+                removed_count += remove as usize;
+                !remove
+            });
+        } else {
+            let mut kept = VecDeque::with_capacity(list.len());
+            while let Some(item) = list.pop_back() {
+                if removed_count < limit && item == value {
+                    removed_count += 1;
+                } else {
+                    kept.push_front(item);
+                }
+            }
+            *list = kept;
+        }
+
+        self.del_if_empty(key);
+        Ok(removed_count)
+    }
+
+    /// Sets `field` in the hash at `key`, creating the hash if needed.
+    /// Returns whether the field is new, as HSET reports.
+    pub fn hset(&mut self, key: &str, field: &str, value: &str) -> Result<bool, StoreError> {
+        let hash = self.typed_or_insert(key, || Value::from(HashMap::new()), Value::as_hash_mut)?;
+        let added = hash.insert(field.to_string(), value.to_string()).is_none();
+        self.note_growth(key);
+
+        Ok(added)
+    }
+
+    /// Sets every field in `pairs` in the hash at `key`, creating the hash if
+    /// needed. Returns how many of the fields are new.
+    pub fn hmset(&mut self, key: &str, pairs: &[(String, String)]) -> Result<usize, StoreError> {
+        let hash = self.typed_or_insert(key, || Value::from(HashMap::new()), Value::as_hash_mut)?;
+        let added = pairs
+            .iter()
+            .filter(|(field, value)| hash.insert(field.clone(), value.clone()).is_none())
+            .count();
+        self.note_growth(key);
+
+        Ok(added)
+    }
+
+    pub fn hget(&self, key: &str, field: &str) -> Result<Option<String>, StoreError> {
+        Ok(self
+            .read(key, Value::as_hash)?
+            .and_then(|hash| hash.get(field).cloned()))
+    }
+
+    /// Returns the values of `fields` and removes them from the hash, deleting
+    /// the key once no fields remain.
+    pub fn hget_del(
+        &mut self,
+        key: &str,
+        fields: &[String],
+    ) -> Result<Vec<Option<String>>, StoreError> {
+        let Some(hash) = self.typed_mut(key, Value::as_hash_mut)? else {
+            return Ok(vec![None; fields.len()]);
+        };
+
+        let values = fields.iter().map(|field| hash.remove(field)).collect();
+        self.del_if_empty(key);
+
+        Ok(values)
+    }
+
+    pub fn hdel(&mut self, key: &str, field: &str) -> Result<bool, StoreError> {
+        let removed = match self.typed_mut(key, Value::as_hash_mut)? {
+            Some(hash) => hash.remove(field).is_some(),
+            None => false,
+        };
+        self.del_if_empty(key);
+        Ok(removed)
+    }
+
+    pub fn hmget(
+        &mut self,
+        key: &str,
+        fields: &[String],
+    ) -> Result<Vec<Option<String>>, StoreError> {
+        Ok(match self.typed(key, Value::as_hash)? {
+            Some(hash) => fields
+                .iter()
+                .map(|field| hash.get(field).cloned())
+                .collect(),
+            None => vec![None; fields.len()],
+        })
+    }
+
+    pub fn hexists(&self, key: &str, field: &str) -> Result<bool, StoreError> {
+        Ok(self
+            .read(key, Value::as_hash)?
+            .is_some_and(|hash| hash.contains_key(field)))
+    }
+
+    /// Field names of the hash at `key`, in hash order. Empty if the key does
+    /// not exist.
+    pub fn hkeys(&mut self, key: &str) -> Result<Vec<String>, StoreError> {
+        Ok(self
+            .typed(key, Value::as_hash)?
+            .map(|hash| hash.keys().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    /// Like `hkeys`, but the values.
+    pub fn hvals(&mut self, key: &str) -> Result<Vec<String>, StoreError> {
+        Ok(self
+            .typed(key, Value::as_hash)?
+            .map(|hash| hash.values().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    /// Number of fields in the hash at `key`, `0` if it does not exist.
+    pub fn hlen(&self, key: &str) -> Result<usize, StoreError> {
+        Ok(self.read(key, Value::as_hash)?.map_or(0, HashMap::len))
+    }
+
+    pub fn hget_all(&mut self, key: &str) -> Result<HashMap<String, String>, StoreError> {
+        Ok(self
+            .typed(key, Value::as_hash)?
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    /// One `HSCAN` page: up to `count` fields from position `cursor` in field
+    /// name order, and the cursor of the next page (0 once the scan is done).
+    /// Fields deleted mid-scan shift later positions, so a scan interleaved
+    /// with deletes can skip fields.
+    pub fn hscan(
+        &mut self,
+        key: &str,
+        cursor: usize,
+        count: usize,
+    ) -> Result<(usize, Vec<(String, String)>), StoreError> {
+        let Some(hash) = self.typed(key, Value::as_hash)? else {
+            return Ok((0, Vec::new()));
+        };
+
+        let mut fields: Vec<_> = hash.iter().collect();
+        fields.sort();
+        let page: Vec<_> = fields
+            .iter()
+            .skip(cursor)
+            .take(count)
+            .map(|&(k, v)| (k.clone(), v.clone()))
+            .collect();
+        let next = cursor + page.len();
+        let next = if next >= fields.len() { 0 } else { next };
+
+        Ok((next, page))
+    }
+
+    /// Adds `by` to `field` in the hash at `key`, creating the hash and field
+    /// as needed. Fails with `NotAnInteger` if the field does not hold an
+    /// integer or the result would overflow.
+    pub fn hincr_by(&mut self, key: &str, field: &str, by: i64) -> Result<i64, StoreError> {
+        let hash = self.typed_or_insert(key, || Value::from(HashMap::new()), Value::as_hash_mut)?;
+
+        if let Some(value) = hash.get_mut(field) {
+            let new_value = value
+                .parse::<i64>()
+                .ok()
+                .and_then(|n| n.checked_add(by))
+                .ok_or(StoreError::NotAnInteger)?;
+            *value = new_value.to_string();
+            Ok(new_value)
+        } else {
+            hash.insert(field.to_string(), by.to_string());
+            self.note_growth(key);
+            Ok(by)
+        }
+    }
+
+    /// Adds `members` to the set at `key`, creating it if needed. Returns how
+    /// many were not already present.
+    pub fn sadd(&mut self, key: &str, members: &[String]) -> Result<usize, StoreError> {
+        let set = self.typed_or_insert(key, || Value::from(HashSet::new()), Value::as_set_mut)?;
+        let added = members
+            .iter()
+            .filter(|&member| set.insert(member.clone()))
+            .count();
+        self.note_growth(key);
+
+        Ok(added)
+    }
+
+    /// Removes `members` from the set at `key`, deleting the key once it is
+    /// empty. Returns how many were present.
+    pub fn srem(&mut self, key: &str, members: &[String]) -> Result<usize, StoreError> {
+        let Some(set) = self.typed_mut(key, Value::as_set_mut)? else {
+            return Ok(0);
+        };
+
+        let removed = members.iter().filter(|&member| set.remove(member)).count();
+        self.del_if_empty(key);
+
+        Ok(removed)
+    }
+
+    pub fn sismember(&self, key: &str, member: &str) -> Result<bool, StoreError> {
+        Ok(self
+            .read(key, Value::as_set)?
+            .is_some_and(|set| set.contains(member)))
+    }
+
+    pub fn smembers(&mut self, key: &str) -> Result<Vec<String>, StoreError> {
+        Ok(self
+            .typed(key, Value::as_set)?
+            .map(|set| set.iter().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    /// One `SSCAN` page: up to `count` members from position `cursor` in
+    /// sorted order, keeping only those matching `pattern`, and the cursor of
+    /// the next page (0 once the scan is done). Like `HSCAN`, a page may come
+    /// back empty while the cursor is still non-zero.
+    pub fn sscan(
+        &mut self,
+        key: &str,
+        cursor: usize,
+        count: usize,
+        pattern: Option<&str>,
+    ) -> Result<(usize, Vec<String>), StoreError> {
+        let Some(set) = self.typed(key, Value::as_set)? else {
+            return Ok((0, Vec::new()));
+        };
+
+        let mut members: Vec<_> = set.iter().collect();
+        members.sort();
+        let page: Vec<String> = members
+            .iter()
+            .skip(cursor)
+            .take(count)
+            .filter(|member| pattern.is_none_or(|pattern| glob_match(pattern, member)))
+            .map(|&member| member.clone())
+            .collect();
+        let next = cursor.saturating_add(count).min(members.len());
+        let next = if next >= members.len() { 0 } else { next };
+
+        Ok((next, page))
+    }
+
+    pub fn scard(&self, key: &str) -> Result<usize, StoreError> {
+        Ok(self.read(key, Value::as_set)?.map_or(0, HashSet::len))
+    }
+
+    /// Combines the sets at `keys` with `op`, treating missing keys as empty
+    /// sets. Fails if any key holds another type.
+    pub fn set_op(&mut self, op: SetOp, keys: &[String]) -> Result<HashSet<String>, StoreError> {
+        // Check every key up front so the sets can then be borrowed together.
+        for key in keys {
+            self.typed(key, Value::as_set)?;
+        }
+        let empty = HashSet::new();
+        let sets: Vec<&HashSet<String>> = keys
+            .iter()
+            .map(|key| self.data.get(key).and_then(Value::as_set).unwrap_or(&empty))
+            .collect();
+        let Some((first, rest)) = sets.split_first() else {
+            return Ok(HashSet::new());
+        };
+
+        Ok(match op {
+            // Drive the intersection from the smallest set, so its cost is
+            // bounded by that set rather than by whichever key came first.
+            SetOp::Inter => {
+                let smallest = sets.iter().min_by_key(|set| set.len()).unwrap();
+                smallest
+                    .iter()
+                    .filter(|member| sets.iter().all(|set| set.contains(*member)))
+                    .cloned()
+                    .collect()
+            }
+            SetOp::Union => sets.iter().flat_map(|set| set.iter().cloned()).collect(),
+            SetOp::Diff => first
+                .iter()
+                .filter(|member| !rest.iter().any(|set| set.contains(*member)))
+                .cloned()
+                .collect(),
+        })
+    }
+
+    /// Like `set_op`, but stores the result at `dest`, replacing whatever was
+    /// there and its TTL, and returns its size. An empty result deletes
+    /// `dest`.
+    pub fn set_op_store(
+        &mut self,
+        op: SetOp,
+        dest: &str,
+        keys: &[String],
+    ) -> Result<usize, StoreError> {
+        let members = self.set_op(op, keys)?;
+        let len = members.len();
+        self.del(dest);
+        if len > 0 {
+            self.data.insert(dest.to_string(), Value::from(members));
+            self.touch(dest);
+            self.note_growth(dest);
+        }
+        Ok(len)
+    }
+
+    /// Adds each `(score, member)` in `members` to the sorted set at `key`,
+    /// creating it if needed. A member already present is moved to its new
+    /// score. Returns how many members are new.
+    pub fn zadd(&mut self, key: &str, members: &[(f64, String)]) -> Result<usize, StoreError> {
+        let zset =
+            self.typed_or_insert(key, || Value::from(ZSet::default()), Value::as_zset_mut)?;
+        let added = members
+            .iter()
+            .filter(|(score, member)| zset.insert(member.clone(), *score))
+            .count();
+        self.note_growth(key);
+
+        Ok(added)
+    }
+
+    pub fn zscore(&mut self, key: &str, member: &str) -> Result<Option<f64>, StoreError> {
+        Ok(self
+            .typed(key, Value::as_zset)?
+            .and_then(|zset| zset.score(member)))
+    }
+
+    /// Zero-based position of `member` in the sorted set at `key`, lowest
+    /// score first.
+    pub fn zrank(&mut self, key: &str, member: &str) -> Result<Option<usize>, StoreError> {
+        Ok(self
+            .typed(key, Value::as_zset)?
+            .and_then(|zset| zset.rank(member)))
+    }
+
+    /// Members at ranks `start..=end` of the sorted set at `key` with their
+    /// scores, where negative ranks count from the highest score as resolved
+    /// by `resolve_range`.
+    pub fn zrange(
+        &mut self,
+        key: &str,
+        start: i64,
+        end: i64,
+    ) -> Result<Vec<(String, f64)>, StoreError> {
+        let Some(zset) = self.typed(key, Value::as_zset)? else {
+            return Ok(Vec::new());
+        };
+        let Some((start, end)) = resolve_range(start, end, zset.len()) else {
+            return Ok(Vec::new());
+        };
+        Ok(zset
+            .iter()
+            .skip(start)
+            .take(end - start + 1)
+            .map(|(member, score)| (member.clone(), score))
+            .collect())
+    }
+
+    /// Members of the sorted set at `key` scored between `min` and `max`,
+    /// with their scores, lowest first.
+    pub fn zrange_by_score(
+        &mut self,
+        key: &str,
+        min: ScoreBound,
+        max: ScoreBound,
+    ) -> Result<Vec<(String, f64)>, StoreError> {
+        Ok(self
+            .typed(key, Value::as_zset)?
+            .map(|zset| {
+                zset.range_by_score(min, max)
+                    .map(|(member, score)| (member.clone(), score))
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    /// Removes `members` from the sorted set at `key`, deleting the key once
+    /// it is empty. Returns how many were present.
+    pub fn zrem(&mut self, key: &str, members: &[String]) -> Result<usize, StoreError> {
+        let Some(zset) = self.typed_mut(key, Value::as_zset_mut)? else {
+            return Ok(0);
+        };
+
+        let removed = members.iter().filter(|member| zset.remove(member)).count();
+        self.del_if_empty(key);
+
+        Ok(removed)
+    }
+
+    pub fn zcard(&mut self, key: &str) -> Result<usize, StoreError> {
+        Ok(self.typed(key, Value::as_zset)?.map_or(0, ZSet::len))
+    }
+
+    /// Appends an entry to the stream at `key` in place, creating it if
+    /// needed; the key keeps its TTL. With no explicit `id` one is generated
+    /// from the current time, bumping the sequence when the clock has not
+    /// moved past the last entry.
+    pub fn xadd(
+        &mut self,
+        key: &str,
+        id: Option<StreamId>,
+        fields: Vec<(String, String)>,
+    ) -> Result<StreamId, StoreError> {
+        let last = self.stream_last_id(key)?;
+        let id = match id {
+            Some(id) if id > last => id,
+            Some(_) => return Err(StoreError::StreamIdTooSmall),
+            None => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_millis() as u64)
+                    .unwrap_or(0);
+                if now > last.ms {
+                    StreamId { ms: now, seq: 0 }
+                } else {
+                    StreamId {
+                        ms: last.ms,
+                        seq: last.seq + 1,
+                    }
+                }
+            }
+        };
+
+        let stream =
+            self.typed_or_insert(key, || Value::Stream(Vec::new()), Value::as_stream_mut)?;
+        stream.push((id, fields));
+        self.stream_notify.notify_waiters();
+
+        Ok(id)
+    }
+
+    /// Number of entries in the stream at `key`, `0` if it does not exist.
+    pub fn xlen(&mut self, key: &str) -> Result<usize, StoreError> {
+        Ok(self.typed(key, Value::as_stream)?.map_or(0, Vec::len))
+    }
+
+    /// Entries with ids in `start..=end`, oldest first, capped at `count`.
+    pub fn xrange(
+        &mut self,
+        key: &str,
+        start: StreamId,
+        end: StreamId,
+        count: Option<usize>,
+    ) -> Result<Vec<StreamEntry>, StoreError> {
+        let Some(stream) = self.typed(key, Value::as_stream)? else {
+            return Ok(Vec::new());
+        };
+        let from = stream.partition_point(|(id, _)| *id < start);
+        Ok(stream[from..]
+            .iter()
+            .take_while(|(id, _)| *id <= end)
+            .take(count.unwrap_or(usize::MAX))
+            .cloned()
+            .collect())
+    }
+
+    /// Id of the last entry in the stream at `key`, or `0-0` if there is none.
+    pub fn stream_last_id(&mut self, key: &str) -> Result<StreamId, StoreError> {
+        Ok(self
+            .typed(key, Value::as_stream)?
+            .and_then(|stream| stream.last())
+            .map_or(StreamId::MIN, |(id, _)| *id))
+    }
+
+    /// For each `(key, id)`, the entries strictly after `id`, up to `count` per
+    /// stream. Streams with nothing new are left out. Fails if any key holds
+    /// a non-stream value.
+    pub fn xread(
+        &mut self,
+        streams: &[(String, StreamId)],
+        count: Option<usize>,
+    ) -> Result<Vec<(String, Vec<StreamEntry>)>, StoreError> {
+        let mut results = Vec::new();
+        for (key, after) in streams {
+            let start = match (after.ms, after.seq) {
+                (u64::MAX, u64::MAX) => continue,
+                (ms, u64::MAX) => StreamId { ms: ms + 1, seq: 0 },
+                (ms, seq) => StreamId { ms, seq: seq + 1 },
+            };
+            let entries = self.xrange(key, start, StreamId::MAX, count)?;
+            if !entries.is_empty() {
+                results.push((key.clone(), entries));
+            }
+        }
+        Ok(results)
+    }
+}
+
+/// Sets `key`'s access time in `accessed` to now.
+fn stamp(accessed: &mut HashMap<String, Instant>, key: &str) {
+    let now = Instant::now();
+    match accessed.get_mut(key) {
+        Some(accessed) => *accessed = now,
+        None => {
+            accessed.insert(key.to_string(), now);
+        }
+    }
+}
+
+/// The deadline `ttl` from now, or `None` if it lies past `MAX_DEADLINE_MS`.
+pub fn deadline_in(ttl: Duration) -> Option<SystemTime> {
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .checked_add(ttl)?;
+    (since_epoch.as_millis() <= MAX_DEADLINE_MS as u128).then(|| UNIX_EPOCH + since_epoch)
+}
+
+/// The deadline `ttl` from now, held to `MAX_DEADLINE_MS` so that no TTL a
+/// command lets through can overflow the clock.
+fn deadline_after(ttl: Duration) -> SystemTime {
+    deadline_in(ttl).unwrap_or(UNIX_EPOCH + Duration::from_millis(MAX_DEADLINE_MS))
+}
+
+/// Turns a Redis-style inclusive index range over `len` elements into
+/// absolute positions. Negative indices count from the end (-1 is the last
+/// element), out-of-range bounds are clamped, and `None` means the range
+/// selects nothing.
+pub fn resolve_range(start: i64, end: i64, len: usize) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let start = if start < 0 { start + len } else { start }.max(0);
+    let end = if end < 0 { end + len } else { end }.min(len - 1);
+    if start > end || start >= len {
+        return None;
+    }
+    Some((start as usize, end as usize))
+}
+
+/// The integer a string value holds, if it is one.
+fn parse_int(value: &[u8]) -> Option<i64> {
+    std::str::from_utf8(value).ok()?.parse().ok()
+}
+
+/// Turns a Redis-style index into `len` elements into an absolute position,
+/// counting negative indices from the end. `None` if it is out of range.
+fn resolve_index(index: i64, len: usize) -> Option<usize> {
+    let index = if index < 0 {
+        index.checked_add(len as i64)?
+    } else {
+        index
+    };
+    usize::try_from(index).ok().filter(|&index| index < len)
+}
+
+/// The position of `key` in `SCAN` order. `DefaultHasher::new` is unkeyed, so
+/// this is stable for the life of the server.
+fn scan_hash(key: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Whether every member of a set is an integer, as Redis' intset requires.
+fn is_intset(members: &HashSet<String>) -> bool {
+    members.iter().all(|member| member.parse::<i64>().is_ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store() -> Store {
+        Store::with_seed(1)
+    }
+
+    fn string(value: &str) -> Value {
+        Value::from(value.to_string())
+    }
+
+    fn strings(items: &[&str]) -> Vec<String> {
+        items.iter().map(|item| item.to_string()).collect()
+    }
+
+    #[test]
+    fn expire_with_jitter_does_not_revive_an_expired_key() {
+        let mut store = store();
+        store.set("k", &string("v"));
+        store
+            .expiry
+            .insert("k".to_string(), SystemTime::now() - Duration::from_secs(1));
+
+        assert!(!store.expire_with_jitter("k", 300, 30));
+        assert!(!store.exists("k"));
+    }
+
+    #[test]
+    fn sinter_matches_whichever_set_is_smallest() {
+        let mut store = store();
+        for member in 0..100 {
+            store.sadd("big", &[member.to_string()]).unwrap();
+        }
+        store
+            .sadd("small", &["3".to_string(), "500".to_string()])
+            .unwrap();
+        let keys = ["big".to_string(), "small".to_string()];
+
+        let inter = store.set_op(SetOp::Inter, &keys).unwrap();
+        assert_eq!(inter, HashSet::from(["3".to_string()]));
+        let reversed = [keys[1].clone(), keys[0].clone()];
+        assert_eq!(store.set_op(SetOp::Inter, &reversed).unwrap(), inter);
+    }
+
+    #[test]
+    fn incr_by_with_ttl_sets_the_ttl_only_on_creation() {
+        let mut store = store();
+        assert_eq!(store.incr_by_with_ttl("hits", 1, 60), Ok(1));
+        store.persist("hits");
+        assert_eq!(store.incr_by_with_ttl("hits", 1, 60), Ok(2));
+        assert_eq!(store.ttl("hits"), -1);
+    }
+
+    #[test]
+    fn incr_by_with_ttl_rejects_other_types() {
+        let mut store = store();
+        store.rpush("list", vec!["a".to_string()]).unwrap();
+        assert_eq!(
+            store.incr_by_with_ttl("list", 1, 60),
+            Err(StoreError::WrongType)
+        );
+    }
+
+    #[test]
+    fn xadd_appends_in_place_and_keeps_the_ttl() {
+        let mut store = store();
+        let first = store
+            .xadd("s", None, vec![("a".into(), "1".into())])
+            .unwrap();
+        store.expire("s", 100);
+        let second = store
+            .xadd("s", None, vec![("b".into(), "2".into())])
+            .unwrap();
+
+        assert!(second > first);
+        assert_eq!(store.xlen("s"), Ok(2));
+        assert!(store.ttl("s") > 0);
+    }
+
+    #[test]
+    fn xadd_rejects_an_id_that_does_not_advance() {
+        let mut store = store();
+        let id = StreamId { ms: 5, seq: 0 };
+        store.xadd("s", Some(id), Vec::new()).unwrap();
+        assert_eq!(
+            store.xadd("s", Some(id), Vec::new()),
+            Err(StoreError::StreamIdTooSmall)
+        );
+        assert_eq!(
+            store.xadd("fresh", Some(StreamId::MIN), Vec::new()),
+            Err(StoreError::StreamIdTooSmall)
+        );
+        assert!(!store.exists("fresh"));
+    }
+
+    #[test]
+    fn stream_commands_report_wrong_type() {
+        let mut store = store();
+        store.set("k", &string("v"));
+        assert_eq!(
+            store.xadd("k", None, Vec::new()),
+            Err(StoreError::WrongType)
+        );
+        assert_eq!(store.xlen("k"), Err(StoreError::WrongType));
+        assert_eq!(
+            store.xrange("k", StreamId::MIN, StreamId::MAX, None),
+            Err(StoreError::WrongType)
+        );
+    }
+
+    #[test]
+    fn setbit_past_the_end_grows_the_string_and_reads_back() {
+        let mut store = store();
+        store.set("b", &string("a"));
+
+        assert_eq!(store.setbit("b", 23, true), Ok(false));
+        assert_eq!(store.get_string("b").unwrap().unwrap(), b"a\0\x01");
+        assert_eq!(store.getbit("b", 23), Ok(true));
+        assert_eq!(store.getbit("b", 22), Ok(false));
+        assert_eq!(store.getbit("b", 1_000), Ok(false));
+        assert_eq!(store.setbit("b", 23, false), Ok(true));
+        assert_eq!(store.getbit("b", 23), Ok(false));
+    }
+
+    #[test]
+    fn setbit_can_set_the_top_bit_of_a_byte() {
+        let mut store = store();
+        assert_eq!(store.setbit("b", 0, true), Ok(false));
+        assert_eq!(store.get_string("b").unwrap().unwrap(), [0x80]);
+        assert_eq!(store.getbit("b", 0), Ok(true));
+    }
+
+    #[test]
+    fn setbit_keeps_the_ttl() {
+        let mut store = store();
+        store.set("b", &string("a"));
+        store.expire("b", 100);
+        store.setbit("b", 7, false).unwrap();
+        assert!(store.ttl("b") > 0);
+    }
+
+    #[test]
+    fn setbit_rejects_an_offset_past_the_size_limit() {
+        let mut store = store();
+        let offset = store.proto_max_bulk_len() as u64 * 8;
+        assert_eq!(store.setbit("b", offset, true), Err(StoreError::TooLarge));
+        assert!(!store.exists("b"));
+    }
+
+    #[test]
+    fn bitcount_counts_across_every_byte() {
+        let mut store = store();
+        assert_eq!(store.bitcount("b"), Ok(0));
+        store.set("b", &Value::from(vec![0xff, 0x00, 0x81, 0x10]));
+        assert_eq!(store.bitcount("b"), Ok(11));
+    }
+
+    #[test]
+    fn bit_commands_reject_other_types() {
+        let mut store = store();
+        store.rpush("l", vec!["a".to_string()]).unwrap();
+        assert_eq!(store.setbit("l", 0, true), Err(StoreError::WrongType));
+        assert_eq!(store.getbit("l", 0), Err(StoreError::WrongType));
+        assert_eq!(store.bitcount("l"), Err(StoreError::WrongType));
+    }
+
+    #[test]
+    fn getrange_counts_negative_offsets_from_the_end() {
+        let mut store = store();
+        store.set("k", &string("Hello, world"));
+
+        assert_eq!(store.get_range("k", -5, -1).unwrap(), b"world");
+        assert_eq!(store.get_range("k", 0, -8).unwrap(), b"Hello");
+        assert_eq!(store.get_range("k", -100, 1).unwrap(), b"He");
+        assert_eq!(store.get_range("k", 5, 2).unwrap(), b"");
+        assert_eq!(store.get_range("missing", 0, -1).unwrap(), b"");
+    }
+
+    #[test]
+    fn getrange_splits_multi_byte_characters_exactly() {
+        let mut store = store();
+        store.set("k", &string("h\u{e9}llo"));
+        assert_eq!(store.get_range("k", 0, 1).unwrap(), b"h\xc3");
+        assert_eq!(store.get_range("k", 2, 2).unwrap(), b"\xa9");
+    }
+
+    #[test]
+    fn setrange_past_the_end_pads_with_zero_bytes() {
+        let mut store = store();
+        store.set("k", &string("abc"));
+        store.expire("k", 100);
+
+        assert_eq!(store.set_range("k", 5, b"xy"), Ok(7));
+        assert_eq!(store.get_string("k").unwrap().unwrap(), b"abc\0\0xy");
+        assert_eq!(store.set_range("k", 1, b"Z"), Ok(7));
+        assert_eq!(store.get_string("k").unwrap().unwrap(), b"aZc\0\0xy");
+        assert!(store.ttl("k") > 0);
+    }
+
+    #[test]
+    fn setrange_on_a_missing_key_creates_it() {
+        let mut store = store();
+        assert_eq!(store.set_range("k", 2, b"hi"), Ok(4));
+        assert_eq!(store.get_string("k").unwrap().unwrap(), b"\0\0hi");
+
+        assert_eq!(store.set_range("empty", 3, b""), Ok(0));
+        assert!(!store.exists("empty"));
+    }
+
+    #[test]
+    fn setrange_overwrites_part_of_a_character_byte_for_byte() {
+        let mut store = store();
+        store.set("k", &string("h\u{e9}"));
+        assert_eq!(store.set_range("k", 2, b"\xff"), Ok(3));
+        assert_eq!(store.get_string("k").unwrap().unwrap(), b"h\xc3\xff");
+    }
+
+    /// Runs a full SCAN, calling `between` before every page after the first,
+    /// and returns every key it reported.
+    fn scan_all(
+        store: &mut Store,
+        count: usize,
+        mut between: impl FnMut(&mut Store),
+    ) -> Vec<String> {
+        let mut seen = Vec::new();
+        let mut cursor = 0;
+        loop {
+            let (next, keys) = store.scan(cursor, count, None);
+            seen.extend(keys);
+            if next == 0 {
+                return seen;
+            }
+            cursor = next;
+            between(store);
+        }
+    }
+
+    #[test]
+    fn scan_returns_stable_keys_despite_writes_between_calls() {
+        let mut store = store();
+        for i in 0..200 {
+            store.set(&format!("stable:{}", i), &string("v"));
+            store.set(&format!("doomed:{}", i), &string("v"));
+        }
+
+        let mut round = 0;
+        let seen = scan_all(&mut store, 10, |store| {
+            round += 1;
+            for i in 0..5 {
+                store.set(&format!("new:{}:{}", round, i), &string("v"));
+            }
+            for i in (round * 5)..(round * 5 + 5) {
+                store.del(&format!("doomed:{}", i));
+            }
+        });
+
+        for i in 0..200 {
+            let key = format!("stable:{}", i);
+            assert_eq!(
+                seen.iter().filter(|seen| **seen == key).count(),
+                1,
+                "{}",
+                key
+            );
+        }
+    }
+
+    #[test]
+    fn scan_pages_are_bounded_by_count() {
+        let mut store = store();
+        for i in 0..100 {
+            store.set(&format!("k{}", i), &string("v"));
+        }
+        let (cursor, keys) = store.scan(0, 10, None);
+        assert_ne!(cursor, 0);
+        assert_eq!(keys.len(), 10);
+    }
+
+    #[test]
+    fn move_key_carries_the_value_and_ttl_to_another_database() {
+        let mut store = store();
+        store.set("k", &string("v"));
+        store.expire("k", 100);
+
+        assert!(store.move_key("k", 2));
+        assert!(!store.exists("k"));
+        assert_eq!(store.selected_db(), 0);
+
+        store.select(2);
+        assert_eq!(store.get_string("k").unwrap().unwrap(), b"v");
+        assert!(store.ttl("k") > 0);
+    }
+
+    #[test]
+    fn move_key_fails_if_missing_here_or_present_there() {
+        let mut store = store();
+        assert!(!store.move_key("k", 1));
+
+        store.set("k", &string("here"));
+        store.select(1);
+        store.set("k", &string("there"));
+        store.select(0);
+        assert!(!store.move_key("k", 1));
+        assert_eq!(store.get_string("k").unwrap().unwrap(), b"here");
+        store.select(1);
+        assert_eq!(store.get_string("k").unwrap().unwrap(), b"there");
+    }
+
+    #[test]
+    fn sscan_match_returns_only_matching_members_across_pages() {
+        let mut store = store();
+        let tags: Vec<String> = (0..50)
+            .map(|i| format!("size:{}", i))
+            .chain(["color:red", "color:blue", "color:green"].map(String::from))
+            .collect();
+        store.sadd("tags", &tags).unwrap();
+
+        let mut found = Vec::new();
+        let mut cursor = 0;
+        loop {
+            let (next, page) = store.sscan("tags", cursor, 7, Some("color:*")).unwrap();
+            assert!(page.len() <= 7);
+            found.extend(page);
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        found.sort();
+        assert_eq!(found, ["color:blue", "color:green", "color:red"]);
+    }
+
+    #[test]
+    fn sscan_without_match_walks_every_member_once() {
+        let mut store = store();
+        let members: Vec<String> = (0..25).map(|i| i.to_string()).collect();
+        store.sadd("s", &members).unwrap();
+
+        let (next, first) = store.sscan("s", 0, 20, None).unwrap();
+        let (last, second) = store.sscan("s", next, 20, None).unwrap();
+        assert_eq!((next, last), (20, 0));
+        assert_eq!(first.len() + second.len(), 25);
+        assert_eq!(
+            store.sscan("missing", 0, 10, None).unwrap(),
+            (0, Vec::new())
+        );
+
+        store.set("str", &string("x"));
+        assert!(matches!(
+            store.sscan("str", 0, 10, None),
+            Err(StoreError::WrongType)
+        ));
+    }
+
+    #[test]
+    fn aof_rewrite_keeps_one_command_per_key() {
+        let mut store = store();
+        for _ in 0..1000 {
+            store.incr_by("counter", 1).unwrap();
+        }
+        store.select(2);
+        store
+            .rpush("list", vec!["a".to_string(), "b".to_string()])
+            .unwrap();
+        store.pexpire_at("list", 4_000_000_000_000);
+        store.select(0);
+
+        let contents = crate::aof::rewrite(&store.aof_rewrite_snapshot());
+        let expected = [
+            "*2\r\n$6\r\nSELECT\r\n$1\r\n0\r\n",
+            "*3\r\n$3\r\nSET\r\n$7\r\ncounter\r\n$4\r\n1000\r\n",
+            "*2\r\n$6\r\nSELECT\r\n$1\r\n2\r\n",
+            "*4\r\n$5\r\nRPUSH\r\n$4\r\nlist\r\n$1\r\na\r\n$1\r\nb\r\n",
+            "*3\r\n$9\r\nPEXPIREAT\r\n$4\r\nlist\r\n$13\r\n4000000000000\r\n",
+        ]
+        .concat();
+        assert_eq!(String::from_utf8(contents).unwrap(), expected);
+    }
+
+    #[test]
+    fn no_touch_lookups_leave_the_access_time_alone() {
+        let mut store = store();
+        store.set("k", &string("v"));
+        let written = Instant::now() - Duration::from_secs(100);
+        store.accessed_mut().insert("k".to_string(), written);
+
+        store.set_no_touch(true);
+        store.get_string("k").unwrap();
+        assert!(store.exists("k"));
+        assert_eq!(store.accessed_mut()["k"], written);
+        assert_eq!(store.idle_time("k"), Some(100));
+
+        assert!(store.touch_existing("k"));
+        assert_eq!(store.idle_time("k"), Some(0));
+
+        store.accessed_mut().insert("k".to_string(), written);
+        store.set_no_touch(false);
+        store.get_string("k").unwrap();
+        assert_eq!(store.idle_time("k"), Some(0));
+    }
+
+    #[test]
+    fn lrange_resolves_negative_and_inclusive_indices() {
+        let mut store = store();
+        store.rpush("l", strings(&["a", "b", "c", "d"])).unwrap();
+
+        assert_eq!(store.lrange("l", 0, -1).unwrap(), ["a", "b", "c", "d"]);
+        assert_eq!(store.lrange("l", -3, -1).unwrap(), ["b", "c", "d"]);
+        assert_eq!(store.lrange("l", 1, 2).unwrap(), ["b", "c"]);
+        assert_eq!(store.lrange("l", -100, 100).unwrap(), ["a", "b", "c", "d"]);
+        assert!(store.lrange("l", 5, 10).unwrap().is_empty());
+        assert!(store.lrange("l", 3, 1).unwrap().is_empty());
+        assert!(store.lrange("l", -1, -2).unwrap().is_empty());
+        assert!(store.lrange("missing", 0, -1).unwrap().is_empty());
+    }
+
+    #[test]
+    fn expire_sweep_purges_at_most_max_keys_of_the_expired_ones() {
+        let mut store = store();
+        let past = SystemTime::now() - Duration::from_secs(1);
+        for i in 0..5 {
+            let key = format!("old:{}", i);
+            store.set(&key, &string("v"));
+            store.expiry.insert(key, past);
+        }
+        store.set("live", &string("v"));
+        store.expire("live", 100);
+        store.set("forever", &string("v"));
+
+        assert_eq!(store.expire_sweep(3), 3);
+        assert_eq!(store.data.len(), 4);
+        assert_eq!(store.expire_sweep(3), 2);
+        assert_eq!(store.expire_sweep(3), 0);
+        assert_eq!(store.keys("*").len(), 2);
+        assert!(store.exists("live") && store.exists("forever"));
+    }
+
+    #[test]
+    fn expire_sweep_skips_deadlines_that_were_extended_or_removed() {
+        let mut store = store();
+        let past = SystemTime::now() - Duration::from_secs(1);
+        for key in ["extended", "persisted", "lapsed"] {
+            store.set(key, &string("v"));
+            store.expiry.insert(key.to_string(), past);
+        }
+        store.expiry.insert(
+            "extended".to_string(),
+            SystemTime::now() + Duration::from_secs(100),
+        );
+        store.expiry.remove("persisted");
+
+        assert_eq!(
+            store.expiry.lapsed(SystemTime::now()).collect::<Vec<_>>(),
+            ["lapsed"]
+        );
+        assert_eq!(store.expiry.by_time.len(), store.expiry.len());
+        assert_eq!(store.expire_sweep(20), 1);
+        assert!(store.exists("extended") && store.exists("persisted"));
+        assert_eq!(store.expiry.by_time.len(), 1);
+    }
+
+    #[test]
+    fn keys_matches_globs_with_prefixes_and_character_classes() {
+        let mut store = store();
+        for key in [
+            "user:1",
+            "user:2",
+            "user:10",
+            "session:1",
+            "hello",
+            "hallo",
+            "hxllo",
+        ] {
+            store.set(key, &string("v"));
+        }
+        let keys = |store: &Store, pattern: &str| {
+            let mut keys = store.keys(pattern);
+            keys.sort();
+            keys
+        };
+
+        assert_eq!(keys(&store, "*").len(), 7);
+        assert_eq!(keys(&store, "user:*"), ["user:1", "user:10", "user:2"]);
+        assert_eq!(keys(&store, "user:?"), ["user:1", "user:2"]);
+        assert_eq!(keys(&store, "h[ae]llo"), ["hallo", "hello"]);
+        assert_eq!(keys(&store, "h[^e]llo"), ["hallo", "hxllo"]);
+        assert_eq!(keys(&store, "h[a-f]llo"), ["hallo", "hello"]);
+        assert!(keys(&store, "nothing*").is_empty());
+    }
+
+    #[test]
+    fn load_snapshot_restores_what_was_saved() {
+        let path = std::env::temp_dir().join(format!("keyval-{}-save.rdb", std::process::id()));
+        let mut store = store();
+        store.set_snapshot_path(&path.to_string_lossy());
+        store.set("s", &string("v"));
+        store.expire("s", 100);
+        store.rpush("l", strings(&["a", "b"])).unwrap();
+        store.select(3);
+        store.sadd("set", &strings(&["x"])).unwrap();
+        store.select(0);
+        store.save().unwrap();
+
+        store.set("s", &string("changed"));
+        store.del("l");
+        store.set("new", &string("v"));
+        store.select(3);
+        store.flush_db();
+        store.select(0);
+
+        assert!(store.load_snapshot().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(store.get_string("s").unwrap().unwrap(), b"v");
+        assert!(store.ttl("s") > 0);
+        assert_eq!(store.lrange("l", 0, -1).unwrap(), ["a", "b"]);
+        assert!(!store.exists("new"));
+        store.select(3);
+        assert_eq!(store.smembers("set").unwrap(), ["x"]);
+    }
+
+    #[test]
+    fn dbsize_counts_only_live_keys() {
+        let mut store = store();
+        assert_eq!(store.dbsize(), 0);
+
+        store.set("a", &string("v"));
+        store.set("b", &string("v"));
+        store.expire("b", 100);
+        assert_eq!(store.dbsize(), 2);
+
+        store.set("stale", &string("v"));
+        let past = SystemTime::now() - Duration::from_secs(1);
+        store.expiry.insert("stale".to_string(), past);
+        assert_eq!(store.dbsize(), 2);
+        assert!(store.data.contains_key("stale"));
+    }
+
+    #[test]
+    fn set_algebra_over_overlapping_sets() {
+        let mut store = store();
+        store.sadd("a", &strings(&["1", "2", "3", "4"])).unwrap();
+        store.sadd("b", &strings(&["3", "4", "5"])).unwrap();
+        store.sadd("c", &strings(&["4", "6"])).unwrap();
+        let set = |members: &[&str]| HashSet::from_iter(strings(members));
+        let keys = |keys: &[&str]| strings(keys);
+
+        assert_eq!(
+            store.set_op(SetOp::Inter, &keys(&["a", "b"])).unwrap(),
+            set(&["3", "4"])
+        );
+        assert_eq!(
+            store.set_op(SetOp::Inter, &keys(&["a", "b", "c"])).unwrap(),
+            set(&["4"])
+        );
+        assert_eq!(
+            store.set_op(SetOp::Union, &keys(&["b", "c"])).unwrap(),
+            set(&["3", "4", "5", "6"])
+        );
+        assert!(
+            store
+                .set_op(SetOp::Inter, &keys(&["a", "missing"]))
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn sdiff_subtracts_the_later_sets_from_the_first() {
+        let mut store = store();
+        store.sadd("a", &strings(&["1", "2", "3"])).unwrap();
+        store.sadd("b", &strings(&["2", "3", "4"])).unwrap();
+        store.sadd("c", &strings(&["3"])).unwrap();
+        let set = |members: &[&str]| HashSet::from_iter(strings(members));
+
+        assert_eq!(
+            store.set_op(SetOp::Diff, &strings(&["a", "b"])).unwrap(),
+            set(&["1"])
+        );
+        assert_eq!(
+            store.set_op(SetOp::Diff, &strings(&["b", "a"])).unwrap(),
+            set(&["4"])
+        );
+        assert_eq!(
+            store.set_op(SetOp::Diff, &strings(&["b", "c"])).unwrap(),
+            set(&["2", "4"])
+        );
+
+        assert_eq!(
+            store
+                .set_op_store(SetOp::Diff, "dest", &strings(&["a", "b"]))
+                .unwrap(),
+            1
+        );
+        assert_eq!(store.smembers("dest").unwrap(), ["1"]);
+    }
+
+    #[test]
+    fn a_full_scan_returns_the_same_keys_as_keys_star() {
+        let mut store = store();
+        for i in 0..137 {
+            store.set(&format!("key:{}", i), &string("v"));
+        }
+        let mut expected = store.keys("*");
+        expected.sort();
+
+        for count in [1, 10, 1000] {
+            let mut scanned = scan_all(&mut store, count, |_| {});
+            scanned.sort();
+            assert_eq!(scanned, expected, "COUNT {}", count);
+        }
+
+        let mut matched = Vec::new();
+        let mut cursor = 0;
+        loop {
+            let (next, keys) = store.scan(cursor, 10, Some("key:1*"));
+            matched.extend(keys);
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        matched.sort();
+        let mut expected = store.keys("key:1*");
+        expected.sort();
+        assert_eq!(matched, expected);
+    }
+
+    #[test]
+    fn popping_the_last_element_deletes_the_list() {
+        let mut store = store();
+        store.rpush("l", strings(&["only"])).unwrap();
+        store.expire("l", 100);
+        assert_eq!(store.lpop("l").unwrap().as_deref(), Some("only"));
+        assert!(!store.data.contains_key("l"));
+        assert!(store.expiry.get("l").is_none());
+        assert_eq!(store.llen("l"), Ok(0));
+        assert_eq!(store.key_type("l"), None);
+
+        store.rpush("r", strings(&["x", "x"])).unwrap();
+        assert_eq!(store.lrem("r", 0, "x".to_string()), Ok(2));
+        assert!(!store.exists("r"));
+
+        // A new push starts a fresh list with no leftover TTL.
+        store.rpush("l", strings(&["again"])).unwrap();
+        assert_eq!(store.ttl("l"), -1);
+    }
+
+    #[test]
+    fn lrem_removes_all_or_up_to_count_occurrences() {
+        let mut store = store();
+        store
+            .rpush("l", strings(&["x", "a", "x", "b", "x"]))
+            .unwrap();
+        assert_eq!(store.lrem("l", 0, "x".to_string()), Ok(3));
+        assert_eq!(store.lrange("l", 0, -1).unwrap(), ["a", "b"]);
+
+        store.rpush("m", strings(&["x", "a", "x"])).unwrap();
+        assert_eq!(store.lrem("m", 10, "x".to_string()), Ok(2));
+        assert_eq!(store.lrange("m", 0, -1).unwrap(), ["a"]);
+
+        store.rpush("n", strings(&["x", "a", "x"])).unwrap();
+        assert_eq!(store.lrem("n", -1, "x".to_string()), Ok(1));
+        assert_eq!(store.lrange("n", 0, -1).unwrap(), ["x", "a"]);
+    }
+
+    #[test]
+    fn lrem_with_extreme_counts_does_not_panic() {
+        let mut store = store();
+        store.rpush("l", strings(&["x", "x", "y"])).unwrap();
+        assert_eq!(store.lrem("l", i64::MIN, "x".to_string()), Ok(2));
+        store.rpush("l", strings(&["x"])).unwrap();
+        assert_eq!(store.lrem("l", i64::MAX, "x".to_string()), Ok(1));
+        assert_eq!(store.lrange("l", 0, -1).unwrap(), ["y"]);
+    }
+
+    #[test]
+    fn random_key_only_ever_returns_live_keys() {
+        let mut store = store();
+        assert_eq!(store.random_key(), None);
+
+        let keys = strings(&["a", "b", "c", "d"]);
+        for key in &keys {
+            store.set(key, &string("v"));
+        }
+        store.set("stale", &string("v"));
+        let past = SystemTime::now() - Duration::from_secs(1);
+        store.expiry.insert("stale".to_string(), past);
+
+        let mut seen = HashSet::new();
+        for _ in 0..200 {
+            let key = store.random_key().unwrap();
+            assert!(keys.contains(&key), "{}", key);
+            seen.insert(key);
+        }
+        assert_eq!(seen.len(), keys.len());
+    }
+
+    #[test]
+    fn idle_time_grows_until_the_key_is_read() {
+        let mut store = store();
+        assert_eq!(store.idle_time("k"), None);
+        store.set("k", &string("v"));
+        assert_eq!(store.idle_time("k"), Some(0));
+
+        let now = Instant::now();
+        store
+            .accessed_mut()
+            .insert("k".to_string(), now - Duration::from_secs(5));
+        assert_eq!(store.idle_time("k"), Some(5));
+        store
+            .accessed_mut()
+            .insert("k".to_string(), now - Duration::from_secs(60));
+        assert_eq!(store.idle_time("k"), Some(60));
+
+        assert!(store.get("k").is_some());
+        assert_eq!(store.idle_time("k"), Some(0));
+
+        store
+            .accessed_mut()
+            .insert("k".to_string(), now - Duration::from_secs(60));
+        store.set("k", &string("w"));
+        assert_eq!(store.idle_time("k"), Some(0));
+    }
+
+    #[test]
+    fn expire_time_logs_the_delete_of_a_lapsed_key() {
+        let mut store = store();
+        store.enable_aof();
+        store.set("k", &string("v"));
+        store
+            .expiry
+            .insert("k".to_string(), SystemTime::now() - Duration::from_secs(1));
+
+        assert_eq!(store.expire_time("k"), -2);
+        assert!(!store.data.contains_key("k"));
+        let logged = String::from_utf8(store.take_aof()).unwrap();
+        assert!(
+            logged.ends_with("*2\r\n$3\r\nDEL\r\n$1\r\nk\r\n"),
+            "{:?}",
+            logged
+        );
+        assert_eq!(store.expire_time("k"), -2);
+        assert!(store.take_aof().is_empty());
+    }
+
+    #[test]
+    fn sscan_with_a_huge_cursor_or_count_does_not_overflow() {
+        let mut store = store();
+        store.sadd("s", &strings(&["a", "b", "c"])).unwrap();
+
+        let (next, members) = store.sscan("s", 1, usize::MAX, None).unwrap();
+        assert_eq!((next, members), (0, strings(&["b", "c"])));
+        let (next, members) = store.sscan("s", usize::MAX, 5, None).unwrap();
+        assert_eq!(next, 0);
+        assert!(members.is_empty());
+        let (next, members) = store.sscan("s", usize::MAX, usize::MAX, None).unwrap();
+        assert_eq!(next, 0);
+        assert!(members.is_empty());
+    }
+
+    #[test]
+    fn expiry_reads_saturate_and_huge_ttls_are_held_to_the_latest_deadline() {
+        let mut store = store();
+        store.set("k", &string("v"));
+        store.expiry.insert(
+            "k".to_string(),
+            UNIX_EPOCH + Duration::from_millis(u64::MAX),
+        );
+        assert_eq!(store.pttl("k"), i64::MAX);
+        assert_eq!(store.expire_time("k"), i64::MAX);
+
+        assert!(store.pexpire("k", u64::MAX));
+        assert_eq!(store.expire_time("k"), i64::MAX);
+        assert!(store.rename_with_ttl("k", "other", u64::MAX));
+        assert_eq!(store.expire_time("other"), i64::MAX);
+    }
+
+    #[test]
+    fn shared_reads_skip_lapsed_keys_without_deleting_them() {
+        let mut store = store();
+        store.enable_aof();
+        store.set("k", &string("v"));
+        store
+            .accessed_mut()
+            .insert("k".to_string(), Instant::now() - Duration::from_secs(60));
+        assert!(store.contains("k"));
+        assert_eq!(store.idle_time("k"), Some(0));
+
+        store
+            .expiry
+            .insert("k".to_string(), SystemTime::now() - Duration::from_secs(1));
+        assert!(!store.contains("k"));
+        assert_eq!(store.get_string("k"), Ok(None));
+        assert_eq!(store.key_type("k"), None);
+        assert!(store.data.contains_key("k"));
+        assert!(store.take_aof().is_empty());
+
+        assert_eq!(store.expire_sweep(20), 1);
+        assert!(!store.data.contains_key("k"));
     }
 }
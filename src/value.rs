@@ -1,28 +1,195 @@
 use core::fmt;
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MIN: StreamId = StreamId { ms: 0, seq: 0 };
+    pub const MAX: StreamId = StreamId {
+        ms: u64::MAX,
+        seq: u64::MAX,
+    };
+
+    /// Parses `ms-seq`, or a bare `ms` whose sequence defaults to `default_seq`.
+    pub fn parse(s: &str, default_seq: u64) -> Option<StreamId> {
+        match s.split_once('-') {
+            Some((ms, seq)) => Some(StreamId {
+                ms: ms.parse().ok()?,
+                seq: seq.parse().ok()?,
+            }),
+            None => Some(StreamId {
+                ms: s.parse().ok()?,
+                seq: default_seq,
+            }),
+        }
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+pub type StreamEntry = (StreamId, Vec<(String, String)>);
+
+/// A sorted-set score, ordered totally. Scores are never NaN, and -0 is
+/// stored as 0, so this agrees with ordinary float comparison.
+#[derive(Debug, Clone, Copy)]
+pub struct Score(pub f64);
+
+impl PartialEq for Score {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+impl Eq for Score {}
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// One end of a score range, as given to ZRANGEBYSCORE: `value` itself is
+/// in range unless `exclusive`.
+#[derive(Debug, Clone, Copy)]
+pub struct ScoreBound {
+    pub value: f64,
+    pub exclusive: bool,
+}
+
+impl ScoreBound {
+    /// Whether `score` is in range with this bound as the minimum.
+    fn admits_above(&self, score: f64) -> bool {
+        if self.exclusive {
+            score > self.value
+        } else {
+            score >= self.value
+        }
+    }
+
+    /// Whether `score` is in range with this bound as the maximum.
+    fn admits_below(&self, score: f64) -> bool {
+        if self.exclusive {
+            score < self.value
+        } else {
+            score <= self.value
+        }
+    }
+}
+
+/// Members with scores, kept ordered by score and then by member name.
+#[derive(Debug, Clone, Default)]
+pub struct ZSet {
+    scores: HashMap<String, f64>,
+    ordered: BTreeSet<(Score, String)>,
+}
+
+impl ZSet {
+    /// Sets `member`'s score, moving it to its new place if it was already
+    /// present. Returns whether the member is new.
+    pub fn insert(&mut self, member: String, score: f64) -> bool {
+        // Adding 0.0 turns -0 into 0, so the two sort as the same score.
+        let score = score + 0.0;
+        let old = self.scores.insert(member.clone(), score);
+        if let Some(old) = old {
+            self.ordered.remove(&(Score(old), member.clone()));
+        }
+        self.ordered.insert((Score(score), member));
+        old.is_none()
+    }
+
+    /// Removes `member`, returning whether it was present.
+    pub fn remove(&mut self, member: &str) -> bool {
+        match self.scores.remove(member) {
+            Some(score) => self.ordered.remove(&(Score(score), member.to_string())),
+            None => false,
+        }
+    }
+
+    pub fn score(&self, member: &str) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    /// Zero-based position of `member` in score order.
+    pub fn rank(&self, member: &str) -> Option<usize> {
+        let score = self.score(member)?;
+        Some(
+            self.ordered
+                .range(..(Score(score), member.to_string()))
+                .count(),
+        )
+    }
+
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    /// Members and their scores in score order.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&String, f64)> {
+        self.ordered.iter().map(|(score, member)| (member, score.0))
+    }
+
+    /// Members scored between `min` and `max`, in score order.
+    pub fn range_by_score(
+        &self,
+        min: ScoreBound,
+        max: ScoreBound,
+    ) -> impl Iterator<Item = (&String, f64)> {
+        self.ordered
+            .range((Score(min.value), String::new())..)
+            .map(|(score, member)| (member, score.0))
+            .skip_while(move |&(_, score)| !min.admits_above(score))
+            .take_while(move |&(_, score)| max.admits_below(score))
+    }
+}
 
 #[derive(Debug, Clone)]
 pub enum Value {
-    String(String),
+    /// Raw bytes: string values are binary-safe, like Redis strings.
+    String(Vec<u8>),
     List(VecDeque<String>),
     Hash(HashMap<String, String>),
     Set(HashSet<String>),
+    Stream(Vec<StreamEntry>),
+    ZSet(ZSet),
 }
 
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Value::String(s) => write!(f, "{}", s),
+            Value::String(s) => write!(f, "{}", String::from_utf8_lossy(s)),
             Value::List(l) => write!(f, "{:?}", l),
             Value::Hash(h) => write!(f, "{:?}", h),
             Value::Set(s) => write!(f, "{:?}", s),
+            Value::Stream(s) => write!(f, "{:?}", s),
+            Value::ZSet(z) => write!(f, "{:?}", z.iter().collect::<Vec<_>>()),
         }
     }
 }
 
+impl From<Vec<u8>> for Value {
+    fn from(value: Vec<u8>) -> Self {
+        Value::String(value)
+    }
+}
 impl From<String> for Value {
     fn from(value: String) -> Self {
-        Value::String(value)
+        Value::String(value.into_bytes())
     }
 }
 impl From<VecDeque<String>> for Value {
@@ -40,9 +207,19 @@ impl From<HashSet<String>> for Value {
         Value::Set(value)
     }
 }
+impl From<Vec<StreamEntry>> for Value {
+    fn from(value: Vec<StreamEntry>) -> Self {
+        Value::Stream(value)
+    }
+}
+impl From<ZSet> for Value {
+    fn from(value: ZSet) -> Self {
+        Value::ZSet(value)
+    }
+}
 
 impl Value {
-    pub fn as_string(&self) -> Option<&String> {
+    pub fn as_string(&self) -> Option<&Vec<u8>> {
         if let Value::String(ref s) = *self {
             Some(s)
         } else {
@@ -50,6 +227,14 @@ impl Value {
         }
     }
 
+    pub fn as_string_mut(&mut self) -> Option<&mut Vec<u8>> {
+        if let Value::String(ref mut s) = *self {
+            Some(s)
+        } else {
+            None
+        }
+    }
+
     pub fn as_list(&self) -> Option<&VecDeque<String>> {
         if let Value::List(ref l) = *self {
             Some(l)
@@ -58,6 +243,14 @@ impl Value {
         }
     }
 
+    pub fn as_list_mut(&mut self) -> Option<&mut VecDeque<String>> {
+        if let Value::List(ref mut l) = *self {
+            Some(l)
+        } else {
+            None
+        }
+    }
+
     pub fn as_hash(&self) -> Option<&HashMap<String, String>> {
         if let Value::Hash(ref h) = *self {
             Some(h)
@@ -66,6 +259,14 @@ impl Value {
         }
     }
 
+    pub fn as_hash_mut(&mut self) -> Option<&mut HashMap<String, String>> {
+        if let Value::Hash(ref mut h) = *self {
+            Some(h)
+        } else {
+            None
+        }
+    }
+
     pub fn as_set(&self) -> Option<&HashSet<String>> {
         if let Value::Set(ref s) = *self {
             Some(s)
@@ -73,32 +274,133 @@ impl Value {
             None
         }
     }
+
+    pub fn as_set_mut(&mut self) -> Option<&mut HashSet<String>> {
+        if let Value::Set(ref mut s) = *self {
+            Some(s)
+        } else {
+            None
+        }
+    }
+
+    pub fn as_stream(&self) -> Option<&Vec<StreamEntry>> {
+        if let Value::Stream(ref s) = *self {
+            Some(s)
+        } else {
+            None
+        }
+    }
+
+    pub fn as_stream_mut(&mut self) -> Option<&mut Vec<StreamEntry>> {
+        if let Value::Stream(ref mut s) = *self {
+            Some(s)
+        } else {
+            None
+        }
+    }
+
+    pub fn as_zset(&self) -> Option<&ZSet> {
+        if let Value::ZSet(ref z) = *self {
+            Some(z)
+        } else {
+            None
+        }
+    }
+
+    pub fn as_zset_mut(&mut self) -> Option<&mut ZSet> {
+        if let Value::ZSet(ref mut z) = *self {
+            Some(z)
+        } else {
+            None
+        }
+    }
 }
 impl Value {
     pub fn is_string(&self) -> bool {
         matches!(self, Value::String(_))
     }
 
+    #[allow(dead_code)]
     pub fn is_list(&self) -> bool {
         matches!(self, Value::List(_))
     }
 
+    #[allow(dead_code)]
     pub fn is_hash(&self) -> bool {
         matches!(self, Value::Hash(_))
     }
 
+    #[allow(dead_code)]
     pub fn is_set(&self) -> bool {
         matches!(self, Value::Set(_))
     }
 }
 
 impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match *self {
+            Value::String(_) => "string",
+            Value::List(_) => "list",
+            Value::Hash(_) => "hash",
+            Value::Set(_) => "set",
+            Value::Stream(_) => "stream",
+            Value::ZSet(_) => "zset",
+        }
+    }
+
     pub fn len(&self) -> usize {
         match *self {
             Value::String(ref s) => s.len(),
             Value::List(ref l) => l.len(),
             Value::Hash(ref h) => h.len(),
             Value::Set(ref s) => s.len(),
+            Value::Stream(ref s) => s.len(),
+            Value::ZSet(ref z) => z.len(),
+        }
+    }
+
+    /// Multi-line, human-readable description of the value: its type, length
+    /// and up to `limit` elements. Hash fields and set members are sorted so
+    /// the output is stable between calls.
+    pub fn describe(&self, limit: usize) -> String {
+        let mut out = format!("type: {}\nlength: {}\n", self.type_name(), self.len());
+
+        let elements: Vec<String> = match self {
+            Value::String(s) => return out + &format!("value: \"{}\"\n", s.escape_ascii()),
+            Value::List(l) => l.iter().map(|v| format!("{:?}", v)).collect(),
+            Value::Hash(h) => {
+                let mut fields: Vec<_> = h.iter().collect();
+                fields.sort();
+                fields
+                    .iter()
+                    .map(|(f, v)| format!("{:?} => {:?}", f, v))
+                    .collect()
+            }
+            Value::Set(s) => {
+                let mut members: Vec<_> = s.iter().collect();
+                members.sort();
+                members.iter().map(|m| format!("{:?}", m)).collect()
+            }
+            Value::Stream(s) => s
+                .iter()
+                .map(|(id, fields)| {
+                    let fields: Vec<String> = fields
+                        .iter()
+                        .map(|(f, v)| format!("{:?}={:?}", f, v))
+                        .collect();
+                    format!("{} {}", id, fields.join(" "))
+                })
+                .collect(),
+            Value::ZSet(z) => z
+                .iter()
+                .map(|(member, score)| format!("{:?} {}", member, score))
+                .collect(),
+        };
+
+        out += &format!("elements (first {}):\n", limit.min(elements.len()));
+        for (i, element) in elements.iter().take(limit).enumerate() {
+            out += &format!("  {}) {}\n", i, element);
         }
+        out
     }
 }